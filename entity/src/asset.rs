@@ -0,0 +1,56 @@
+use chrono::Utc;
+use sea_orm::entity::prelude::*;
+use sea_orm::{sea_query, ActiveValue};
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "asset")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub key: String,
+    /// One CID per line, in the order they are tried
+    pub cids: String,
+    pub updated_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    pub fn cids(&self) -> Vec<String> {
+        self.cids.lines().map(|cid| cid.to_string()).collect()
+    }
+}
+
+pub async fn find_asset(
+    db: &DatabaseConnection,
+    key: &str,
+) -> Result<Option<Model>, anyhow::Error> {
+    Ok(Entity::find().filter(Column::Key.eq(key)).one(db).await?)
+}
+
+pub async fn set_asset(
+    db: &DatabaseConnection,
+    key: &str,
+    cids: &[String],
+) -> Result<(), anyhow::Error> {
+    let asset = ActiveModel {
+        key: ActiveValue::set(key.to_owned()),
+        cids: ActiveValue::set(cids.join("\n")),
+        updated_at: ActiveValue::set(Utc::now().naive_utc()),
+        ..Default::default()
+    };
+
+    Entity::insert(asset)
+        .on_conflict(
+            sea_query::OnConflict::column(Column::Key)
+                .update_columns([Column::Cids, Column::UpdatedAt])
+                .to_owned(),
+        )
+        .exec(db)
+        .await?;
+
+    Ok(())
+}
@@ -1,6 +1,6 @@
 use chrono::Utc;
 use sea_orm::entity::prelude::*;
-use sea_orm::{sea_query, ActiveValue};
+use sea_orm::{sea_query, ActiveValue, ConnectionTrait};
 
 #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
 #[sea_orm(table_name = "ipfs_object")]
@@ -12,6 +12,10 @@ pub struct Model {
     pub last_accessed_at: DateTime,
     pub content_type: String,
     pub content_size: i64,
+    /// One `name: value` per line
+    pub forwarded_headers: Option<String>,
+    /// Hex SHA-256 of the cached file, only recorded with `hash_content`
+    pub content_hash: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -19,8 +23,8 @@ pub enum Relation {}
 
 impl ActiveModelBehavior for ActiveModel {}
 
-pub async fn update_entry(
-    db: &DatabaseConnection,
+pub async fn update_entry<C: ConnectionTrait>(
+    db: &C,
     ipfs_url: &str,
     content_type: &str,
     content_size: i64,
@@ -45,3 +49,58 @@ pub async fn update_entry(
 
     Ok(())
 }
+
+/// Sets `last_accessed_at` of an existing entry only, an entry deleted since its
+/// access isn't created again
+pub async fn touch_entry<C: ConnectionTrait>(
+    db: &C,
+    ipfs_url: &str,
+    accessed_at: DateTime,
+) -> Result<(), anyhow::Error> {
+    Entity::update_many()
+        .col_expr(Column::LastAccessedAt, sea_query::Expr::value(accessed_at))
+        .filter(Column::RemoteUrl.eq(ipfs_url))
+        .exec(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Record a fresh fetch, unlike `update_entry` this resets `cached_at`
+pub async fn refresh_entry(
+    db: &DatabaseConnection,
+    ipfs_url: &str,
+    content_type: &str,
+    content_size: i64,
+    forwarded_headers: Option<String>,
+    content_hash: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let ipfs_url = ActiveModel {
+        remote_url: ActiveValue::set(ipfs_url.to_owned()),
+        cached_at: ActiveValue::set(Utc::now().naive_utc()),
+        last_accessed_at: ActiveValue::set(Utc::now().naive_utc()),
+        content_type: ActiveValue::set(content_type.to_string()),
+        content_size: ActiveValue::set(content_size),
+        forwarded_headers: ActiveValue::set(forwarded_headers),
+        content_hash: ActiveValue::set(content_hash),
+        ..Default::default()
+    };
+
+    Entity::insert(ipfs_url)
+        .on_conflict(
+            sea_query::OnConflict::column(Column::RemoteUrl)
+                .update_columns([
+                    Column::CachedAt,
+                    Column::LastAccessedAt,
+                    Column::ContentType,
+                    Column::ContentSize,
+                    Column::ForwardedHeaders,
+                    Column::ContentHash,
+                ])
+                .to_owned(),
+        )
+        .exec(db)
+        .await?;
+
+    Ok(())
+}
@@ -1 +1,3 @@
+pub mod asset;
 pub mod ipfs_object;
+pub mod thumbnail;
@@ -0,0 +1,42 @@
+use chrono::Utc;
+use sea_orm::entity::prelude::*;
+use sea_orm::{sea_query, ActiveValue, ConnectionTrait};
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "thumbnail")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i32,
+    pub source_filename: String,
+    pub filename: String,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+pub async fn record_thumbnail<C: ConnectionTrait>(
+    db: &C,
+    source_filename: &str,
+    filename: &str,
+) -> Result<(), anyhow::Error> {
+    let thumbnail = ActiveModel {
+        source_filename: ActiveValue::set(source_filename.to_owned()),
+        filename: ActiveValue::set(filename.to_owned()),
+        created_at: ActiveValue::set(Utc::now().naive_utc()),
+        ..Default::default()
+    };
+
+    Entity::insert(thumbnail)
+        .on_conflict(
+            sea_query::OnConflict::column(Column::Filename)
+                .update_column(Column::CreatedAt)
+                .to_owned(),
+        )
+        .exec(db)
+        .await?;
+
+    Ok(())
+}
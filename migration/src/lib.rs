@@ -1,12 +1,24 @@
 pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_table;
+mod m20221201_000001_create_thumbnail_table;
+mod m20221201_000002_create_asset_table;
+mod m20221201_000003_add_forwarded_headers;
+mod m20221201_000004_add_content_hash;
+mod m20221201_000005_encode_remote_urls;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m20220101_000001_create_table::Migration)]
+        vec![
+            Box::new(m20220101_000001_create_table::Migration),
+            Box::new(m20221201_000001_create_thumbnail_table::Migration),
+            Box::new(m20221201_000002_create_asset_table::Migration),
+            Box::new(m20221201_000003_add_forwarded_headers::Migration),
+            Box::new(m20221201_000004_add_content_hash::Migration),
+            Box::new(m20221201_000005_encode_remote_urls::Migration),
+        ]
     }
 }
@@ -0,0 +1,71 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Thumbnail::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Thumbnail::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(Thumbnail::SourceFilename)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(Thumbnail::Filename).string().not_null())
+                    .col(ColumnDef::new(Thumbnail::CreatedAt).date_time().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                sea_query::Index::create()
+                    .name("thumbnail_filenames")
+                    .table(Thumbnail::Table)
+                    .col(Thumbnail::Filename)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                sea_query::Index::create()
+                    .name("thumbnail_source_filenames")
+                    .table(Thumbnail::Table)
+                    .col(Thumbnail::SourceFilename)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Thumbnail::Table).to_owned())
+            .await
+    }
+}
+
+/// Thumbnails derived from a cached file, deleted along with it
+#[derive(Iden)]
+enum Thumbnail {
+    Table,
+    Id,
+    SourceFilename,
+    Filename,
+    CreatedAt,
+}
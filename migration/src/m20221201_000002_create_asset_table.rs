@@ -0,0 +1,57 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Asset::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Asset::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Asset::Key).string().not_null())
+                    .col(ColumnDef::new(Asset::Cids).string().not_null())
+                    .col(ColumnDef::new(Asset::UpdatedAt).date_time().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                sea_query::Index::create()
+                    .name("asset_keys")
+                    .table(Asset::Table)
+                    .col(Asset::Key)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Asset::Table).to_owned())
+            .await
+    }
+}
+
+/// Stable keys mapped to the CIDs of the same logical file, tried in order
+#[derive(Iden)]
+enum Asset {
+    Table,
+    Id,
+    Key,
+    Cids,
+    UpdatedAt,
+}
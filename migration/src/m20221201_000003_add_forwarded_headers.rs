@@ -0,0 +1,36 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(IpfsObject::Table)
+                    .add_column(ColumnDef::new(IpfsObject::ForwardedHeaders).string().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(IpfsObject::Table)
+                    .drop_column(IpfsObject::ForwardedHeaders)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Gateway headers listed in `forward_headers`, re-emitted on cache hits
+#[derive(Iden)]
+enum IpfsObject {
+    Table,
+    ForwardedHeaders,
+}
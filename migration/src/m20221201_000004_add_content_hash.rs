@@ -0,0 +1,36 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(IpfsObject::Table)
+                    .add_column(ColumnDef::new(IpfsObject::ContentHash).string().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(IpfsObject::Table)
+                    .drop_column(IpfsObject::ContentHash)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Hex SHA-256 of the cached file, recorded with `hash_content`
+#[derive(Iden)]
+enum IpfsObject {
+    Table,
+    ContentHash,
+}
@@ -0,0 +1,116 @@
+use entity::ipfs_object;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, QueryFilter, QueryOrder, QuerySelect,
+};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+/// Rows rewritten per query
+const BATCH_SIZE: u64 = 1000;
+
+/// Characters percent-encoded in the path segments of `remote_url`, as the proxy
+/// normalizes urls since this migration
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}')
+    .add(b'%')
+    .add(b'/')
+    .add(b'\\');
+
+/// `remote_url` with its path segments after the CID decoded and encoded again, e.g.
+/// `ipfs://cid/My File.png` becomes `ipfs://cid/My%20File.png`
+fn canonical_url(remote_url: &str) -> String {
+    let Some(base_uri) = remote_url.strip_prefix("ipfs://") else {
+        return remote_url.to_string();
+    };
+
+    let segments = base_uri
+        .split('/')
+        .enumerate()
+        .map(
+            |(index, segment)| match percent_decode_str(segment).decode_utf8() {
+                Ok(decoded) if index > 0 => utf8_percent_encode(&decoded, PATH_SEGMENT).to_string(),
+                _ => segment.to_string(),
+            },
+        )
+        .collect::<Vec<String>>();
+
+    format!("ipfs://{}", segments.join("/"))
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    /// Both forms of a url map to the same cache file, so a row left in the old form
+    /// would delete the file of its encoded twin once it expires
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let db = manager.get_connection();
+        let mut last_id = 0;
+
+        loop {
+            let rows = ipfs_object::Entity::find()
+                .filter(ipfs_object::Column::Id.gt(last_id))
+                .order_by_asc(ipfs_object::Column::Id)
+                .limit(BATCH_SIZE)
+                .all(db)
+                .await?;
+            let Some(last) = rows.last() else {
+                break;
+            };
+            last_id = last.id;
+
+            for row in rows {
+                let canonical = canonical_url(&row.remote_url);
+                if canonical == row.remote_url {
+                    continue;
+                }
+
+                let twin = ipfs_object::Entity::find()
+                    .filter(ipfs_object::Column::RemoteUrl.eq(canonical.as_str()))
+                    .one(db)
+                    .await?;
+                match twin {
+                    // Fetched again in the encoded form, the old row only adds its last use
+                    Some(twin) => {
+                        if row.last_accessed_at > twin.last_accessed_at {
+                            ipfs_object::ActiveModel {
+                                id: ActiveValue::unchanged(twin.id),
+                                last_accessed_at: ActiveValue::set(row.last_accessed_at),
+                                ..Default::default()
+                            }
+                            .update(db)
+                            .await?;
+                        }
+                        ipfs_object::Entity::delete_by_id(row.id).exec(db).await?;
+                    }
+                    None => {
+                        ipfs_object::ActiveModel {
+                            id: ActiveValue::unchanged(row.id),
+                            remote_url: ActiveValue::set(canonical),
+                            ..Default::default()
+                        }
+                        .update(db)
+                        .await?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encoded urls decode to the same cache files, the proxy before this migration
+    /// finds them as they are
+    async fn down(&self, _manager: &SchemaManager) -> Result<(), DbErr> {
+        Ok(())
+    }
+}
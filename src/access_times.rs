@@ -0,0 +1,183 @@
+use chrono::{NaiveDateTime, Utc};
+use dashmap::DashMap;
+use sea_orm::{DatabaseConnection, TransactionTrait};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tracing::{debug, error};
+
+use crate::AppContext;
+use entity::ipfs_object::touch_entry;
+use entity::thumbnail::record_thumbnail;
+
+/// Cache hits waiting for their `last_accessed_at` update, and thumbnails served
+/// waiting for their `created_at` one. Hits on the same url or thumbnail between two
+/// flushes are merged into a single write.
+#[derive(Default)]
+pub struct AccessTimes {
+    /// Time of the last hit by url
+    pending: DashMap<String, NaiveDateTime>,
+    /// Source filenames by thumbnail filename
+    thumbnails: DashMap<String, String>,
+}
+
+impl AccessTimes {
+    pub fn record(&self, ipfs_url: &str) {
+        self.pending
+            .insert(ipfs_url.to_string(), Utc::now().naive_utc());
+    }
+
+    /// Use of a thumbnail already recorded, see `evict_thumbnails`
+    pub fn record_thumbnail(&self, source_filename: &str, thumbnail_filename: &str) {
+        self.thumbnails
+            .insert(thumbnail_filename.to_string(), source_filename.to_string());
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len() + self.thumbnails.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty() && self.thumbnails.is_empty()
+    }
+
+    /// Write every pending access in one transaction, returns how many were written.
+    /// They stay pending until it's committed, so a failed flush is retried by the next.
+    pub async fn flush(&self, db: &DatabaseConnection) -> Result<usize, anyhow::Error> {
+        let accesses: Vec<(String, NaiveDateTime)> = self
+            .pending
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        let thumbnails: Vec<(String, String)> = self
+            .thumbnails
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+
+        if accesses.is_empty() && thumbnails.is_empty() {
+            return Ok(0);
+        }
+
+        let txn = db.begin().await?;
+        for (ipfs_url, accessed_at) in &accesses {
+            touch_entry(&txn, ipfs_url, *accessed_at).await?;
+        }
+        for (thumbnail_filename, source_filename) in &thumbnails {
+            record_thumbnail(&txn, source_filename, thumbnail_filename).await?;
+        }
+        txn.commit().await?;
+
+        // Hits recorded again while flushing are left for the next flush
+        for (ipfs_url, accessed_at) in &accesses {
+            self.pending
+                .remove_if(ipfs_url, |_, pending| pending == accessed_at);
+        }
+        for (thumbnail_filename, source_filename) in &thumbnails {
+            self.thumbnails
+                .remove_if(thumbnail_filename, |_, pending| pending == source_filename);
+        }
+
+        Ok(accesses.len() + thumbnails.len())
+    }
+}
+
+/// Flush access times every `access_flush_seconds`
+pub fn spawn_flush(ctx: Arc<AppContext>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+            ctx.config.access_flush_seconds,
+        ));
+
+        loop {
+            interval.tick().await;
+
+            match ctx.access_times.flush(&ctx.db).await {
+                Ok(0) => {}
+                Ok(written) => debug!("Flushed {written} access times"),
+                Err(error) => error!("Error flushing access times: {error}"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_orm::entity::prelude::*;
+
+    #[tokio::test]
+    async fn merge_accesses_per_url() -> Result<(), anyhow::Error> {
+        let ctx = AppContext::build_for_test().await;
+
+        let ipfs_url =
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/accessed.json";
+        entity::ipfs_object::update_entry(&ctx.db, ipfs_url, "application/json", 2).await?;
+        let access_times = AccessTimes::default();
+        access_times.record(ipfs_url);
+        access_times.record(ipfs_url);
+        let accessed_at = *access_times.pending.get(ipfs_url).expect("No access");
+
+        assert_eq!(access_times.len(), 1);
+        assert_eq!(access_times.flush(&ctx.db).await?, 1);
+        assert!(access_times.is_empty());
+
+        let ipfs_object = entity::ipfs_object::Entity::find()
+            .filter(entity::ipfs_object::Column::RemoteUrl.eq(ipfs_url))
+            .one(&ctx.db)
+            .await?;
+        assert_eq!(
+            ipfs_object.map(|ipfs_object| ipfs_object.last_accessed_at),
+            Some(accessed_at)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skip_deleted_entries() -> Result<(), anyhow::Error> {
+        let ctx = AppContext::build_for_test().await;
+
+        // Hit, then deleted by cleanup before the flush
+        let ipfs_url =
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/deleted.json";
+        let access_times = AccessTimes::default();
+        access_times.record(ipfs_url);
+
+        assert_eq!(access_times.flush(&ctx.db).await?, 1);
+        assert_eq!(entity::ipfs_object::Entity::find().count(&ctx.db).await?, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_accesses_of_failed_flush() -> Result<(), anyhow::Error> {
+        // Without tables every write fails
+        let db = sea_orm::Database::connect("sqlite::memory:").await?;
+        let access_times = AccessTimes::default();
+        access_times.record("ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi");
+        access_times.record_thumbnail("image.png", "image.png-100x100.png");
+
+        assert!(access_times.flush(&db).await.is_err());
+        assert_eq!(access_times.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn merge_thumbnail_uses() -> Result<(), anyhow::Error> {
+        let ctx = AppContext::build_for_test().await;
+
+        let access_times = AccessTimes::default();
+        access_times.record_thumbnail("image.png", "image.png-100x100.png");
+        access_times.record_thumbnail("image.png", "image.png-100x100.png");
+        access_times.record_thumbnail("image.png", "image.png-50x50.png");
+
+        assert_eq!(access_times.len(), 2);
+        assert_eq!(entity::thumbnail::Entity::find().count(&ctx.db).await?, 0);
+        assert_eq!(access_times.flush(&ctx.db).await?, 2);
+        assert!(access_times.is_empty());
+        assert_eq!(entity::thumbnail::Entity::find().count(&ctx.db).await?, 2);
+
+        Ok(())
+    }
+}
@@ -1,51 +1,100 @@
 use crate::app_context::AppContext;
-use crate::config::Dimension;
-use actix_web::http::header;
+use crate::config::{Dimension, RuntimeSettingsUpdate, Settings};
+use actix_files::HttpRange;
+use actix_web::http::{header, Method, StatusCode};
 use actix_web::middleware::Logger;
 use actix_web::web::{self, ServiceConfig};
 use actix_web::{
-    body::MessageBody,
-    dev::{Server, ServiceFactory, ServiceRequest, ServiceResponse},
+    body::{BodySize, BoxBody, MessageBody},
+    dev::{Server, Service, ServiceFactory, ServiceRequest, ServiceResponse},
     middleware::Compress,
     App, Error, HttpRequest, HttpResponse, HttpServer, Responder,
 };
+use actix_web_opentelemetry::PrometheusMetricsHandler;
+use bytes::Bytes;
+use futures::{future, stream, StreamExt};
 use imagesize::size;
 use mime;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::io::SeekFrom;
 use std::net::TcpListener;
 use std::sync::Arc;
+use std::time::Instant;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
 use tracing::{debug, error, info};
 use tracing_actix_web::TracingLogger;
 
+use crate::access_times;
+use crate::caching::{self, Data};
+use crate::client_limits;
 use crate::ipfs_client;
+use crate::openapi;
+use crate::size_token;
+use crate::telemetry;
+use crate::thumbnails;
+use crate::url_signature::{self, SignedParams};
+use entity::asset::{find_asset, set_asset};
+use entity::thumbnail::record_thumbnail;
 
 pub fn run(ctx: AppContext, listener: TcpListener) -> anyhow::Result<Server> {
     let port = listener.local_addr().unwrap().port();
     let ip = listener.local_addr().unwrap().ip();
     let ctx = web::Data::new(ctx);
+    access_times::spawn_flush(ctx.clone().into_inner());
+    let exporter = telemetry::init_metrics();
 
-    let server = HttpServer::new(move || make_app().configure(config_app(ctx.clone())))
-        .listen(listener)?
-        .run();
+    let server = HttpServer::new(move || {
+        make_app(
+            ctx.config.compress_min_bytes,
+            ctx.config.compress_content_types.clone(),
+            ctx.config.slow_request_threshold_ms,
+        )
+        .configure(config_app(ctx.clone()))
+        .route(
+            "/metrics",
+            web::get().to(PrometheusMetricsHandler::new(exporter.clone())),
+        )
+    })
+    .listen(listener)?
+    .run();
 
     info!("Listening to http://{ip}:{port}/");
 
     Ok(server)
 }
 
-fn config_app(app_ctx: web::Data<AppContext>) -> Box<dyn Fn(&mut ServiceConfig)> {
+pub(crate) fn config_app(app_ctx: web::Data<AppContext>) -> Box<dyn Fn(&mut ServiceConfig)> {
     Box::new(move |cfg: &mut ServiceConfig| {
         cfg.service(
             web::resource("/ipfs/{ipfs_file:.+}")
                 .route(web::get().to(ipfs_file))
-                .route(web::head().to(ipfs_file)),
+                .route(web::head().to(ipfs_file))
+                .route(web::method(Method::OPTIONS).to(ipfs_file_options)),
+        );
+
+        cfg.service(
+            web::resource("/asset/{key}")
+                .route(web::get().to(asset))
+                .route(web::head().to(asset))
+                .route(web::put().to(update_asset)),
         );
 
+        cfg.service(web::resource("/health").route(web::get().to(health)));
+        cfg.service(web::resource("/config").route(web::put().to(update_config)));
+        cfg.service(web::resource("/gateways").route(web::get().to(gateways)));
+        cfg.service(web::resource("/openapi.json").route(web::get().to(openapi)));
+
         cfg.app_data(app_ctx.clone());
     })
 }
 
-fn make_app() -> App<
+pub(crate) fn make_app(
+    compress_min_bytes: u64,
+    compress_content_types: Vec<String>,
+    slow_request_threshold_ms: Option<u64>,
+) -> App<
     impl ServiceFactory<
         ServiceRequest,
         Response = ServiceResponse<impl MessageBody>,
@@ -55,13 +104,431 @@ fn make_app() -> App<
     >,
 > {
     App::new()
+        .wrap_fn(|req, srv| {
+            let admitted = match req.app_data::<web::Data<AppContext>>() {
+                Some(ctx) if is_client_limited(&ctx.config, req.path()) => {
+                    client_limits::client_ip(&ctx.config, req.request())
+                        .map(|ip| ctx.client_limits.admit(&ctx.config, ip))
+                        .transpose()
+                }
+                _ => Ok(None),
+            };
+            let response = match admitted {
+                Ok(guard) => Ok((guard, srv.call(req))),
+                Err(retry_after) => Err((req, retry_after)),
+            };
+
+            async move {
+                match response {
+                    Ok((guard, response)) => {
+                        let response = response.await?.map_into_boxed_body();
+                        Ok(response
+                            .map_body(|_, body| client_limits::GuardedBody::new(body, guard))
+                            .map_into_left_body())
+                    }
+                    Err((req, retry_after)) => {
+                        let response = HttpResponse::TooManyRequests()
+                            .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                            .body("Error: too many requests");
+                        Ok(req.into_response(response).map_into_right_body())
+                    }
+                }
+            }
+        })
+        // `Logger` already logs every request, this only singles out slow ones
+        .wrap_fn(move |req, srv| {
+            let started = Instant::now();
+            let path = req.path().to_string();
+            let response = srv.call(req);
+
+            async move {
+                let response = response.await?;
+                if slow_request_threshold_ms.is_some() {
+                    telemetry::log_duration(
+                        slow_request_threshold_ms,
+                        started.elapsed(),
+                        &format!("[{}] Served {path}", response.status().as_u16()),
+                    );
+                }
+
+                Ok(response)
+            }
+        })
         .wrap(Logger::default())
         .wrap(TracingLogger::default())
         .wrap(actix_web_opentelemetry::RequestTracing::new())
+        // Runs before `Compress`, which leaves responses with a Content-Encoding alone
+        .wrap_fn(move |req, srv| {
+            let response = srv.call(req);
+            let compress_content_types = compress_content_types.clone();
+
+            async move {
+                let mut response = response.await?;
+
+                let size = match response.response().body().size() {
+                    BodySize::Sized(size) => Some(size),
+                    _ => None,
+                };
+                let content_type = response
+                    .headers()
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok());
+
+                // Precompressed files already carry their encoding
+                if !response.headers().contains_key(header::CONTENT_ENCODING)
+                    && !should_compress(
+                        content_type,
+                        size,
+                        compress_min_bytes,
+                        &compress_content_types,
+                    )
+                {
+                    response.headers_mut().insert(
+                        header::CONTENT_ENCODING,
+                        header::HeaderValue::from_static("identity"),
+                    );
+                }
+
+                Ok(response)
+            }
+        })
         .wrap(Compress::default())
 }
 
+/// Only text-like content above `compress_min_bytes` is worth compressing, media
+/// formats are already compressed
+fn should_compress(
+    content_type: Option<&str>,
+    size: Option<u64>,
+    compress_min_bytes: u64,
+    compress_content_types: &[String],
+) -> bool {
+    if matches!(size, Some(size) if size < compress_min_bytes) {
+        return false;
+    }
+
+    let Some(content_type) = content_type else {
+        return false;
+    };
+
+    compress_content_types
+        .iter()
+        .any(|compressible| content_type.starts_with(compressible.as_str()))
+}
+
+/// Ranges beyond this are answered with the whole file
+const MAX_RANGES: usize = 16;
+
+/// Opened file, length and ranges of a `Range` header asking for several parts of
+/// `filename`
+fn multiple_ranges(
+    req: &HttpRequest,
+    filename: &str,
+) -> Option<(std::fs::File, u64, Vec<HttpRange>)> {
+    let range = req.headers().get(header::RANGE)?.to_str().ok()?;
+    let file = std::fs::File::open(filename).ok()?;
+    let length = file.metadata().ok()?.len();
+    let ranges = HttpRange::parse(range, length).ok()?;
+
+    (ranges.len() > 1 && ranges.len() <= MAX_RANGES).then_some((file, length, ranges))
+}
+
+/// `multipart/byteranges` response streaming each range with its own `Content-Range`.
+/// The file is already open, uncached files are removed once the response is built.
+fn send_ranges(
+    file: std::fs::File,
+    content_type: String,
+    length: u64,
+    ranges: Vec<HttpRange>,
+) -> HttpResponse {
+    let boundary = format!(
+        "{:x}",
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    );
+
+    let part_boundary = boundary.clone();
+    let parts = stream::iter(ranges)
+        .then(move |range| {
+            // Parts are read one after the other, so sharing the file offset is fine
+            let file = file.try_clone();
+            let header = format!(
+                "--{part_boundary}\r\nContent-Type: {content_type}\r\nContent-Range: bytes {}-{}/{length}\r\n\r\n",
+                range.start,
+                range.start + range.length - 1
+            );
+
+            async move {
+                let body = match file.map(tokio::fs::File::from_std) {
+                    Ok(mut file) => match file.seek(SeekFrom::Start(range.start)).await {
+                        Ok(_) => ReaderStream::new(file.take(range.length)).left_stream(),
+                        Err(error) => stream::once(future::ready(Err(error))).right_stream(),
+                    },
+                    Err(error) => stream::once(future::ready(Err(error))).right_stream(),
+                };
+
+                stream::once(future::ready(Ok(Bytes::from(header))))
+                    .chain(body)
+                    .chain(stream::once(future::ready(Ok(Bytes::from_static(
+                        b"\r\n",
+                    )))))
+            }
+        })
+        .flatten()
+        .chain(stream::once(future::ready(Ok::<_, std::io::Error>(
+            Bytes::from(format!("--{boundary}--\r\n")),
+        ))));
+
+    HttpResponse::PartialContent()
+        .content_type(format!("multipart/byteranges; boundary={boundary}"))
+        .streaming(parts)
+}
+
+/// Whether `Accept-Encoding` lists `br` without `q=0`
+fn accepts_brotli(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all(header::ACCEPT_ENCODING)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|encoding| {
+            let mut parts = encoding.split(';').map(|part| part.trim());
+            parts.next() == Some("br")
+                && !parts.any(|part| {
+                    part.strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .map(|q| q == 0.0)
+                        .unwrap_or_default()
+                })
+        })
+}
+
+#[derive(Serialize)]
+struct Health {
+    writable: bool,
+    available_bytes: Option<u64>,
+    total_bytes: Option<u64>,
+    available_inodes: Option<u64>,
+}
+
+async fn health(ctx: web::Data<AppContext>) -> impl Responder {
+    let ctx = ctx.into_inner();
+    let directory = ctx.config.full_ipfs_cache_directory();
+
+    let writable = std::fs::create_dir_all(&directory).is_ok()
+        && tempfile::Builder::new().tempfile_in(&directory).is_ok();
+    let disk_usage = match caching::cache_disk_usage(ctx.clone()) {
+        Ok(disk_usage) => Some(disk_usage),
+        Err(error) => {
+            error!("Can't get disk usage for {directory}: {error}");
+            None
+        }
+    };
+
+    let healthy = writable
+        && disk_usage
+            .as_ref()
+            .map(|disk_usage| {
+                disk_usage.available_bytes >= ctx.config.min_free_bytes
+                    && disk_usage.available_inodes >= ctx.config.min_free_inodes
+            })
+            .unwrap_or_default();
+
+    let health = Health {
+        writable,
+        available_bytes: disk_usage.as_ref().map(|d| d.available_bytes),
+        total_bytes: disk_usage.as_ref().map(|d| d.total_bytes),
+        available_inodes: disk_usage.as_ref().map(|d| d.available_inodes),
+    };
+
+    if healthy {
+        HttpResponse::Ok().json(health)
+    } else {
+        HttpResponse::ServiceUnavailable().json(health)
+    }
+}
+
+/// Client limits are configured and apply to `path`, monitoring endpoints are exempt
+fn is_client_limited(config: &Settings, path: &str) -> bool {
+    (config.client_requests_per_second.is_some()
+        || config.max_concurrent_requests_per_client.is_some())
+        && path != "/health"
+        && path != "/metrics"
+}
+
+/// Admin endpoints require `Authorization: Bearer <admin_token>`
+fn is_admin(req: &HttpRequest, ctx: &AppContext) -> bool {
+    let Some(admin_token) = &ctx.config.admin_token else {
+        return false;
+    };
+
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        // Compared in constant time so the token can't be guessed byte by byte
+        .map(|token| bool::from(token.as_bytes().ct_eq(admin_token.as_bytes())))
+        .unwrap_or_default()
+}
+
+async fn update_config(
+    req: HttpRequest,
+    ctx: web::Data<AppContext>,
+    update: web::Json<RuntimeSettingsUpdate>,
+) -> impl Responder {
+    if !is_admin(&req, &ctx) {
+        return HttpResponse::Forbidden().body("Error: admin token required");
+    }
+
+    let update = update.into_inner();
+    let mut result = Ok(());
+    // Applied to the current settings atomically, a concurrent update isn't lost
+    let previous = ctx.runtime.rcu(|current| {
+        let runtime = current.update(update.clone());
+        result = if runtime.ipfs_gateways.is_empty() {
+            Err("ipfs_gateways can't be empty".to_string())
+        } else {
+            runtime.validate().map_err(|error| error.to_string())
+        };
+
+        match result {
+            Ok(()) => Arc::new(runtime),
+            Err(_) => current.clone(),
+        }
+    });
+    if let Err(error) = result {
+        return HttpResponse::BadRequest().body(format!("Error: {error}"));
+    }
+
+    let runtime = previous.update(update);
+    ctx.resize_fetches(
+        previous.max_concurrent_fetches,
+        runtime.max_concurrent_fetches,
+    );
+    info!("Runtime settings updated: {:?}", &runtime);
+
+    HttpResponse::Ok().json(runtime)
+}
+
+async fn gateways(req: HttpRequest, ctx: web::Data<AppContext>) -> impl Responder {
+    if !is_admin(&req, &ctx) {
+        return HttpResponse::Forbidden().body("Error: admin token required");
+    }
+
+    HttpResponse::Ok().json(ipfs_client::gateway_statuses(&ctx).await)
+}
+
+async fn openapi(ctx: web::Data<AppContext>) -> impl Responder {
+    HttpResponse::Ok().json(openapi::openapi_document(&ctx.config))
+}
+
+/// Methods and query parameters of the IPFS route, its path item of the OpenAPI document
+async fn ipfs_file_options(ctx: web::Data<AppContext>) -> impl Responder {
+    let document = openapi::openapi_document(&ctx.config);
+
+    HttpResponse::Ok()
+        .insert_header((header::ALLOW, "GET, HEAD, OPTIONS"))
+        .insert_header((header::LINK, "</openapi.json>; rel=\"service-desc\""))
+        .json(&document["paths"]["/ipfs/{ipfs_file}"])
+}
+
+#[derive(Deserialize, Serialize)]
+struct AssetUpdate {
+    /// Tried in order until one can be fetched
+    cids: Vec<String>,
+}
+
+async fn update_asset(
+    req: HttpRequest,
+    ctx: web::Data<AppContext>,
+    key: web::Path<String>,
+    update: web::Json<AssetUpdate>,
+) -> impl Responder {
+    if !is_admin(&req, &ctx) {
+        return HttpResponse::Forbidden().body("Error: admin token required");
+    }
+
+    if update.cids.is_empty() {
+        return HttpResponse::BadRequest().body("Error: cids can't be empty");
+    }
+
+    for cid in &update.cids {
+        if let Err(error) = ipfs_client::check_ipfs_url(&format!("ipfs://{cid}")) {
+            return HttpResponse::BadRequest().body(format!("Error: {error}"));
+        }
+    }
+
+    if let Err(error) = set_asset(&ctx.db, &key, &update.cids).await {
+        error!("Can't update asset {}: {error}", &key);
+        return HttpResponse::InternalServerError().body(format!("Error: {error}"));
+    }
+
+    info!("Asset {} updated: {:?}", &key, &update.cids);
+    HttpResponse::Ok().json(update.into_inner())
+}
+
+/// Serve a stable asset key from the first of its CIDs which can be fetched
+async fn asset(
+    req: HttpRequest,
+    ctx: web::Data<AppContext>,
+    key: web::Path<String>,
+) -> HttpResponse {
+    let ctx = ctx.into_inner();
+
+    let asset = match find_asset(&ctx.db, &key).await {
+        Ok(Some(asset)) => asset,
+        Ok(None) => {
+            return error_response(
+                &req,
+                &ctx,
+                StatusCode::NOT_FOUND,
+                format!("Error: unknown asset {key}"),
+            );
+        }
+        Err(error) => {
+            return error_response(
+                &req,
+                &ctx,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error: {error}"),
+            );
+        }
+    };
+
+    match ipfs_client::fetch_first_available(ctx.clone(), &asset.cids()).await {
+        Ok(Data {
+            filename: Some(filename),
+            content_type: Some(content_type),
+            uncached,
+            headers,
+            ..
+        }) => {
+            let mut response = send_filename(&req, filename.clone(), content_type).await;
+            if uncached {
+                tokio::fs::remove_file(&filename).await.ok();
+            }
+            forward_headers(&mut response, &headers);
+            response
+        }
+        Ok(_) => error_response(
+            &req,
+            &ctx,
+            StatusCode::BAD_GATEWAY,
+            "Error, no data.".to_string(),
+        ),
+        Err(error) => error_response(
+            &req,
+            &ctx,
+            fetch_error_status(&error),
+            format!("Error: {error}"),
+        ),
+    }
+}
+
+/// Repeated and unknown parameters are rejected, see also `check_query`
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 struct ImageInfo {
     #[serde(rename(deserialize = "img-width"))]
     img_width: Option<String>,
@@ -69,60 +536,474 @@ struct ImageInfo {
     img_height: Option<String>,
     #[serde(rename(deserialize = "img-format"))]
     img_format: Option<String>,
+    /// Output format picked from the source when `img-format` isn't set, see
+    /// `thumbnails::auto_format`
+    #[serde(rename(deserialize = "img-auto"))]
+    img_auto: Option<String>,
+    /// Device pixel ratio multiplying the permitted `img-width` and `img-height`
+    dpr: Option<String>,
+    /// Subpath appended to the CID, same as putting it in the url path
+    path: Option<String>,
+    /// Signed size limit above `max_content_length` for this CID, see `size_token`
+    size_token: Option<String>,
+    /// Served instead of the cached content type, only for admins
+    #[serde(rename(deserialize = "content-type"))]
+    content_type: Option<String>,
+    /// Required with `require_signed_urls`, see `url_signature`
+    signature: Option<String>,
 }
 
-async fn ipfs_file(
-    req: HttpRequest,
-    ctx: web::Data<AppContext>,
-    info: web::Query<ImageInfo>,
-) -> impl Responder {
+/// `content-type` requested by an admin for mislabeled content, ignored for anyone else
+fn content_type_override(req: &HttpRequest, ctx: &AppContext, info: &ImageInfo) -> Option<String> {
+    let content_type = info.content_type.as_ref()?;
+    if !is_admin(req, ctx) || content_type.parse::<mime::Mime>().is_err() {
+        return None;
+    }
+
+    Some(content_type.clone())
+}
+
+/// Bound the query string before it's parsed into `ImageInfo`
+fn check_query(config: &Settings, query: &str) -> Result<(), String> {
+    let params = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .collect::<Vec<&str>>();
+    if params.len() > config.max_query_params {
+        return Err(format!(
+            "at most {} query parameters are allowed",
+            config.max_query_params
+        ));
+    }
+
+    if let Some((name, _)) = params
+        .iter()
+        .map(|param| param.split_once('=').unwrap_or((param, "")))
+        .find(|(_, value)| value.len() > config.max_query_value_length)
+    {
+        return Err(format!(
+            "query parameter {name} is longer than {} characters",
+            config.max_query_value_length
+        ));
+    }
+
+    Ok(())
+}
+
+async fn ipfs_file(req: HttpRequest, ctx: web::Data<AppContext>) -> impl Responder {
+    let started = Instant::now();
+    let ctx = ctx.into_inner();
+    let info = match check_query(&ctx.config, req.query_string()).and_then(|_| {
+        web::Query::<ImageInfo>::from_query(req.query_string()).map_err(|error| error.to_string())
+    }) {
+        Ok(info) => info,
+        Err(error) => {
+            return error_response(
+                &req,
+                &ctx,
+                StatusCode::BAD_REQUEST,
+                format!("Error: {error}"),
+            );
+        }
+    };
     let ipfs_file = match req.match_info().get("ipfs_file") {
         Some(ipfs_file) => ipfs_file,
         None => {
-            let result = HttpResponse::BadRequest().body("Error");
+            return error_response(&req, &ctx, StatusCode::BAD_REQUEST, "Error".to_string());
+        }
+    };
+
+    let requested = ipfs_file;
+    let ipfs_file = match &info.path {
+        Some(path) => format!("ipfs://{ipfs_file}/{}", path.trim_start_matches('/')),
+        None => format!("ipfs://{ipfs_file}"),
+    };
 
-            return result;
+    let base_uri = match ipfs_client::check_ipfs_url(&ipfs_file).and_then(|base_uri| {
+        ipfs_client::check_path_segments(&ctx.config, &base_uri)?;
+        Ok(base_uri)
+    }) {
+        Ok(base_uri) => base_uri,
+        Err(error) => {
+            return error_response(
+                &req,
+                &ctx,
+                StatusCode::BAD_REQUEST,
+                format!("Error: {error}"),
+            );
         }
     };
 
-    let ipfs_file = format!("ipfs://{ipfs_file}");
-    let ctx = ctx.into_inner();
+    let params = SignedParams {
+        img_width: info.img_width.as_deref(),
+        img_height: info.img_height.as_deref(),
+        img_format: info.img_format.as_deref(),
+        dpr: info.dpr.as_deref(),
+        img_auto: info.img_auto.as_deref(),
+    };
+    if !url_signature::verify_url_signature(
+        &ctx.config,
+        &base_uri,
+        params,
+        info.signature.as_deref(),
+    ) {
+        return error_response(
+            &req,
+            &ctx,
+            StatusCode::FORBIDDEN,
+            "Error: missing or invalid signature".to_string(),
+        );
+    }
+
+    // Clients are sent to the canonical url so caches key on a single form of each CID.
+    // The signature was checked for the requested CID, the canonical one gets its own.
+    if ctx.config.redirect_to_canonical {
+        let (cid, path) = requested.split_at(requested.find('/').unwrap_or(requested.len()));
+        match ipfs_client::canonical_cid(cid) {
+            Ok(canonical) if canonical != cid => {
+                // Encoded again, the router decoded most of the path
+                let canonical_url =
+                    ipfs_client::normalize_ipfs_url(&format!("ipfs://{canonical}{path}"));
+                let mut location = format!("/ipfs/{}", canonical_url.trim_start_matches("ipfs://"));
 
-    match ipfs_client::fetch_ipfs_data(ctx.clone(), &ipfs_file).await {
-        Err(error) => HttpResponse::BadRequest().body(format!("Error: {error}")),
+                let canonical_base_uri = format!("{canonical}{}", &base_uri[cid.len()..]);
+                let signature = url_signature::resign_url(
+                    &ctx.config,
+                    info.signature.as_deref(),
+                    &canonical_base_uri,
+                    params,
+                );
+                let query = req
+                    .query_string()
+                    .split('&')
+                    .filter(|param| !param.is_empty())
+                    .map(|param| match (&signature, param.split_once('=')) {
+                        (Some(signature), Some(("signature", _))) => {
+                            format!("signature={signature}")
+                        }
+                        _ => param.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("&");
+                if !query.is_empty() {
+                    location = format!("{location}?{query}");
+                }
+
+                return HttpResponse::Found()
+                    .insert_header((header::LOCATION, location))
+                    .finish();
+            }
+            Ok(_) => {}
+            Err(error) => {
+                return error_response(
+                    &req,
+                    &ctx,
+                    StatusCode::BAD_REQUEST,
+                    format!("Error: {error}"),
+                );
+            }
+        }
+    }
+
+    if !ctx.config.caching_enabled {
+        return stream_ipfs_file(&req, ctx, &ipfs_file).await;
+    }
+
+    // An invalid or expired token falls back to the global limit
+    let cid = base_uri.split('/').next().unwrap_or_default();
+    let max_content_length = info
+        .size_token
+        .as_deref()
+        .and_then(|token| size_token::verify_size_token(&ctx.config, cid, token))
+        .unwrap_or(ctx.config.max_content_length);
+
+    let lookup_started = Instant::now();
+    match ipfs_client::fetch_ipfs_data_with_limit(ctx.clone(), &ipfs_file, max_content_length).await
+    {
+        Err(error) => error_response(
+            &req,
+            &ctx,
+            fetch_error_status(&error),
+            format!("Error: {error}"),
+        ),
         Ok(data) => {
+            let fetch_duration = data.fetch_duration.unwrap_or_default();
+            let mut timings = vec![(
+                "cache",
+                lookup_started.elapsed().saturating_sub(fetch_duration),
+            )];
+            if data.fetch_duration.is_some() {
+                timings.push(("fetch", fetch_duration));
+            }
+
             let Some(content_type) = data.content_type else {
-                return HttpResponse::BadRequest().body("Can't find file format for the remote IPFS file".to_string());
+                return error_response(
+                    &req,
+                    &ctx,
+                    StatusCode::BAD_GATEWAY,
+                    "Can't find file format for the remote IPFS file".to_string(),
+                );
             };
+            let content_type = content_type_override(&req, &ctx, &info).unwrap_or(content_type);
 
-            match data.filename {
-                Some(filename) => match resize_image(ctx, info, filename, content_type) {
-                    Ok((filename, content_type)) => {
-                        send_filename(&req, filename, content_type).await
-                    }
-                    Err(error) => {
-                        error!("Error: {error}");
+            let mut response = match (data.filename, &data.bytes) {
+                // Not cached, so not resized either, the file is removed once opened
+                (Some(filename), _) if data.uncached => {
+                    let response = send_filename(&req, filename.clone(), content_type).await;
+                    tokio::fs::remove_file(&filename).await.ok();
+                    response
+                }
+                // Small hot files are served from memory unless a resize is requested
+                (Some(filename), Some(bytes))
+                    if info.img_width.is_none() && info.img_height.is_none() =>
+                {
+                    send_memory_hit(&req, filename, content_type, bytes.clone()).await
+                }
+                (Some(filename), _) => {
+                    let resize_started = Instant::now();
+                    let auto_format = is_auto_format(&info);
+                    let accept = req
+                        .headers()
+                        .get(header::ACCEPT)
+                        .and_then(|value| value.to_str().ok());
+                    let resized = resize_image(ctx.clone(), info, accept, filename, content_type);
+                    timings.push(("resize", resize_started.elapsed()));
+
+                    match resized {
+                        Ok((filename, content_type)) => {
+                            let mut response = send_filename(&req, filename, content_type).await;
+                            if auto_format {
+                                vary(&mut response, "accept");
+                            }
+                            response
+                        }
+                        Err(error) => {
+                            error!("Error: {error}");
 
-                        HttpResponse::BadRequest().body(format!("Error: {error}"))
+                            return error_response(
+                                &req,
+                                &ctx,
+                                StatusCode::BAD_REQUEST,
+                                format!("Error: {error}"),
+                            );
+                        }
                     }
-                },
-                None => HttpResponse::BadRequest().body("Error, no data.".to_string()),
+                }
+                (None, _) => {
+                    return error_response(
+                        &req,
+                        &ctx,
+                        StatusCode::BAD_GATEWAY,
+                        "Error, no data.".to_string(),
+                    )
+                }
+            };
+            forward_headers(&mut response, &data.headers);
+
+            if ctx.config.server_timing {
+                timings.push(("total", started.elapsed()));
+                if let Ok(value) = header::HeaderValue::from_str(&server_timing(&timings)) {
+                    response
+                        .headers_mut()
+                        .insert(header::HeaderName::from_static("server-timing"), value);
+                }
             }
+
+            response
         }
     }
 }
 
+/// `Server-Timing` header value, durations in milliseconds
+fn server_timing(timings: &[(&str, std::time::Duration)]) -> String {
+    timings
+        .iter()
+        .map(|(name, duration)| format!("{name};dur={:.1}", duration.as_secs_f64() * 1000.0))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Missing and oversized files keep their status, and disk pressure gets its own so
+/// monitoring can tell it from gateway failures
+fn fetch_error_status(error: &anyhow::Error) -> StatusCode {
+    if ipfs_client::has_cause::<caching::DiskFull>(error) {
+        StatusCode::INSUFFICIENT_STORAGE
+    } else if ipfs_client::has_cause::<ipfs_client::CidNotAllowed>(error) {
+        StatusCode::FORBIDDEN
+    } else if ipfs_client::has_cause::<ipfs_client::NotFoundOnGateways>(error) {
+        StatusCode::NOT_FOUND
+    } else if ipfs_client::has_cause::<ipfs_client::ContentTooLarge>(error) {
+        StatusCode::PAYLOAD_TOO_LARGE
+    } else {
+        StatusCode::BAD_GATEWAY
+    }
+}
+
+/// Plain text error, or the configured `error_page_path` template for clients accepting HTML.
+/// `{status}` and `{message}` are replaced in the template.
+fn error_response(
+    req: &HttpRequest,
+    ctx: &AppContext,
+    status: StatusCode,
+    message: String,
+) -> HttpResponse {
+    let accepts_html = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains("text/html"))
+        .unwrap_or_default();
+
+    if let (true, Some(error_page_path)) = (accepts_html, &ctx.config.error_page_path) {
+        match std::fs::read_to_string(error_page_path) {
+            Ok(template) => {
+                let body = template
+                    .replace("{status}", status.as_str())
+                    .replace("{message}", &escape_html(&message));
+
+                let mut response = HttpResponse::build(status)
+                    .content_type(mime::TEXT_HTML_UTF_8)
+                    .body(body);
+                vary(&mut response, "accept");
+                return response;
+            }
+            Err(error) => {
+                error!("Can't read error page {error_page_path}: {error}");
+            }
+        }
+    }
+
+    // The plain message is a variant too when browsers get the error page
+    let mut response = HttpResponse::build(status).body(message);
+    if ctx.config.error_page_path.is_some() {
+        vary(&mut response, "accept");
+    }
+    response
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Read-through mode: stream the gateway response to the client without writing it to disk
+async fn stream_ipfs_file(
+    req: &HttpRequest,
+    ctx: Arc<AppContext>,
+    ipfs_file: &str,
+) -> HttpResponse {
+    match ipfs_client::stream_ipfs_data(ctx.clone(), ipfs_file).await {
+        Err(error) => error_response(
+            req,
+            &ctx,
+            fetch_error_status(&error),
+            format!("Error: {error}"),
+        ),
+        Ok(streamed) => {
+            let response = &streamed.response;
+            let content_type = response
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or(mime::APPLICATION_OCTET_STREAM.as_ref())
+                .to_string();
+
+            debug!("Streaming data {} from {}", &content_type, response.url());
+            let headers = ipfs_client::forwarded_headers(&ctx, response.headers());
+
+            let mut client_response = HttpResponse::Ok()
+                .content_type(content_type)
+                .streaming(streamed.bytes_stream());
+            forward_headers(&mut client_response, &headers);
+            client_response
+        }
+    }
+}
+
+fn forward_headers(response: &mut HttpResponse, headers: &[(String, String)]) {
+    for (name, value) in headers {
+        if let (Ok(name), Ok(value)) = (
+            header::HeaderName::from_bytes(name.as_bytes()),
+            header::HeaderValue::from_str(value),
+        ) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+}
+
+/// Caches in front of us must key the response on this request header too
+fn vary(response: &mut HttpResponse, header_name: &'static str) {
+    response
+        .headers_mut()
+        .append(header::VARY, header::HeaderValue::from_static(header_name));
+}
+
+/// A memory cache hit gets the same headers, Range, If-None-Match/If-Modified-Since and
+/// `.br` sibling handling as its file, only a full plain body is taken from memory
+async fn send_memory_hit(
+    req: &HttpRequest,
+    filename: String,
+    content_type: String,
+    bytes: Bytes,
+) -> HttpResponse {
+    let response = send_filename(req, filename, content_type).await;
+    if response.status() != StatusCode::OK
+        || response.headers().contains_key(header::CONTENT_ENCODING)
+    {
+        return response;
+    }
+
+    response.map_body(|_, _| BoxBody::new(bytes))
+}
+
 async fn send_filename(req: &HttpRequest, filename: String, content_type: String) -> HttpResponse {
     let mime_type = content_type
         .parse()
         .unwrap_or(mime::APPLICATION_OCTET_STREAM);
-    let file = actix_files::NamedFile::open_async(&filename)
-        .await
-        .unwrap()
-        .disable_content_disposition()
-        .set_content_type(mime_type);
 
-    let mut response = file.into_response(&req);
+    // A `.br` sibling compressed out of band is sent as is to clients accepting it
+    let brotli_filename = format!("{filename}.br");
+    let has_brotli = std::path::Path::new(&brotli_filename).is_file();
+    let precompressed = has_brotli && accepts_brotli(req);
+
+    // NamedFile only answers the first range of a multi-range request
+    if !precompressed {
+        if let Some((file, length, ranges)) = multiple_ranges(req, &filename) {
+            let mut response = send_ranges(file, content_type, length, ranges);
+            if has_brotli {
+                vary(&mut response, "accept-encoding");
+            }
+            return response;
+        }
+    }
+
+    let mut file = match actix_files::NamedFile::open_async(if precompressed {
+        &brotli_filename
+    } else {
+        &filename
+    })
+    .await
+    {
+        Ok(file) => file
+            .disable_content_disposition()
+            .set_content_type(mime_type),
+        // Removed by cleanup or verify since it was looked up
+        Err(error) => {
+            error!("Can't open {filename}: {error}");
+            return HttpResponse::NotFound().body("Error: cached file not found");
+        }
+    };
+    if precompressed {
+        file = file.set_content_encoding(header::ContentEncoding::Brotli);
+    }
+
+    let mut response = file.into_response(req);
+    if has_brotli {
+        vary(&mut response, "accept-encoding");
+    }
     let Ok(dim) = size(&filename) else {
         return response;
     };
@@ -154,73 +1035,746 @@ async fn send_filename(req: &HttpRequest, filename: String, content_type: String
     response
 }
 
+/// `img-auto` without `img-format`, the output format depends on `Accept`
+fn is_auto_format(info: &ImageInfo) -> bool {
+    info.img_format.is_none() && matches!(info.img_auto.as_deref(), Some("1" | "true"))
+}
+
+#[tracing::instrument(skip(ctx, info))]
 fn resize_image(
     ctx: Arc<AppContext>,
     info: web::Query<ImageInfo>,
+    accept: Option<&str>,
     filename: String,
     content_type: String,
 ) -> Result<(String, String), anyhow::Error> {
-    let width = info
-        .img_width
-        .as_ref()
-        .map(|w| w.parse::<u32>().ok())
-        .flatten();
-    let height = info
-        .img_height
-        .as_ref()
-        .map(|h| h.parse::<u32>().ok())
-        .flatten();
-    let requested_file_format = info
-        .img_format
-        .as_ref()
-        .map(|h| h.to_string())
-        .unwrap_or_else(|| "png".to_string());
+    let width = info.img_width.as_ref().and_then(|w| w.parse::<u32>().ok());
+    let height = info.img_height.as_ref().and_then(|h| h.parse::<u32>().ok());
+    let (requested_file_format, quality) = match &info.img_format {
+        Some(format) => (format.to_string(), None),
+        None if is_auto_format(&info) => {
+            let format =
+                thumbnails::auto_format(ctx.config.resize_auto_policy, &content_type, accept);
+            (
+                format.to_string(),
+                Some(ctx.config.resize_auto_jpeg_quality),
+            )
+        }
+        None => ("png".to_string(), None),
+    };
 
     let (Some(width), Some(height)) = (width, height) else {
-            return Ok((filename, content_type));
-        };
+        return Ok((filename, content_type));
+    };
 
+    let dimension = Dimension { width, height };
     if !ctx
         .clone()
         .config
         .permitted_resize_dimensions
-        .contains(&Dimension { width, height })
+        .contains(&dimension)
     {
         return Err(anyhow::anyhow!("Requested dimensions are not allowed"));
     }
 
-    debug!("Resizing to {}x{} is requested", &width, &height);
-    let thumbnail_filename = match requested_file_format.as_str() {
-        "jpeg" => {
-            format!("{}-{}x{}.jpeg", &filename, width, height)
+    let dpr = thumbnails::parse_dpr(info.dpr.as_deref());
+    let dimension = Dimension {
+        width: width * dpr,
+        height: height * dpr,
+    };
+
+    if let Ok(source) = size(&filename) {
+        if !thumbnails::worth_resizing(
+            source.width as u32,
+            source.height as u32,
+            &dimension,
+            ctx.config.min_resizable_dimension,
+        ) {
+            debug!(
+                "Not resizing {} of {}x{}",
+                &filename, source.width, source.height
+            );
+            return Ok((filename, content_type));
         }
+    }
+
+    debug!(
+        "Resizing to {}x{} is requested",
+        &dimension.width, &dimension.height
+    );
+    let (thumbnail_filename, content_type) = thumbnails::thumbnail_filename(
+        &ctx.config,
+        &filename,
+        &dimension,
+        &requested_file_format,
+        quality,
+    );
+
+    // Thumbnails already on disk are only used again, which is written with the next
+    // access times flush. New ones are recorded right away so deleting their original
+    // finds them.
+    if std::path::Path::new(&thumbnail_filename).exists() {
+        ctx.access_times
+            .record_thumbnail(&filename, &thumbnail_filename);
+        return Ok((thumbnail_filename, content_type));
+    }
 
-        "png" | _ => {
-            format!("{}-{}x{}.png", &filename, width, height)
+    if let Err(error) = thumbnails::create_thumbnail(
+        &ctx.config,
+        &filename,
+        &thumbnail_filename,
+        &dimension,
+        quality,
+    ) {
+        error!("Couldn't resize file {}: {error}", &filename);
+        return Err(error);
+    }
+
+    let source_filename = filename.clone();
+    let recorded_filename = thumbnail_filename.clone();
+    tokio::spawn(async move {
+        if let Err(error) = record_thumbnail(&ctx.db, &source_filename, &recorded_filename).await {
+            error!("Error recording thumbnail {recorded_filename}: {error}");
         }
+    });
+
+    Ok((thumbnail_filename, content_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        http_response, mock_context, mock_context_with, mock_gateway, mock_gateway_with,
+        png_response, test_app,
     };
 
-    if !std::path::Path::new(&thumbnail_filename).exists() {
-        debug!("Resizing image {} to {}x{}", &filename, &width, &height);
-        match image::open(&filename) {
-            Err(error) => {
-                error!("Couldn't open file {}: {error}", &filename)
+    #[test]
+    fn compress_text_above_threshold() {
+        let content_types = vec!["text/".to_string(), "application/json".to_string()];
+
+        assert!(should_compress(
+            Some("application/json"),
+            Some(4096),
+            1024,
+            &content_types
+        ));
+        assert!(should_compress(
+            Some("text/html; charset=utf-8"),
+            None,
+            1024,
+            &content_types
+        ));
+        assert!(!should_compress(
+            Some("application/json"),
+            Some(100),
+            1024,
+            &content_types
+        ));
+        assert!(!should_compress(
+            Some("image/png"),
+            Some(4096),
+            1024,
+            &content_types
+        ));
+        assert!(!should_compress(None, Some(4096), 1024, &content_types));
+    }
+
+    #[actix_web::test]
+    async fn send_precompressed_brotli() -> Result<(), anyhow::Error> {
+        let directory = tempfile::tempdir()?;
+        let filename = directory.path().join("metadata.json").display().to_string();
+        std::fs::write(&filename, br#"{"name":"plain"}"#)?;
+        std::fs::write(format!("{filename}.br"), b"brotli bytes")?;
+
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((header::ACCEPT_ENCODING, "gzip, br"))
+            .to_http_request();
+        let response = send_filename(&req, filename.clone(), "application/json".to_string()).await;
+        assert_eq!(
+            response.headers().get(header::CONTENT_ENCODING),
+            Some(&header::HeaderValue::from_static("br"))
+        );
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE),
+            Some(&header::HeaderValue::from_static("application/json"))
+        );
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert_eq!(body.as_ref(), b"brotli bytes");
+
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((header::ACCEPT_ENCODING, "gzip, br;q=0"))
+            .to_http_request();
+        let response = send_filename(&req, filename, "application/json".to_string()).await;
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert_eq!(body.as_ref(), br#"{"name":"plain"}"#);
+
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn limit_client_requests() {
+        let ctx = mock_context_with(vec![], |config| {
+            config.client_requests_per_second = Some(1);
+        })
+        .await;
+        let app = actix_web::test::init_service(test_app(ctx)).await;
+        let request = |path: &str| {
+            actix_web::test::TestRequest::get()
+                .uri(path)
+                .peer_addr("10.0.0.1:4000".parse().unwrap())
+                .to_request()
+        };
+
+        let response = actix_web::test::call_service(&app, request("/openapi.json")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = actix_web::test::call_service(&app, request("/openapi.json")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers().get(header::RETRY_AFTER),
+            Some(&header::HeaderValue::from_static("1"))
+        );
+        // Health checks aren't limited
+        let response = actix_web::test::call_service(&app, request("/health")).await;
+        assert_ne!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_web::test]
+    async fn describe_ipfs_route_options() {
+        let ctx = mock_context(vec![]).await;
+        let app = actix_web::test::init_service(test_app(ctx)).await;
+
+        let request = actix_web::test::TestRequest::with_uri(
+            "/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/image.png",
+        )
+        .method(Method::OPTIONS)
+        .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(header::ALLOW).unwrap(),
+            "GET, HEAD, OPTIONS"
+        );
+        let body: serde_json::Value = actix_web::test::read_body_json(response).await;
+        let parameters = body["get"]["parameters"].as_array().unwrap();
+        assert!(parameters
+            .iter()
+            .any(|parameter| parameter["name"] == "img-format"
+                && parameter["schema"]["enum"] == serde_json::json!(["png", "jpeg"])));
+    }
+
+    #[actix_web::test]
+    async fn redirect_signed_url_to_canonical() {
+        let ctx = mock_context_with(vec![], |config| {
+            config.redirect_to_canonical = true;
+            config.require_signed_urls = true;
+            config.url_signing_secret = Some("secret".to_string());
+        })
+        .await;
+        let app = actix_web::test::init_service(test_app(ctx)).await;
+        let cid = "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG";
+        let expires_at = chrono::Utc::now().timestamp() + 60;
+        let signature = url_signature::sign_url(
+            "secret",
+            &format!("{cid}/1.json"),
+            SignedParams::default(),
+            expires_at,
+        );
+        let get = |uri: &str| actix_web::test::TestRequest::get().uri(uri).to_request();
+
+        let response =
+            actix_web::test::call_service(&app, get(&format!("/ipfs/{cid}/1.json"))).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = actix_web::test::call_service(
+            &app,
+            get(&format!("/ipfs/{cid}/1.json?signature={signature}")),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .unwrap()
+            .to_string();
+        let canonical = ipfs_client::canonical_cid(cid).unwrap();
+        assert!(location.starts_with(&format!("/ipfs/{canonical}/1.json?signature={expires_at}.")));
+
+        // Accepted, there's no gateway to fetch it from
+        let response = actix_web::test::call_service(&app, get(&location)).await;
+        assert_ne!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn redirect_encoded_path_to_canonical() {
+        let ctx = mock_context_with(vec![], |config| {
+            config.redirect_to_canonical = true;
+        })
+        .await;
+        let app = actix_web::test::init_service(test_app(ctx)).await;
+        let cid = "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG";
+        let canonical = ipfs_client::canonical_cid(cid).unwrap();
+
+        let request = actix_web::test::TestRequest::get()
+            .uri(&format!("/ipfs/{cid}/My%20Caf%C3%A9%23%3F.png?img-auto=1"))
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(
+            response.headers().get(header::LOCATION).unwrap(),
+            &format!("/ipfs/{canonical}/My%20Caf%C3%A9%23%3F.png?img-auto=1")
+        );
+    }
+
+    async fn fetch_status(gateway: String, configure: impl FnOnce(&mut Settings)) -> StatusCode {
+        let ctx = mock_context_with(vec![gateway], configure).await;
+        let app = actix_web::test::init_service(test_app(ctx)).await;
+
+        let request = actix_web::test::TestRequest::get()
+            .uri("/ipfs/bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/status/1")
+            .to_request();
+        actix_web::test::call_service(&app, request).await.status()
+    }
+
+    #[actix_web::test]
+    async fn not_found_on_gateways_status() -> Result<(), anyhow::Error> {
+        let gateway = mock_gateway(http_response("404 Not Found", &[], b""), 0).await;
+
+        assert_eq!(fetch_status(gateway, |_| {}).await, StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn content_too_large_status() -> Result<(), anyhow::Error> {
+        let body = "x".repeat(100);
+        let announced = format!(
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: text/plain\r\nContent-Length: 100\r\n\r\n{body}"
+        );
+        let gateway = mock_gateway(announced.into_bytes(), 0).await;
+        let status = fetch_status(gateway, |config| config.max_content_length = 10).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Without Content-Length the download is aborted once past the limit
+        let unannounced = format!(
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: text/plain\r\n\r\n{body}"
+        );
+        let gateway = mock_gateway(unannounced.into_bytes(), 0).await;
+        let status = fetch_status(gateway, |config| config.max_content_length = 10).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn check_allowed_cid_after_conversion() -> Result<(), anyhow::Error> {
+        let gateway = mock_gateway(
+            http_response("200 OK", &[("Content-Type", "text/plain")], b"allowed"),
+            0,
+        )
+        .await;
+        let get = |uri: String| actix_web::test::TestRequest::get().uri(&uri).to_request();
+        let rejected = "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku";
+
+        for caching_enabled in [true, false] {
+            let ctx = mock_context_with(vec![gateway.clone()], |config| {
+                config.allowed_cid_prefixes = vec!["bafy".to_string()];
+                config.convert_cid_v0 = true;
+                config.redirect_to_canonical = false;
+                config.caching_enabled = caching_enabled;
+            })
+            .await;
+            let app = actix_web::test::init_service(test_app(ctx)).await;
+
+            // A CIDv0 is allowed by the prefix of its CIDv1
+            let cid = "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG";
+            let response =
+                actix_web::test::call_service(&app, get(format!("/ipfs/{cid}/allowed.txt"))).await;
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let response =
+                actix_web::test::call_service(&app, get(format!("/ipfs/{rejected}/1.txt"))).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn resize_sizes_from_one_fetch() -> Result<(), anyhow::Error> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Slow enough for both requests to be waiting on the same fetch
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let response = png_response(200, 200);
+        let gateway = mock_gateway_with(200, move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            response.clone()
+        })
+        .await;
+        let ctx = mock_context_with(vec![gateway], |config| {
+            config.min_resizable_dimension = 0;
+            config.permitted_resize_dimensions = [100, 50]
+                .into_iter()
+                .map(|size| Dimension {
+                    width: size,
+                    height: size,
+                })
+                .collect();
+        })
+        .await;
+        let app = actix_web::test::init_service(test_app(ctx)).await;
+
+        let url = "/ipfs/bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/image.png";
+        let request = |size: u32| {
+            actix_web::test::TestRequest::get()
+                .uri(&format!("{url}?img-width={size}&img-height={size}"))
+                .to_request()
+        };
+        let (large, small) = future::join(
+            actix_web::test::call_service(&app, request(100)),
+            actix_web::test::call_service(&app, request(50)),
+        )
+        .await;
+
+        for (response, size) in [(large, 100), (small, 50)] {
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = actix_web::test::read_body(response).await;
+            let thumbnail = image::load_from_memory(&body)?;
+            assert_eq!((thumbnail.width(), thumbnail.height()), (size, size));
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn negotiate_auto_format() -> Result<(), anyhow::Error> {
+        let gateway = mock_gateway(png_response(200, 200), 0).await;
+        let ctx = mock_context_with(vec![gateway], |config| {
+            config.min_resizable_dimension = 0;
+            config.permitted_resize_dimensions = vec![Dimension {
+                width: 100,
+                height: 100,
+            }];
+        })
+        .await;
+        let app = actix_web::test::init_service(test_app(ctx)).await;
+
+        let url = "/ipfs/bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/auto.png?img-width=100&img-height=100&img-auto=1";
+        for (accept, content_type) in [
+            (None, "image/png"),
+            (Some("image/jpeg, image/*;q=0.5"), "image/jpeg"),
+        ] {
+            let mut request = actix_web::test::TestRequest::get().uri(url);
+            if let Some(accept) = accept {
+                request = request.insert_header((header::ACCEPT, accept));
             }
-            Ok(img) => {
-                let thumbnail = img.resize(width, height, image::imageops::FilterType::Lanczos3);
+            let response = actix_web::test::call_service(&app, request.to_request()).await;
 
-                thumbnail
-                    .save(&thumbnail_filename)
-                    .expect("Saving image failed");
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().get(header::CONTENT_TYPE).unwrap(),
+                content_type
+            );
+            assert_eq!(response.headers().get(header::VARY).unwrap(), "accept");
+            let body = actix_web::test::read_body(response).await;
+            assert_eq!(image::load_from_memory(&body)?.width(), 100);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn format_server_timing() {
+        let timings = [
+            ("cache", std::time::Duration::from_micros(1250)),
+            ("fetch", std::time::Duration::from_millis(300)),
+        ];
+
+        assert_eq!(server_timing(&timings), "cache;dur=1.2, fetch;dur=300.0");
+        assert_eq!(server_timing(&[]), "");
+    }
+
+    #[test]
+    fn reject_malformed_query() {
+        let mut config = Settings::new().expect("Can't create configuration");
+        config.max_query_params = 3;
+        config.max_query_value_length = 8;
+
+        assert!(check_query(&config, "").is_ok());
+        assert!(check_query(&config, "img-width=100&img-height=100&dpr=2").is_ok());
+        assert!(check_query(&config, "a=1&b=2&c=3&d=4").is_err());
+        assert!(check_query(&config, "img-format=aaaaaaaaa").is_err());
+
+        assert!(web::Query::<ImageInfo>::from_query("img-width=100&dpr=2").is_ok());
+        assert!(web::Query::<ImageInfo>::from_query("img-width=100&unknown=1").is_err());
+        assert!(web::Query::<ImageInfo>::from_query("img-width=100&img-width=200").is_err());
+    }
+
+    #[tokio::test]
+    async fn override_content_type_for_admins() {
+        let mut ctx = AppContext::build_for_test().await;
+        ctx.config.admin_token = Some("secret".to_string());
+        let info = web::Query::<ImageInfo>::from_query("content-type=text/plain")
+            .expect("Can't parse query");
+
+        let admin = actix_web::test::TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_http_request();
+        let anonymous = actix_web::test::TestRequest::default().to_http_request();
+
+        assert_eq!(
+            content_type_override(&admin, &ctx, &info),
+            Some("text/plain".to_string())
+        );
+        assert_eq!(content_type_override(&anonymous, &ctx, &info), None);
+        for token in ["Bearer secreT", "Bearer secret2", "Bearer "] {
+            let wrong = actix_web::test::TestRequest::default()
+                .insert_header((header::AUTHORIZATION, token))
+                .to_http_request();
+            assert_eq!(content_type_override(&wrong, &ctx, &info), None);
+        }
+
+        let invalid =
+            web::Query::<ImageInfo>::from_query("content-type=plain").expect("Can't parse query");
+        assert_eq!(content_type_override(&admin, &ctx, &invalid), None);
+    }
+
+    #[actix_web::test]
+    async fn update_runtime_config() -> Result<(), anyhow::Error> {
+        let ctx = mock_context_with(vec!["http://127.0.0.1:1/ipfs".to_string()], |config| {
+            config.admin_token = Some("secret".to_string());
+        })
+        .await;
+        let app = actix_web::test::init_service(test_app(ctx.clone())).await;
+        let put_config = |body: serde_json::Value| {
+            actix_web::test::TestRequest::put()
+                .uri("/config")
+                .insert_header((header::AUTHORIZATION, "Bearer secret"))
+                .set_json(body)
+                .to_request()
+        };
+
+        let request = put_config(serde_json::json!({
+            "connect_timeout": 5,
+            "max_concurrent_fetches": 2,
+        }));
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(ctx.runtime.load().connect_timeout, 5);
+        // Shrinking waits for the surplus permits in the background
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(ctx.fetches.available_permits(), 2);
+
+        for body in [
+            serde_json::json!({ "ipfs_gateways": [] }),
+            serde_json::json!({ "ipfs_gateways": ["ipfs.io/ipfs"] }),
+            serde_json::json!({ "connect_timeout": 0 }),
+            serde_json::json!({ "pause_gateway_seconds": -1 }),
+            serde_json::json!({ "pause_gateway_seconds": i64::MAX }),
+            serde_json::json!({ "connect_timeout": 7, "max_concurrent_fetches": 0 }),
+        ] {
+            let response = actix_web::test::call_service(&app, put_config(body.clone())).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{body}");
+        }
+        assert_eq!(ctx.runtime.load().connect_timeout, 5);
+        assert_eq!(ctx.runtime.load().max_concurrent_fetches, 2);
+
+        let request = put_config(serde_json::json!({ "max_concurrent_fetches": 3 }));
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(ctx.fetches.available_permits(), 3);
+
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn vary_on_negotiated_responses() -> Result<(), anyhow::Error> {
+        let directory = tempfile::tempdir()?;
+        let plain = directory.path().join("plain.json").display().to_string();
+        let compressed = directory
+            .path()
+            .join("compressed.json")
+            .display()
+            .to_string();
+        std::fs::write(&plain, b"0123456789")?;
+        std::fs::write(&compressed, b"0123456789")?;
+        std::fs::write(format!("{compressed}.br"), b"brotli")?;
+        let varies = |response: &HttpResponse| {
+            response
+                .headers()
+                .get_all(header::VARY)
+                .filter_map(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let request = |accept_encoding: &str, range: Option<&str>| {
+            let mut request = actix_web::test::TestRequest::default()
+                .insert_header((header::ACCEPT_ENCODING, accept_encoding));
+            if let Some(range) = range {
+                request = request.insert_header((header::RANGE, range));
+            }
+            request.to_http_request()
+        };
+        let content_type = "application/json".to_string();
+
+        // Without a brotli sibling nothing depends on Accept-Encoding
+        let response = send_filename(&request("br", None), plain, content_type.clone()).await;
+        assert!(varies(&response).is_empty());
+        for (accept_encoding, range) in [
+            ("br", None),
+            ("gzip", None),
+            ("gzip", Some("bytes=0-1,4-5")),
+        ] {
+            let response = send_filename(
+                &request(accept_encoding, range),
+                compressed.clone(),
+                content_type.clone(),
+            )
+            .await;
+            assert_eq!(varies(&response), vec!["accept-encoding"]);
+        }
+
+        // The error page depends on Accept only when it's configured
+        let mut ctx = AppContext::build_for_test().await;
+        let html = actix_web::test::TestRequest::default()
+            .insert_header((header::ACCEPT, "text/html"))
+            .to_http_request();
+        let response = error_response(&html, &ctx, StatusCode::NOT_FOUND, "Error".to_string());
+        assert!(varies(&response).is_empty());
+
+        let error_page = directory.path().join("error.html");
+        std::fs::write(&error_page, "<p>{status} {message}</p>")?;
+        ctx.config.error_page_path = Some(error_page.display().to_string());
+        let plain_request = actix_web::test::TestRequest::default().to_http_request();
+        for req in [&html, &plain_request] {
+            let response = error_response(req, &ctx, StatusCode::NOT_FOUND, "Error".to_string());
+            assert_eq!(varies(&response), vec!["accept"]);
+        }
+
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn range_on_memory_cache_hit() -> Result<(), anyhow::Error> {
+        let mut ctx = AppContext::build_for_test().await;
+        ctx.mem_cache = crate::mem_cache::MemCache::new(1024 * 1024, 1024);
+        let ipfs_url =
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/memory.txt";
+        let filename = caching::caching_path(&ctx, ipfs_url, None, true).await?;
+        std::fs::write(&filename, b"0123456789")?;
+        entity::ipfs_object::update_entry(&ctx.db, ipfs_url, "text/plain", 10).await?;
+        let ctx = Arc::new(ctx);
+        let app = actix_web::test::init_service(test_app(ctx.clone())).await;
+
+        let uri = "/ipfs/bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/memory.txt";
+        // The first hit loads the file in memory, the next ones are served from there
+        for _ in 0..2 {
+            let request = actix_web::test::TestRequest::get().uri(uri).to_request();
+            let response = actix_web::test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            for name in [header::ETAG, header::LAST_MODIFIED, header::ACCEPT_RANGES] {
+                assert!(response.headers().contains_key(&name), "No {name}");
             }
+            assert_eq!(
+                actix_web::test::read_body(response).await,
+                Bytes::from_static(b"0123456789")
+            );
         }
+
+        let request = actix_web::test::TestRequest::get()
+            .uri(uri)
+            .insert_header((header::RANGE, "bytes=2-5"))
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let etag = response.headers().get(header::ETAG).cloned();
+        assert_eq!(
+            actix_web::test::read_body(response).await,
+            Bytes::from_static(b"2345")
+        );
+
+        let request = actix_web::test::TestRequest::get()
+            .uri(uri)
+            .insert_header((header::IF_NONE_MATCH, etag.expect("No ETag")))
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // Deleted out of band, the memory entry is dropped instead of serving a missing file
+        std::fs::remove_file(&filename)?;
+        assert!(ctx.mem_cache.get(ipfs_url).is_some());
+        let request = actix_web::test::TestRequest::get()
+            .uri(uri)
+            .insert_header((header::RANGE, "bytes=2-5"))
+            .to_request();
+        let response = actix_web::test::call_service(&app, request).await;
+        assert_ne!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(ctx.mem_cache.get(ipfs_url).is_none());
+
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        let response = send_filename(&req, filename, "text/plain".to_string()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
     }
-    let filename = thumbnail_filename;
-    let content_type = match requested_file_format.as_str() {
-        "jpeg" => "image/jpeg".to_string(),
 
-        "png" | _ => "image/png".to_string(),
-    };
+    #[actix_web::test]
+    async fn send_multiple_ranges() -> Result<(), anyhow::Error> {
+        let directory = tempfile::tempdir()?;
+        let filename = directory.path().join("ranges.bin").display().to_string();
+        std::fs::write(&filename, b"0123456789abcdef")?;
+
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((header::RANGE, "bytes=0-1,4-5"))
+            .to_http_request();
+        let response = send_filename(&req, filename.clone(), "application/pdf".to_string()).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        // Like an uncached file, removed before the body is sent
+        let removed = format!("{filename}.removed");
+        std::fs::rename(&filename, &removed)?;
 
-    Ok((filename, content_type))
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .expect("Not a multipart response");
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&body),
+            format!(
+                "--{boundary}\r\nContent-Type: application/pdf\r\nContent-Range: bytes 0-1/16\r\n\r\n01\r\n\
+                 --{boundary}\r\nContent-Type: application/pdf\r\nContent-Range: bytes 4-5/16\r\n\r\n45\r\n\
+                 --{boundary}--\r\n"
+            )
+        );
+
+        // A single range is left to NamedFile
+        std::fs::rename(&removed, &filename)?;
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((header::RANGE, "bytes=2-3"))
+            .to_http_request();
+        let response = send_filename(&req, filename, "application/pdf".to_string()).await;
+        assert_eq!(
+            response.headers().get(header::CONTENT_RANGE),
+            Some(&header::HeaderValue::from_static("bytes 2-3/16"))
+        );
+
+        Ok(())
+    }
 }
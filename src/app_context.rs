@@ -1,38 +1,72 @@
+use anyhow::{anyhow, Context};
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use migration::{Migrator, MigratorTrait};
 use sea_orm::{
     ConnectOptions, ConnectionTrait, Database, DatabaseBackend, DatabaseConnection, Statement,
 };
 use std::fs::File;
 use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
 
-use crate::config::Settings;
+use crate::access_times::AccessTimes;
+use crate::client_limits::ClientLimits;
+use crate::config::{RuntimeSettings, Settings};
+use crate::mem_cache::MemCache;
 
 pub struct AppContext {
     pub db: DatabaseConnection,
     pub config: Settings,
+    /// Settings which can be changed while running, initialized from `config`
+    pub runtime: ArcSwap<RuntimeSettings>,
+    /// Bounds concurrent cache writes independently of the network fan-out
+    pub disk_writes: Semaphore,
+    /// Bounds gateway fetches, see `max_concurrent_fetches`
+    pub fetches: Arc<Semaphore>,
+    /// Bounds background thumbnail generation
+    pub resizes: Arc<Semaphore>,
+    /// Cache hits not yet written to the database
+    pub access_times: AccessTimes,
+    pub mem_cache: MemCache,
+    /// Clients reused per gateway so their connections are pooled, with the connect
+    /// timeout and redirect limit they were built for
+    pub gateway_clients: DashMap<String, ((u64, usize), reqwest::Client)>,
+    /// Bounds requests to each gateway, see `max_connections_per_gateway`
+    pub gateway_connections: DashMap<String, Arc<Semaphore>>,
+    pub client_limits: Arc<ClientLimits>,
 }
 
 impl AppContext {
     pub async fn build() -> Self {
-        let config = Settings::new().expect("Can't create configuration");
+        match Self::try_build().await {
+            Ok(ctx) => ctx,
+            Err(error) => panic!("{error:#}"),
+        }
+    }
+
+    /// Like `build` but configuration and database errors are returned
+    pub async fn try_build() -> Result<Self, anyhow::Error> {
+        let config = Settings::new().context("Can't create configuration")?;
 
-        let database_url = std::env::var("DATABASE_URL").unwrap_or_else(|_| {
-            let filename = "objects.sqlite";
-            if !Path::new(filename).exists() {
-                File::create(filename).expect("Can't create DB");
+        let database_url = match std::env::var("DATABASE_URL") {
+            Ok(database_url) => database_url,
+            Err(_) => {
+                let filename = "objects.sqlite";
+                if !Path::new(filename).exists() {
+                    File::create(filename).context("Can't create DB")?;
+                }
+                "sqlite://objects.sqlite".to_string()
             }
-            "sqlite://objects.sqlite".to_string()
-        });
+        };
 
         let mut opt = ConnectOptions::new(database_url);
         opt.max_connections(config.db_max_connections)
             .min_connections(config.db_min_connections);
 
-        let db = match Database::connect(opt).await {
-            Err(err) => {
-                panic!("Could not connect to database: {err}");
-            }
-            Ok(db) => db,
-        };
+        let db = Database::connect(opt)
+            .await
+            .context("Could not connect to database")?;
 
         // For faster execution using multithread
         db.execute(Statement::from_string(
@@ -40,8 +74,181 @@ impl AppContext {
             "PRAGMA journal_mode=WAL;".to_owned(),
         ))
         .await
-        .expect("Can't set PRAGMA");
+        .context("Can't set PRAGMA")?;
+
+        Ok(Self::with_db(db, config))
+    }
+
+    /// In-memory database with migrations applied and a temporary cache directory,
+    /// so tests run isolated from each other and from the real database
+    #[cfg(test)]
+    pub async fn build_for_test() -> Self {
+        let mut config = Settings::new().expect("Can't create configuration");
+        let directory = tempfile::Builder::new()
+            .prefix("ipfs-proxy-test-")
+            .tempdir()
+            .expect("Can't create cache directory")
+            .into_path();
+        config.ipfs_cache_directory = directory.display().to_string();
+
+        // Every connection to `sqlite::memory:` opens a distinct database
+        let mut opt = ConnectOptions::new("sqlite::memory:".to_string());
+        opt.max_connections(1).min_connections(1);
+
+        let db = Database::connect(opt)
+            .await
+            .expect("Could not connect to database");
+        Migrator::up(&db, None).await.expect("Can't run migrations");
+
+        Self::with_db(db, config)
+    }
+
+    /// Apply pending migrations when `auto_migrate` is set, otherwise refuse to start
+    /// on a database the `migrate` bin hasn't brought up to date
+    pub async fn prepare_database(&self) -> Result<(), anyhow::Error> {
+        if self.config.auto_migrate {
+            Migrator::up(&self.db, None).await?;
+            return Ok(());
+        }
+
+        let pending = Migrator::get_pending_migrations(&self.db).await?;
+        if !pending.is_empty() {
+            return Err(anyhow!(
+                "The database has {} pending migrations, run the migrate bin or set auto_migrate",
+                pending.len()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Changes the permits of `fetches` from `from` to `to`. Fetches in flight keep
+    /// theirs, a lower limit applies as they finish.
+    pub fn resize_fetches(&self, from: usize, to: usize) {
+        if to > from {
+            self.fetches.add_permits(to - from);
+        } else if to < from {
+            let fetches = self.fetches.clone();
+            let surplus = (from - to) as u32;
+            tokio::spawn(async move {
+                if let Ok(permits) = fetches.acquire_many_owned(surplus).await {
+                    permits.forget();
+                }
+            });
+        }
+    }
+
+    fn with_db(db: DatabaseConnection, config: Settings) -> Self {
+        let disk_writes = Semaphore::new(config.max_concurrent_disk_writes);
+        let fetches = Arc::new(Semaphore::new(config.max_concurrent_fetches));
+        let resizes = Arc::new(Semaphore::new(config.max_concurrent_resizes));
+        let mem_cache = MemCache::new(config.mem_cache_bytes, config.mem_cache_max_entry_bytes);
+
+        let runtime = ArcSwap::from_pointee(RuntimeSettings::from(&config));
+
+        AppContext {
+            db,
+            config,
+            runtime,
+            disk_writes,
+            fetches,
+            resizes,
+            access_times: Default::default(),
+            mem_cache,
+            gateway_clients: Default::default(),
+            gateway_connections: Default::default(),
+            client_limits: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn unmigrated(auto_migrate: bool) -> AppContext {
+        let mut config = Settings::new().expect("Can't create configuration");
+        config.auto_migrate = auto_migrate;
+
+        let mut opt = ConnectOptions::new("sqlite::memory:".to_string());
+        opt.max_connections(1).min_connections(1);
+        let db = Database::connect(opt)
+            .await
+            .expect("Could not connect to database");
+
+        AppContext::with_db(db, config)
+    }
+
+    #[tokio::test]
+    async fn refuse_unmigrated_database() {
+        assert!(unmigrated(false).await.prepare_database().await.is_err());
+        assert!(AppContext::build_for_test()
+            .await
+            .prepare_database()
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn auto_migrate_database() -> Result<(), anyhow::Error> {
+        let ctx = unmigrated(true).await;
+        ctx.prepare_database().await?;
+
+        assert!(Migrator::get_pending_migrations(&ctx.db).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn encode_legacy_remote_urls() -> Result<(), anyhow::Error> {
+        use entity::ipfs_object;
+        use sea_orm::{ActiveModelTrait, ActiveValue, EntityTrait, QueryOrder};
+
+        let ctx = unmigrated(true).await;
+        // Up to the migration adding `content_hash`
+        Migrator::up(&ctx.db, Some(5)).await?;
+
+        let now = chrono::Utc::now().naive_utc();
+        let cid = "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+        for (remote_url, minutes_ago) in [
+            (format!("ipfs://{cid}/My File.png"), 1),
+            (format!("ipfs://{cid}/Café.png"), 5),
+            (format!("ipfs://{cid}/Caf%C3%A9.png"), 10),
+            (format!("ipfs://{cid}/plain.png"), 1),
+        ] {
+            ipfs_object::ActiveModel {
+                remote_url: ActiveValue::set(remote_url),
+                cached_at: ActiveValue::set(now),
+                last_accessed_at: ActiveValue::set(now - chrono::Duration::minutes(minutes_ago)),
+                content_type: ActiveValue::set("image/png".to_string()),
+                content_size: ActiveValue::set(1),
+                ..Default::default()
+            }
+            .insert(&ctx.db)
+            .await?;
+        }
+
+        ctx.prepare_database().await?;
+
+        let rows = ipfs_object::Entity::find()
+            .order_by_asc(ipfs_object::Column::Id)
+            .all(&ctx.db)
+            .await?;
+        let urls = rows
+            .iter()
+            .map(|row| row.remote_url.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            [
+                format!("ipfs://{cid}/My%20File.png"),
+                format!("ipfs://{cid}/Caf%C3%A9.png"),
+                format!("ipfs://{cid}/plain.png"),
+            ]
+        );
+        // The twins are merged, keeping the latest use
+        assert_eq!(rows[1].last_accessed_at, now - chrono::Duration::minutes(5));
 
-        AppContext { db, config }
+        Ok(())
     }
 }
@@ -1,39 +1,131 @@
 use chrono::{Duration, Utc};
+use clap::Parser;
 use ipfs_proxy::{
-    caching::delete_caching,
+    caching::{delete_caching, delete_stale_partials},
     telemetry::{get_subscriber, init_subscriber},
+    thumbnails::evict_thumbnails,
     AppContext,
 };
 
-use sea_orm::{entity::prelude::*, TransactionTrait};
+use futures::future::join_all;
+use sea_orm::{entity::prelude::*, QuerySelect, TransactionTrait};
 use std::sync::Arc;
-use tracing::error;
+use tokio::sync::Semaphore;
+use tracing::{error, info};
+
+#[derive(Parser, Debug)]
+#[clap(author, version)]
+#[clap(about = "This will delete cached IPFS files not accessed recently.")]
+struct Args {
+    /// How many entries are deleted per transaction
+    #[clap(short, long, value_parser = clap::value_parser!(u64).range(1..))]
+    batch_size: Option<u64>,
+
+    /// How many files are deleted in parallel
+    #[clap(short, long, value_parser = clap::value_parser!(u32).range(1..))]
+    concurrency: Option<u32>,
+
+    /// Remove every thumbnail under `thumbnail_directory`, originals are kept
+    #[clap(long, action)]
+    purge_thumbnails: bool,
+}
 
 #[tokio::main]
 pub async fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+
     let subscriber = get_subscriber("info");
     init_subscriber(subscriber);
 
     let ctx = Arc::new(AppContext::build().await);
-    let txn = ctx.db.begin().await?;
-    let date = Utc::now().naive_utc() - Duration::days(ctx.config.delete_after_days);
 
-    let ipfs_objects = entity::ipfs_object::Entity::find()
-        .filter(entity::ipfs_object::Column::LastAccessedAt.lt(date))
-        .all(&txn)
-        .await?;
-
-    for ipfs_object in ipfs_objects {
-        if let Err(error) = delete_caching(ctx.clone(), &ipfs_object.remote_url).await {
-            error!(
-                "Can't delete file related to {}: {}",
-                &ipfs_object.remote_url, error
-            );
+    if args.purge_thumbnails {
+        match ctx.config.full_thumbnail_directory() {
+            Some(directory) => match tokio::fs::remove_dir_all(&directory).await {
+                Ok(()) => info!("Purged thumbnails in {directory}"),
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                    info!("No thumbnails to purge in {directory}")
+                }
+                Err(error) => {
+                    error!("Can't purge thumbnails in {directory}: {error}");
+                    std::process::exit(1);
+                }
+            },
+            None => error!("No thumbnail_directory configured, thumbnails live with originals"),
         }
-        ipfs_object.delete(&txn).await?;
+
+        return Ok(());
     }
 
-    txn.commit().await?;
+    let deleted = delete_stale_partials(&ctx.config).await?;
+    info!("Deleted {deleted} interrupted downloads older than partial_max_age_hours");
+
+    if ctx.config.max_thumbnail_cache_bytes.is_some() {
+        let evicted = evict_thumbnails(&ctx).await?;
+        info!("Deleted {evicted} thumbnails above max_thumbnail_cache_bytes");
+    }
+
+    let date = Utc::now().naive_utc() - Duration::days(ctx.config.delete_after_days);
+    let batch_size = args.batch_size.unwrap_or(100);
+
+    // how many parallel deletions at a time
+    let sem = Arc::new(Semaphore::new(args.concurrency.unwrap_or(10) as usize));
+
+    loop {
+        let ipfs_objects = entity::ipfs_object::Entity::find()
+            .filter(entity::ipfs_object::Column::LastAccessedAt.lt(date))
+            .limit(batch_size)
+            .all(&ctx.db)
+            .await?;
+
+        if ipfs_objects.is_empty() {
+            break;
+        }
+
+        let deletions = ipfs_objects.iter().map(|ipfs_object| {
+            let sem = sem.clone();
+            let ctx = ctx.clone();
+            let remote_url = ipfs_object.remote_url.clone();
+
+            tokio::spawn(async move {
+                let _permit = sem.acquire_owned().await;
+
+                if let Err(error) = delete_caching(ctx, &remote_url).await {
+                    error!("Can't delete file related to {}: {}", &remote_url, error);
+                }
+            })
+        });
+        join_all(deletions).await;
+
+        // Small transactions so the server isn't locked out of SQLite for long
+        let txn = ctx.db.begin().await?;
+        entity::ipfs_object::Entity::delete_many()
+            .filter(
+                entity::ipfs_object::Column::Id
+                    .is_in(ipfs_objects.iter().map(|ipfs_object| ipfs_object.id)),
+            )
+            .exec(&txn)
+            .await?;
+        txn.commit().await?;
+
+        info!("Deleted {} cached entries", ipfs_objects.len());
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_zero_sizes() {
+        let args = Args::try_parse_from(["cleanup", "--batch-size", "5", "--concurrency", "2"])
+            .expect("Can't parse arguments");
+        assert_eq!(args.batch_size, Some(5));
+        assert_eq!(args.concurrency, Some(2));
+
+        assert!(Args::try_parse_from(["cleanup", "--batch-size", "0"]).is_err());
+        assert!(Args::try_parse_from(["cleanup", "--concurrency", "0"]).is_err());
+    }
+}
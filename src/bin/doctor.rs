@@ -0,0 +1,72 @@
+use clap::Parser;
+use ipfs_proxy::AppContext;
+use ipfs_proxy::{caching::check_cache_directory, config::Settings, ipfs_client::check_gateway};
+use migration::{Migrator, MigratorTrait};
+
+#[derive(Parser, Debug)]
+#[clap(author, version)]
+#[clap(about = "Check the configuration, database, cache directory and gateways before deploying.")]
+struct Args {}
+
+#[tokio::main]
+pub async fn main() -> Result<(), anyhow::Error> {
+    Args::parse();
+
+    if let Err(error) = Settings::new() {
+        report("configuration", Err(error.into()));
+        std::process::exit(1);
+    }
+    report("configuration", Ok(()));
+
+    let ctx = match AppContext::try_build().await {
+        Ok(ctx) => ctx,
+        Err(error) => {
+            report("database connection", Err(error));
+            std::process::exit(1);
+        }
+    };
+    let mut healthy = report("database connection", Ok(()));
+
+    let migrations = match Migrator::get_pending_migrations(&ctx.db).await {
+        Ok(pending) if pending.is_empty() || ctx.config.auto_migrate => Ok(()),
+        Ok(pending) => Err(anyhow::anyhow!(
+            "{} pending, run the migrate bin or set auto_migrate",
+            pending.len()
+        )),
+        Err(error) => Err(error.into()),
+    };
+    healthy &= report("migrations", migrations);
+
+    if ctx.config.caching_enabled {
+        healthy &= report("cache directory", check_cache_directory(&ctx.config));
+    }
+
+    let runtime = ctx.runtime.load_full();
+    for ipfs_gateway in runtime
+        .ipfs_gateways
+        .iter()
+        .chain(&ctx.config.fallback_proxy_urls)
+    {
+        healthy &= report(ipfs_gateway, check_gateway(&ctx, ipfs_gateway).await);
+    }
+
+    if !healthy {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Print a line of the report, returns whether the check passed
+fn report(check: &str, result: Result<(), anyhow::Error>) -> bool {
+    match result {
+        Ok(()) => {
+            println!("[ok]   {check}");
+            true
+        }
+        Err(error) => {
+            println!("[FAIL] {check}: {error:#}");
+            false
+        }
+    }
+}
@@ -19,6 +19,7 @@ struct Args {
     #[clap(short, long, value_parser)]
     file: String,
 
+    /// Defaults to `max_concurrent_fetches`
     #[clap(short, long, value_parser)]
     threads_count: Option<usize>,
 }
@@ -33,15 +34,15 @@ pub async fn main() -> Result<(), anyhow::Error> {
     let ctx = Arc::new(AppContext::build().await);
 
     // how many parallel requests at a time
-    let sem = Arc::new(Semaphore::new(args.threads_count.unwrap_or(50)));
+    let threads_count = args
+        .threads_count
+        .unwrap_or(ctx.config.max_concurrent_fetches);
+    let sem = Arc::new(Semaphore::new(threads_count));
 
     let join_handlers: Arc<Mutex<HashMap<usize, JoinHandle<()>>>> =
         Arc::new(Mutex::new(HashMap::new()));
 
-    info!(
-        "Will fetch urls with {} at a time.",
-        args.threads_count.unwrap_or(50)
-    );
+    info!("Will fetch urls with {threads_count} at a time.");
     if let Ok(lines) = read_lines(args.file) {
         for (index, line) in lines.enumerate() {
             if let Ok(ipfs_url) = line {
@@ -92,6 +93,8 @@ pub async fn main() -> Result<(), anyhow::Error> {
         sleep(Duration::from_millis(500)).await;
     }
 
+    ctx.access_times.flush(&ctx.db).await?;
+
     Ok(())
 }
 
@@ -1,5 +1,6 @@
 use ipfs_proxy::actix_server;
 use ipfs_proxy::app_context::AppContext;
+use ipfs_proxy::caching;
 use ipfs_proxy::telemetry::{get_subscriber, init_subscriber};
 
 use std::net::TcpListener;
@@ -10,6 +11,10 @@ pub async fn main() -> Result<(), anyhow::Error> {
     init_subscriber(subscriber);
 
     let ctx = AppContext::build().await;
+    ctx.prepare_database().await?;
+    if ctx.config.caching_enabled {
+        caching::check_cache_directory(&ctx.config)?;
+    }
 
     let ip = "0.0.0.0";
     let port = ctx.config.server_port;
@@ -0,0 +1,173 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use ipfs_proxy::{
+    caching::{caching_path, ipfs_url_from_path, is_temp_file},
+    telemetry::{get_subscriber, init_subscriber},
+    thumbnails::thumbnail_source,
+    AppContext,
+};
+
+use sea_orm::{entity::prelude::*, ActiveValue};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+#[derive(Parser, Debug)]
+#[clap(author, version)]
+#[clap(about = "This will add database entries for cached IPFS files missing from it.")]
+struct Args {
+    /// Only report the entries which would be added
+    #[clap(long, action)]
+    dry_run: bool,
+}
+
+#[tokio::main]
+pub async fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+
+    let subscriber = get_subscriber("info");
+    init_subscriber(subscriber);
+
+    let ctx = AppContext::build().await;
+    if ctx.config.flat_cache {
+        return Err(anyhow!(
+            "flat_cache filenames are hashes of the urls, which can't be recovered"
+        ));
+    }
+
+    let mut files = vec![];
+    list_files(
+        Path::new(&ctx.config.full_ipfs_cache_directory()),
+        &mut files,
+    )?;
+
+    let (mut added, mut indexed, mut skipped) = (0, 0, 0);
+    for file in files.iter().filter(|file| !is_derived(file)) {
+        let filename = file.display().to_string();
+        let Some(remote_url) = ipfs_url_from_path(&ctx.config, &filename) else {
+            warn!("Can't tell the url of {filename}");
+            skipped += 1;
+            continue;
+        };
+        let content_type = guess_content_type(file);
+
+        // Only files at the path the url would be cached at, which also checks the CID
+        match caching_path(&ctx, &remote_url, Some(content_type.clone()), false).await {
+            Ok(path) if Path::new(&path) == file => {}
+            _ => {
+                warn!("{filename} isn't where {remote_url} would be cached");
+                skipped += 1;
+                continue;
+            }
+        }
+
+        let existing = entity::ipfs_object::Entity::find()
+            .filter(entity::ipfs_object::Column::RemoteUrl.eq(remote_url.as_str()))
+            .one(&ctx.db)
+            .await?;
+        if existing.is_some() {
+            indexed += 1;
+            continue;
+        }
+
+        added += 1;
+        if args.dry_run {
+            info!("Would add {remote_url} as {content_type}");
+            continue;
+        }
+
+        let metadata = std::fs::metadata(file)?;
+        let modified_at = DateTime::<Utc>::from(metadata.modified()?).naive_utc();
+        entity::ipfs_object::ActiveModel {
+            remote_url: ActiveValue::set(remote_url.clone()),
+            cached_at: ActiveValue::set(modified_at),
+            last_accessed_at: ActiveValue::set(modified_at),
+            content_type: ActiveValue::set(content_type.clone()),
+            content_size: ActiveValue::set(metadata.len() as i64),
+            ..Default::default()
+        }
+        .insert(&ctx.db)
+        .await?;
+        info!("Added {remote_url} as {content_type}");
+    }
+
+    info!(
+        "{} {added} entries, {indexed} already in the database, {skipped} files skipped",
+        if args.dry_run { "Would add" } else { "Added" }
+    );
+
+    Ok(())
+}
+
+/// Sniffed from the content, then the extension. NFT metadata is often JSON without one.
+fn guess_content_type(file: &Path) -> String {
+    if let Ok(Some(kind)) = infer::get_from_path(file) {
+        return kind.mime_type().to_string();
+    }
+
+    if let Some(mime) = mime_guess::from_path(file).first() {
+        return mime.essence_str().to_string();
+    }
+
+    let is_json = std::fs::read(file)
+        .map(|bytes| serde_json::from_slice::<serde_json::Value>(&bytes).is_ok())
+        .unwrap_or_default();
+    if is_json {
+        "application/json".to_string()
+    } else {
+        mime::APPLICATION_OCTET_STREAM.to_string()
+    }
+}
+
+/// Downloads in progress, precompressed siblings and thumbnails next to their original
+fn is_derived(file: &Path) -> bool {
+    let name = file
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    if is_temp_file(file) || (name.ends_with(".br") && file.with_extension("").is_file()) {
+        return true;
+    }
+
+    thumbnail_source(name).is_some_and(|source| file.with_file_name(source).is_file())
+}
+
+fn list_files(directory: &Path, files: &mut Vec<PathBuf>) -> Result<(), std::io::Error> {
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_derived_files() -> Result<(), std::io::Error> {
+        let directory = tempfile::tempdir()?;
+        let original = directory.path().join("image.png");
+        std::fs::write(&original, b"png")?;
+
+        for name in [
+            "image.png-100x100.png",
+            "image.png-100x100.jpeg",
+            "image.png-100x100-q80.jpeg",
+        ] {
+            assert!(is_derived(&directory.path().join(name)), "{name}");
+        }
+        assert!(!is_derived(&original));
+        // Same shape, but without the original next to it
+        assert!(!is_derived(
+            &directory.path().join("other.png-100x100-q80.jpeg")
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use clap::Parser;
+use ipfs_proxy::{
+    caching::{caching_path, delete_caching, is_temp_file},
+    ipfs_client::{check_ipfs_url, content_matches_cid, fetch_ipfs_data},
+    telemetry::{get_subscriber, init_subscriber},
+    AppContext,
+};
+
+use sea_orm::{entity::prelude::*, PaginatorTrait};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+
+#[derive(Parser, Debug)]
+#[clap(author, version)]
+#[clap(about = "This will report cached IPFS files which are missing, orphaned or corrupted.")]
+struct Args {
+    /// Check files of raw CIDs against their hash, other CIDs can't be checked
+    #[clap(long, action)]
+    check_cids: bool,
+
+    /// Check files against the SHA-256 recorded with `hash_content`
+    #[clap(long, action)]
+    check_hashes: bool,
+
+    /// Delete orphaned files, fetch missing and corrupted ones again
+    #[clap(long, action)]
+    repair: bool,
+
+    /// How many entries are read per query
+    #[clap(short, long, value_parser)]
+    batch_size: Option<u64>,
+
+    /// Files modified this many minutes before the check started or later aren't
+    /// orphans yet, the server may not have recorded them. Defaults to 10.
+    #[clap(long, value_parser)]
+    grace_minutes: Option<u64>,
+}
+
+#[tokio::main]
+pub async fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+
+    let subscriber = get_subscriber("info");
+    init_subscriber(subscriber);
+
+    let ctx = Arc::new(AppContext::build().await);
+
+    // Taken before reading the database, a file renamed into place while it's read
+    // gets its row afterwards
+    let grace = Duration::from_secs(args.grace_minutes.unwrap_or(10) * 60);
+    let cutoff = SystemTime::now()
+        .checked_sub(grace)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    let mut known_files = HashSet::new();
+    let mut missing = vec![];
+    let mut corrupted = vec![];
+    let mut checked = 0;
+
+    let mut pages =
+        entity::ipfs_object::Entity::find().paginate(&ctx.db, args.batch_size.unwrap_or(1000));
+    while let Some(ipfs_objects) = pages.fetch_and_next().await? {
+        for ipfs_object in ipfs_objects {
+            checked += 1;
+            let remote_url = ipfs_object.remote_url;
+            let filename = match caching_path(
+                &ctx,
+                &remote_url,
+                Some(ipfs_object.content_type.clone()),
+                false,
+            )
+            .await
+            {
+                Ok(filename) => filename,
+                Err(error) => {
+                    error!("Can't build the cache filename of {remote_url}: {error}");
+                    continue;
+                }
+            };
+
+            if !Path::new(&filename).is_file() {
+                warn!("Missing {filename} for {remote_url}");
+                missing.push(remote_url);
+                continue;
+            }
+
+            if args.check_cids && check_content(&remote_url, &filename) == Some(false) {
+                warn!("{filename} doesn't match the CID of {remote_url}");
+                corrupted.push(remote_url.clone());
+            }
+
+            if args.check_hashes
+                && check_hash(ipfs_object.content_hash.as_deref(), &filename) == Some(false)
+            {
+                warn!("{filename} doesn't match the hash recorded for {remote_url}");
+                corrupted.push(remote_url.clone());
+            }
+
+            known_files.insert(PathBuf::from(filename));
+        }
+    }
+
+    for thumbnail in entity::thumbnail::Entity::find().all(&ctx.db).await? {
+        known_files.insert(PathBuf::from(thumbnail.filename));
+    }
+
+    let mut files = vec![];
+    list_files(
+        Path::new(&ctx.config.full_ipfs_cache_directory()),
+        &mut files,
+    )?;
+    let orphans = files
+        .into_iter()
+        .filter(|file| !known_files.contains(file) && !is_transient(file, &known_files))
+        .filter(|file| modified_before(file, cutoff))
+        .collect::<Vec<_>>();
+    for orphan in &orphans {
+        warn!("Orphaned file {}", orphan.display());
+    }
+
+    info!(
+        "Checked {checked} entries: {} missing, {} corrupted, {} orphaned files",
+        missing.len(),
+        corrupted.len(),
+        orphans.len()
+    );
+
+    if !args.repair {
+        return Ok(());
+    }
+
+    for orphan in &orphans {
+        if let Err(error) = std::fs::remove_file(orphan) {
+            error!("Can't delete {}: {error}", orphan.display());
+        }
+    }
+
+    for remote_url in &corrupted {
+        if let Err(error) = delete_caching(ctx.clone(), remote_url).await {
+            error!("Can't delete files related to {remote_url}: {error}");
+        }
+    }
+
+    for remote_url in missing.iter().chain(&corrupted) {
+        match fetch_ipfs_data(ctx.clone(), remote_url).await {
+            Ok(_) => info!("Fetched {remote_url} again"),
+            Err(error) => error!("Can't fetch {remote_url}: {error}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Only files of a whole CID can be hashed, `None` when it can't be checked
+fn check_content(remote_url: &str, filename: &str) -> Option<bool> {
+    let base_uri = check_ipfs_url(remote_url).ok()?;
+    let cid = base_uri.strip_suffix('/').unwrap_or(&base_uri);
+    if cid.contains('/') {
+        return None;
+    }
+
+    content_matches_cid(cid, &std::fs::read(filename).ok()?)
+}
+
+/// `None` when no hash was recorded for the file
+fn check_hash(content_hash: Option<&str>, filename: &str) -> Option<bool> {
+    let content_hash = content_hash?;
+    let bytes = std::fs::read(filename).ok()?;
+
+    Some(hex::encode(Sha256::digest(bytes)) == content_hash)
+}
+
+/// Downloads in progress and precompressed siblings aren't in the database
+fn is_transient(file: &Path, known_files: &HashSet<PathBuf>) -> bool {
+    is_temp_file(file)
+        || (file.extension().map(|extension| extension == "br") == Some(true)
+            && known_files.contains(&file.with_extension("")))
+}
+
+/// Files without a readable modification time are kept too
+fn modified_before(file: &Path, cutoff: SystemTime) -> bool {
+    std::fs::metadata(file)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| modified < cutoff)
+}
+
+fn list_files(directory: &Path, files: &mut Vec<PathBuf>) -> Result<(), std::io::Error> {
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_recently_written_orphans() -> Result<(), std::io::Error> {
+        let directory = tempfile::tempdir()?;
+        let file = directory.path().join("renamed-into-place.json");
+        std::fs::write(&file, b"{}")?;
+
+        let cutoff = SystemTime::now() - Duration::from_secs(600);
+        assert!(!modified_before(&file, cutoff));
+        assert!(modified_before(
+            &file,
+            SystemTime::now() + Duration::from_secs(1)
+        ));
+        assert!(!modified_before(
+            &directory.path().join("gone.json"),
+            cutoff
+        ));
+
+        Ok(())
+    }
+}
@@ -1,36 +1,177 @@
-use async_recursion::async_recursion;
+use anyhow::{anyhow, Context};
+use chrono::{Duration, Utc};
 use futures::StreamExt;
+use nix::errno::Errno;
+use nix::sys::statvfs::statvfs;
 use sea_orm::entity::prelude::*;
-use std::io::prelude::*;
+use sha2::{Digest, Sha256};
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::pin::Pin;
 use std::sync::Arc;
 use tempfile::Builder;
 use tokio::fs;
-use tracing::debug;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tracing::{debug, error, Span};
 
-use crate::ipfs_client::check_ipfs_url;
+use crate::config::{Settings, MAX_CACHE_MAX_AGE_SECONDS};
+use crate::ipfs_client::{check_ipfs_url, normalize_ipfs_url};
+use crate::ipfs_client::{decode_path_segment, encode_path_segment, ContentTooLarge};
+use crate::mem_cache::MemEntry;
 use crate::AppContext;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Data {
     pub content_type: Option<String>,
     pub filename: Option<String>,
+    /// The file is outside the cache, it must be removed once served
+    pub uncached: bool,
+    /// Content of small files held by the memory cache
+    pub bytes: Option<bytes::Bytes>,
+    /// Gateway headers listed in `forward_headers`
+    pub headers: Vec<(String, String)>,
+    /// Hex SHA-256 computed while writing the file, see `hash_content`
+    pub content_hash: Option<String>,
+    /// Time spent waiting for the gateways, `None` for cache hits
+    pub fetch_duration: Option<std::time::Duration>,
+}
+
+/// Headers are stored one `name: value` per line
+pub fn encode_headers(headers: &[(String, String)]) -> Option<String> {
+    if headers.is_empty() {
+        return None;
+    }
+
+    Some(
+        headers
+            .iter()
+            .map(|(name, value)| format!("{name}: {value}"))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
+}
+
+pub fn decode_headers(headers: Option<&str>) -> Vec<(String, String)> {
+    headers
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_once(": "))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+/// The cache filesystem is full, the request fails but the proxy is fine
+#[derive(Debug)]
+pub struct DiskFull(pub std::io::Error);
+
+impl std::fmt::Display for DiskFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cache disk is full: {}", self.0)
+    }
+}
+
+impl std::error::Error for DiskFull {}
+
+/// Disk full errors become `DiskFull`, permission errors are configuration
+/// mistakes and get logged loudly, anything else is passed through
+pub fn classify_io_error(error: std::io::Error) -> anyhow::Error {
+    match error.raw_os_error().map(Errno::from_i32) {
+        Some(Errno::ENOSPC) | Some(Errno::EDQUOT) => DiskFull(error).into(),
+        Some(Errno::EACCES) | Some(Errno::EPERM) | Some(Errno::EROFS) => {
+            error!(
+                "!!! Can't write to the cache, check ipfs_cache_directory permissions: {error} !!!"
+            );
+            error.into()
+        }
+        _ => error.into(),
+    }
+}
+
+/// Fail at startup when the cache directory can't be written to
+pub fn check_cache_directory(config: &Settings) -> Result<(), anyhow::Error> {
+    let directory = config.full_ipfs_cache_directory();
+
+    std::fs::create_dir_all(&directory)
+        .and_then(|_| Builder::new().tempfile_in(&directory).map(|_| ()))
+        .map_err(|error| anyhow!("Cache directory {directory} isn't writable: {error}"))
 }
 
-#[async_recursion]
 pub async fn get_caching(
     ctx: Arc<AppContext>,
     ipfs_url: &str,
 ) -> Result<Option<Data>, anyhow::Error> {
-    let filename = caching_filename(
-        ipfs_url,
-        &ctx.config.full_ipfs_cache_directory(),
-        None,
-        false,
-    )
-    .await?;
+    lookup_caching(ctx, ipfs_url, false).await
+}
+
+/// Like `get_caching` but files older than `cache_max_age_seconds` are returned too,
+/// for `serve_stale_on_error`
+pub async fn get_stale_caching(
+    ctx: Arc<AppContext>,
+    ipfs_url: &str,
+) -> Result<Option<Data>, anyhow::Error> {
+    lookup_caching(ctx, ipfs_url, true).await
+}
+
+async fn lookup_caching(
+    ctx: Arc<AppContext>,
+    ipfs_url: &str,
+    allow_stale: bool,
+) -> Result<Option<Data>, anyhow::Error> {
+    for variant in lookup_variants(ipfs_url) {
+        if let Some(data) = lookup_variant(ctx.clone(), &variant, allow_stale).await? {
+            return Ok(Some(data));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Normalized urls an entry of `ipfs_url` can be cached under, the requested form first
+/// then the same url with or without its trailing slash. Listings fetched without a
+/// slash are recorded with one, older entries may lack it.
+pub fn lookup_variants(ipfs_url: &str) -> Vec<String> {
+    let normalized = normalize_ipfs_url(ipfs_url);
+
+    let other = match normalized.strip_suffix('/') {
+        Some(bare) => bare.to_string(),
+        None => format!("{normalized}/"),
+    };
+
+    vec![normalized, other]
+}
+
+#[tracing::instrument(skip(ctx), fields(filename, content_type))]
+async fn lookup_variant(
+    ctx: Arc<AppContext>,
+    ipfs_url: &str,
+    allow_stale: bool,
+) -> Result<Option<Data>, anyhow::Error> {
+    if let Some(entry) = ctx.mem_cache.get(ipfs_url) {
+        // The file can be deleted by cleanup or verify while its entry is still in memory
+        if !Path::new(&entry.filename).is_file() {
+            debug!(
+                "{} is gone, evicting {ipfs_url} from memory",
+                entry.filename
+            );
+            ctx.mem_cache.remove(ipfs_url);
+            return Ok(None);
+        }
+
+        debug!("Memory cache hit for {ipfs_url}");
+        return Ok(Some(Data {
+            content_type: entry.content_type,
+            filename: Some(entry.filename),
+            uncached: false,
+            bytes: Some(entry.bytes),
+            headers: entry.headers,
+            content_hash: None,
+            fetch_duration: None,
+        }));
+    }
+
+    let filename = caching_path(&ctx, ipfs_url, None, false).await?;
     let filename = filename.as_str();
+    Span::current().record("filename", filename);
 
     debug!("Looking for {filename}");
     if Path::new(filename).is_file() {
@@ -40,63 +181,505 @@ pub async fn get_caching(
             .filter(entity::ipfs_object::Column::RemoteUrl.eq(ipfs_url))
             .one(&ctx.db)
             .await?;
+        let recorded_size = object.as_ref().map(|object| object.content_size);
+        if !is_complete(bytes.len() as i64, recorded_size) {
+            debug!("{filename} doesn't match its recorded size {recorded_size:?}, fetching again");
+            return Ok(None);
+        }
+        let expires_at = object.as_ref().and_then(|object| {
+            let max_age = ctx.config.max_age_seconds(&object.content_type)?;
+            // Validated already, clamped so the addition can't panic either way
+            let max_age = max_age.clamp(0, MAX_CACHE_MAX_AGE_SECONDS);
+            object
+                .cached_at
+                .checked_add_signed(Duration::seconds(max_age))
+        });
+        let expired = expires_at
+            .map(|expires_at| Utc::now().naive_utc() > expires_at)
+            .unwrap_or_default();
+        if expired && !allow_stale {
+            debug!("{filename} is past its max age, fetching again");
+            return Ok(None);
+        }
+        let headers = decode_headers(
+            object
+                .as_ref()
+                .and_then(|object| object.forwarded_headers.as_deref()),
+        );
+        let content_hash = object
+            .as_ref()
+            .and_then(|object| object.content_hash.clone());
         let content_type = match object {
             Some(object) => Some(object.content_type),
             None => infer::get(&bytes).map(|k| k.mime_type().to_string()),
         };
+        let content_type = ctx.config.fallback_content_type(ipfs_url, content_type);
+        if let Some(content_type) = &content_type {
+            Span::current().record("content_type", content_type.as_str());
+        }
+
+        let bytes = (!expired && ctx.mem_cache.accepts(bytes.len())).then(|| {
+            let bytes = bytes::Bytes::from(bytes);
+            ctx.mem_cache.insert(
+                ipfs_url,
+                MemEntry {
+                    content_type: content_type.clone(),
+                    filename: filename.to_string(),
+                    bytes: bytes.clone(),
+                    headers: headers.clone(),
+                    expires_at,
+                },
+            );
+            bytes
+        });
 
         let data = Data {
             content_type,
             filename: Some(filename.to_string()),
+            uncached: false,
+            bytes,
+            headers,
+            content_hash,
+            fetch_duration: None,
         };
 
         return Ok(Some(data));
     }
 
-    if !ipfs_url.ends_with('/') {
-        return get_caching(ctx, &format!("{ipfs_url}/")).await;
-    }
-
     Ok(None)
 }
 
+/// The database entry is written once the whole file is, so a size mismatch is an
+/// interrupted write. Without an entry an empty file can't be told from a failed write,
+/// only a recorded size of 0 makes it a genuinely empty file.
+fn is_complete(size: i64, recorded_size: Option<i64>) -> bool {
+    match recorded_size {
+        Some(recorded_size) => size == recorded_size,
+        None => size > 0,
+    }
+}
+
+#[tracing::instrument(skip(ctx, stream), fields(filename))]
 pub async fn set_stream_caching(
     ctx: Arc<AppContext>,
     ipfs_url: &str,
     content_type: Option<String>,
-    mut stream: Pin<Box<impl futures::Stream<Item = Result<bytes::Bytes, reqwest::Error>>>>,
+    stream: Pin<Box<impl futures::Stream<Item = Result<bytes::Bytes, reqwest::Error>>>>,
+    max_content_length: u64,
+) -> Result<Data, anyhow::Error> {
+    check_free_inodes(&ctx)?;
+    let filename = caching_path(&ctx, ipfs_url, content_type.clone(), true).await?;
+    Span::current().record("filename", filename.as_str());
+
+    let tmp_file = cache_temp_file(&ctx)?;
+
+    let content_hash = write_stream(
+        &ctx,
+        &mut fs::File::from_std(tmp_file.reopen().map_err(classify_io_error)?),
+        &filename,
+        stream,
+        max_content_length,
+        0,
+    )
+    .await?;
+
+    fs::rename(&tmp_file, &filename)
+        .await
+        .map_err(classify_io_error)?;
+    drop(tmp_file);
+    apply_cache_file_mode(&ctx.config, &filename)?;
+
+    Ok(Data {
+        content_type,
+        filename: Some(filename),
+        uncached: false,
+        bytes: None,
+        headers: Vec::new(),
+        content_hash,
+        fetch_duration: None,
+    })
+}
+
+/// Apply `cache_file_mode` to a file written under the cache or thumbnail directory, and
+/// to its parents up to that directory. Directories are also made searchable wherever
+/// the mode can read, e.g. 0o640 gives 0o750.
+pub fn apply_cache_file_mode(config: &Settings, filename: &str) -> Result<(), std::io::Error> {
+    let Some(mode) = config.cache_file_mode else {
+        return Ok(());
+    };
+    std::fs::set_permissions(filename, std::fs::Permissions::from_mode(mode))?;
+
+    let directory_mode = mode | ((mode & 0o444) >> 2);
+    let Some(root) = [
+        config.full_thumbnail_directory(),
+        Some(config.full_ipfs_cache_directory()),
+    ]
+    .into_iter()
+    .flatten()
+    .find(|root| Path::new(filename).starts_with(root)) else {
+        return Ok(());
+    };
+
+    for directory in Path::new(filename).ancestors().skip(1) {
+        std::fs::set_permissions(directory, std::fs::Permissions::from_mode(directory_mode))?;
+        if directory == Path::new(&root) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Downloads in progress and uncached files being served, in the cache root
+const TEMP_FILE_PREFIX: &str = ".tmp";
+
+/// Interrupted downloads waiting to be resumed, in the cache root
+const PARTIAL_FILE_PREFIX: &str = ".partial-";
+
+/// Temporary file in the cache root, on the same filesystem as the cache paths it's
+/// renamed to
+fn cache_temp_file(ctx: &AppContext) -> Result<tempfile::NamedTempFile, anyhow::Error> {
+    Builder::new()
+        .prefix(TEMP_FILE_PREFIX)
+        .tempfile_in(ctx.config.full_ipfs_cache_directory())
+        .map_err(classify_io_error)
+}
+
+/// Files without a database entry which are still in use: temporary files and
+/// interrupted downloads, see `partial_path`
+pub fn is_temp_file(file: &Path) -> bool {
+    let name = file
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+
+    name.starts_with(TEMP_FILE_PREFIX) || name.starts_with(PARTIAL_FILE_PREFIX)
+}
+
+/// Where an interrupted download of `ipfs_url` waits to be resumed
+pub fn partial_path(ctx: &AppContext, ipfs_url: &str) -> String {
+    let hash = Sha256::digest(ipfs_url);
+    format!(
+        "{}/{PARTIAL_FILE_PREFIX}{hash:x}",
+        ctx.config.full_ipfs_cache_directory()
+    )
+}
+
+/// Delete interrupted downloads not written to for `partial_max_age_hours`, they're
+/// kept even once `resumable_downloads` is turned off. Returns how many were deleted.
+pub async fn delete_stale_partials(config: &Settings) -> Result<usize, anyhow::Error> {
+    let max_age = std::time::Duration::from_secs(config.partial_max_age_hours * 3600);
+    let mut entries = match fs::read_dir(config.full_ipfs_cache_directory()).await {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(error) => return Err(error.into()),
+    };
+
+    let mut deleted = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(PARTIAL_FILE_PREFIX))
+        {
+            continue;
+        }
+        let stale = entry
+            .metadata()
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age > max_age);
+
+        if stale {
+            match fs::remove_file(entry.path()).await {
+                Ok(()) => deleted += 1,
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => error!("Can't delete {}: {error}", entry.path().display()),
+            }
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Like `set_stream_caching` but written to `partial_path`, which is kept when the stream
+/// breaks so the next fetch can resume it. `resume_from` is the offset the stream starts
+/// at, 0 for a whole file.
+#[tracing::instrument(skip(ctx, stream), fields(filename))]
+pub async fn set_stream_resumable(
+    ctx: Arc<AppContext>,
+    ipfs_url: &str,
+    content_type: Option<String>,
+    stream: Pin<Box<impl futures::Stream<Item = Result<bytes::Bytes, reqwest::Error>>>>,
+    max_content_length: u64,
+    resume_from: u64,
+) -> Result<Data, anyhow::Error> {
+    check_free_inodes(&ctx)?;
+    let filename = caching_path(&ctx, ipfs_url, content_type.clone(), true).await?;
+    Span::current().record("filename", filename.as_str());
+
+    let partial = partial_path(&ctx, ipfs_url);
+    let mut partial_file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&partial)
+        .await
+        .map_err(classify_io_error)?;
+
+    // Another gateway response may have been appended since the range was requested
+    let partial_length = partial_file.metadata().await?.len();
+    if resume_from == 0 {
+        partial_file.set_len(0).await.map_err(classify_io_error)?;
+    } else if partial_length != resume_from {
+        return Err(anyhow!(
+            "Can't resume {ipfs_url} at {resume_from}, {partial} has {partial_length} bytes"
+        ));
+    } else {
+        debug!("Resuming {ipfs_url} at {resume_from} bytes");
+    }
+
+    let content_hash = write_stream(
+        &ctx,
+        &mut partial_file,
+        &filename,
+        stream,
+        max_content_length,
+        resume_from,
+    )
+    .await;
+    drop(partial_file);
+
+    let content_hash = match content_hash {
+        Ok(content_hash) => content_hash,
+        Err(error) => {
+            // Only a broken stream is worth resuming
+            if error.downcast_ref::<reqwest::Error>().is_none() {
+                fs::remove_file(&partial).await.ok();
+            }
+            return Err(error);
+        }
+    };
+
+    fs::rename(&partial, &filename)
+        .await
+        .map_err(classify_io_error)?;
+    apply_cache_file_mode(&ctx.config, &filename)?;
+
+    Ok(Data {
+        content_type,
+        filename: Some(filename),
+        uncached: false,
+        bytes: None,
+        headers: Vec::new(),
+        content_hash,
+        fetch_duration: None,
+    })
+}
+
+/// Like `set_stream_caching` but the file is kept out of the cache, for content
+/// types listed in `no_cache_content_types`
+pub async fn set_stream_uncached(
+    ctx: Arc<AppContext>,
+    ipfs_url: &str,
+    content_type: Option<String>,
+    stream: Pin<Box<impl futures::Stream<Item = Result<bytes::Bytes, reqwest::Error>>>>,
+    max_content_length: u64,
 ) -> Result<Data, anyhow::Error> {
-    let filename = caching_filename(
+    let tmp_file = cache_temp_file(&ctx)?;
+
+    let content_hash = write_stream(
+        &ctx,
+        &mut fs::File::from_std(tmp_file.reopen().map_err(classify_io_error)?),
         ipfs_url,
-        &ctx.config.full_ipfs_cache_directory(),
-        content_type.clone(),
-        true,
+        stream,
+        max_content_length,
+        0,
     )
     .await?;
 
-    let mut tmp_file = Builder::new()
-        .prefix(&format!("{}/", &ctx.config.full_ipfs_cache_directory()))
-        .tempfile()?;
+    let filename = tmp_file.into_temp_path().keep()?;
+    debug!("Not caching {ipfs_url}, kept in {}", filename.display());
+
+    Ok(Data {
+        content_type,
+        filename: Some(filename.display().to_string()),
+        uncached: true,
+        bytes: None,
+        headers: Vec::new(),
+        content_hash,
+        fetch_duration: None,
+    })
+}
+
+/// Whether `content_type` is listed in `no_cache_content_types`, parameters are ignored
+pub fn is_uncached_content_type(ctx: &AppContext, content_type: Option<&str>) -> bool {
+    let Some(content_type) = content_type.and_then(|content_type| content_type.split(';').next())
+    else {
+        return false;
+    };
+
+    ctx.config
+        .no_cache_content_types
+        .iter()
+        .any(|uncached| uncached.eq_ignore_ascii_case(content_type.trim()))
+}
+
+/// Writes `stream` to `file` through a `write_buffer_size` buffer, flushed before
+/// returning. `written` bytes are already in it when resuming. Returns the hex SHA-256
+/// of the bytes with `hash_content`, but not for a resumed file as the start isn't seen.
+#[tracing::instrument(skip(ctx, file, stream), fields(bytes))]
+async fn write_stream(
+    ctx: &AppContext,
+    file: &mut (impl AsyncWrite + Unpin),
+    filename: &str,
+    mut stream: Pin<Box<impl futures::Stream<Item = Result<bytes::Bytes, reqwest::Error>>>>,
+    max_content_length: u64,
+    mut written: u64,
+) -> Result<Option<String>, anyhow::Error> {
+    // Requests queue for a write slot rather than all hammering the disk at once
+    let _permit = ctx.disk_writes.acquire().await?;
+    let mut file = BufWriter::with_capacity(ctx.config.write_buffer_size, file);
+    let mut hasher = (ctx.config.hash_content && written == 0).then(Sha256::new);
 
     while let Some(bytes) = stream.next().await {
         match bytes {
             Err(error) => {
+                // Keep what was received for resumable downloads
+                file.flush().await.ok();
                 return Err(error.into());
             }
             Ok(bytes) => {
-                debug!("Reading {} bytes to file {}", bytes.len(), &filename);
-                tmp_file.write_all(bytes.as_ref())?;
+                debug!("Reading {} bytes to file {}", bytes.len(), filename);
+                written += bytes.len() as u64;
+
+                // The temporary file is deleted when dropped
+                if written > max_content_length {
+                    return Err(ContentTooLarge {
+                        size: written,
+                        max_content_length,
+                        aborted: true,
+                    }
+                    .into());
+                }
+
+                file.write_all(bytes.as_ref())
+                    .await
+                    .map_err(classify_io_error)?;
+                if let Some(hasher) = &mut hasher {
+                    hasher.update(&bytes);
+                }
             }
         }
     }
 
-    fs::rename(&tmp_file, &filename).await?;
-    drop(tmp_file);
+    // Also waits for the tokio file to hand its last write to the OS
+    file.flush().await.map_err(classify_io_error)?;
 
-    Ok(Data {
-        content_type,
-        filename: Some(filename),
-    })
+    Span::current().record("bytes", written);
+
+    Ok(hasher.map(|hasher| hex::encode(hasher.finalize())))
+}
+
+/// Cache filename for the configured layout, nested like the IPFS path or flat. Urls
+/// aren't held to `max_path_segments` here, entries cached before it was lowered must
+/// still be found and deleted.
+pub async fn caching_path(
+    ctx: &AppContext,
+    ipfs_url: &str,
+    content_type: Option<String>,
+    create: bool,
+) -> Result<String, anyhow::Error> {
+    let directory = shard_directory(&ctx.config, ipfs_url)?;
+
+    if ctx.config.flat_cache {
+        flat_caching_filename(ipfs_url, &directory, create).await
+    } else {
+        caching_filename(ipfs_url, &directory, content_type, create).await
+    }
+}
+
+/// Cache directory with `cache_shard_depth` levels of two characters taken from the end
+/// of the CID, e.g. `tmp/ipfs/44/33` for `bafy...3344`. The start is the same for every
+/// CID of a given version and codec, so it would put everything in the same shard.
+pub fn shard_directory(config: &Settings, ipfs_url: &str) -> Result<String, anyhow::Error> {
+    let directory = config.full_ipfs_cache_directory();
+    if config.cache_shard_depth == 0 {
+        return Ok(directory);
+    }
+
+    let base_uri = check_ipfs_url(ipfs_url)?;
+    let cid = base_uri.split('/').next().unwrap_or_default();
+    let shards = cid
+        .chars()
+        .rev()
+        .take(config.cache_shard_depth * 2)
+        .collect::<Vec<char>>()
+        .chunks(2)
+        .map(|shard| shard.iter().collect::<String>())
+        .collect::<Vec<String>>();
+
+    Ok(format!("{directory}/{}", shards.join("/")))
+}
+
+/// Flat layout: the filename is the SHA-256 of the url, avoiding deep nesting and
+/// special characters. The extension comes from the url so lookups don't need the
+/// content type.
+pub async fn flat_caching_filename(
+    ipfs_url: &str,
+    directory: &str,
+    create: bool,
+) -> Result<String, anyhow::Error> {
+    let base_uri = check_ipfs_url(ipfs_url)?;
+    let hash = Sha256::digest(format!("ipfs://{base_uri}"));
+
+    let extension = base_uri
+        .rsplit('/')
+        .next()
+        .filter(|filename| !mime_guess::from_path(filename).is_empty())
+        .and_then(|filename| Path::new(filename).extension())
+        .and_then(|extension| extension.to_str())
+        .map(|extension| format!(".{extension}"))
+        .unwrap_or_default();
+
+    if create {
+        fs::create_dir_all(directory)
+            .await
+            .map_err(classify_io_error)?;
+    }
+
+    Ok(format!("{directory}/{hash:x}{extension}"))
+}
+
+/// Url a file of the nested layout was cached for, the inverse of `caching_path`. An
+/// `index.html` maps to the directory url listings are recorded under. `None` for the
+/// flat layout, whose filenames are hashes.
+pub fn ipfs_url_from_path(config: &Settings, filename: &str) -> Option<String> {
+    if config.flat_cache {
+        return None;
+    }
+
+    let relative = Path::new(filename)
+        .strip_prefix(config.full_ipfs_cache_directory())
+        .ok()?;
+    let segments = relative
+        .iter()
+        .map(|segment| segment.to_str())
+        .collect::<Option<Vec<&str>>>()?;
+    // Encoded back to the canonical form of `normalize_ipfs_url`, the CID needs none
+    let segments = segments
+        .get(config.cache_shard_depth..)?
+        .iter()
+        .map(|segment| encode_path_segment(segment))
+        .collect::<Vec<String>>();
+
+    match segments.split_last()? {
+        (index, path) if index == "index.html" && !path.is_empty() => {
+            Some(format!("ipfs://{}/", path.join("/")))
+        }
+        _ => Some(format!("ipfs://{}", segments.join("/"))),
+    }
 }
 
 pub async fn caching_filename(
@@ -107,15 +690,33 @@ pub async fn caching_filename(
 ) -> Result<String, anyhow::Error> {
     let base_uri = check_ipfs_url(ipfs_url)?;
 
-    let mut splits = base_uri.split('/').collect::<Vec<&str>>();
-    splits.insert(0, directory);
+    // The trailing empty split of a directory url is dropped, `index.html` is added below.
+    // Files are named after the decoded segments, `My File.png` for `My%20File.png`.
+    let mut splits = base_uri
+        .split('/')
+        .filter(|split| !split.is_empty())
+        .map(decode_path_segment)
+        .collect::<Result<Vec<String>, _>>()
+        .with_context(|| format!("Can't build a cache filename for {ipfs_url}"))?;
+
+    if let Some(split) = splits
+        .iter()
+        .find(|split| split.trim().is_empty() || *split == "." || *split == "..")
+    {
+        return Err(anyhow!(
+            "Can't build a cache filename for {ipfs_url}: invalid path segment {split:?}"
+        ));
+    }
+
+    splits.insert(0, directory.to_string());
 
     // If url ends with `/` we know it's a directory
     let mut is_directory = base_uri.ends_with('/');
 
     if !is_directory {
         if let Some(content_type) = content_type {
-            if content_type == "text/html" {
+            let essence = content_type.split(';').next().unwrap_or_default().trim();
+            if essence.eq_ignore_ascii_case("text/html") {
                 // If the file has no extension and is HTML, we know it's a directory listing
                 if let Some(filename) = splits.last() {
                     let mimes = mime_guess::from_path(filename);
@@ -132,30 +733,104 @@ pub async fn caching_filename(
     let filename = if is_directory {
         format!("{cache_dir}/index.html")
     } else {
-        let filename = splits.pop().expect("Should have an element");
+        let filename = splits
+            .pop()
+            .ok_or_else(|| anyhow!("Can't build a cache filename for {ipfs_url}: empty path"))?;
         cache_dir = splits.join("/");
         format!("{cache_dir}/{filename}")
     };
 
     if create {
         debug!("creating {cache_dir} from {:?}", splits);
-        fs::create_dir_all(&cache_dir).await?;
+        fs::create_dir_all(&cache_dir)
+            .await
+            .map_err(classify_io_error)?;
     }
 
     Ok(filename)
 }
 
-/// Remove caching and parent directories if empty
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct DiskUsage {
+    pub available_bytes: u64,
+    pub total_bytes: u64,
+    pub available_inodes: u64,
+}
+
+/// Free and total space of the filesystem holding the cache directory
+pub fn cache_disk_usage(ctx: Arc<AppContext>) -> Result<DiskUsage, anyhow::Error> {
+    let stat = statvfs(Path::new(&ctx.config.full_ipfs_cache_directory()))?;
+    let fragment_size = stat.fragment_size() as u64;
+
+    Ok(DiskUsage {
+        available_bytes: stat.blocks_available() as u64 * fragment_size,
+        total_bytes: stat.blocks() as u64 * fragment_size,
+        available_inodes: stat.files_available() as u64,
+    })
+}
+
+/// `DiskFull` when fewer than `min_free_inodes` are left to create a file
+fn check_free_inodes(ctx: &Arc<AppContext>) -> Result<(), anyhow::Error> {
+    let min_free_inodes = ctx.config.min_free_inodes;
+    if min_free_inodes == 0 {
+        return Ok(());
+    }
+
+    let available_inodes = cache_disk_usage(ctx.clone())?.available_inodes;
+    if available_inodes < min_free_inodes {
+        return Err(DiskFull(std::io::Error::other(format!(
+            "{available_inodes} inodes left, min_free_inodes is {min_free_inodes}"
+        )))
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Remove caching, its thumbnails and parent directories if empty
 pub async fn delete_caching(ctx: Arc<AppContext>, ipfs_url: &str) -> Result<(), anyhow::Error> {
-    let filename =
-        caching_filename(ipfs_url, &ctx.config.ipfs_cache_directory, None, false).await?;
+    let filename = caching_path(&ctx, ipfs_url, None, false).await?;
+    let directory = ctx.config.full_ipfs_cache_directory();
+
+    ctx.mem_cache.remove(&normalize_ipfs_url(ipfs_url));
+
+    let thumbnails = entity::thumbnail::Entity::find()
+        .filter(entity::thumbnail::Column::SourceFilename.eq(filename.as_str()))
+        .all(&ctx.db)
+        .await?;
+    let thumbnail_directory = ctx
+        .config
+        .full_thumbnail_directory()
+        .unwrap_or_else(|| directory.clone());
+
+    for thumbnail in &thumbnails {
+        fs::remove_file(&thumbnail.filename).await.ok();
+        remove_empty_parents(&thumbnail.filename, &thumbnail_directory).await;
+    }
+
+    entity::thumbnail::Entity::delete_many()
+        .filter(entity::thumbnail::Column::SourceFilename.eq(filename.as_str()))
+        .exec(&ctx.db)
+        .await?;
 
     fs::remove_file(&filename).await.ok();
+    // Precompressed sibling served by `send_filename`
+    fs::remove_file(format!("{filename}.br")).await.ok();
+    remove_empty_parents(&filename, &directory).await;
 
-    let mut path = Path::new(&filename).parent();
+    Ok(())
+}
+
+/// Remove the parent directories of `filename` while empty, up to `directory`
+pub(crate) async fn remove_empty_parents(filename: &str, directory: &str) {
+    let mut path = Path::new(filename).parent();
 
     while path.is_some() {
         let dir = path.unwrap();
+        if dir == Path::new(directory) {
+            break;
+        }
+
         match dir.read_dir() {
             Err(_) => break,
             Ok(mut files) => {
@@ -168,30 +843,27 @@ pub async fn delete_caching(ctx: Arc<AppContext>, ipfs_url: &str) -> Result<(),
 
         path = dir.parent();
     }
-
-    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::config::Dimension;
     use crate::ipfs_client::fetch_ipfs_data;
+    use crate::thumbnails::thumbnail_filename;
+    use entity::ipfs_object::update_entry;
+    use entity::thumbnail::record_thumbnail;
+    use sea_orm::sea_query::Expr;
 
     use super::*;
 
-    async fn delete_dir(ctx: Arc<AppContext>) {
-        fs::remove_dir_all(&ctx.config.ipfs_cache_directory)
-            .await
-            .ok();
-    }
-
     #[tokio::test]
     async fn filename_for_dir() -> Result<(), anyhow::Error> {
-        let ctx = Arc::new(AppContext::build().await);
-        delete_dir(ctx.clone()).await;
+        let ctx = AppContext::build_for_test().await;
+        let directory = ctx.config.ipfs_cache_directory;
 
         let filename = caching_filename(
             "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344",
-            "tmp/ipfs",
+            &directory,
             Some("text/html".to_string()),
             true,
         )
@@ -199,25 +871,25 @@ mod tests {
 
         assert_eq!(
             filename,
-            "tmp/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/index.html"
+            format!("{directory}/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/index.html")
         );
 
-        assert!(
-            Path::new("tmp/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344")
-                .is_dir()
-        );
+        assert!(Path::new(&format!(
+            "{directory}/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344"
+        ))
+        .is_dir());
 
         Ok(())
     }
 
     #[tokio::test]
     async fn filename_for_subdir() -> Result<(), anyhow::Error> {
-        let ctx = Arc::new(AppContext::build().await);
-        delete_dir(ctx.clone()).await;
+        let ctx = AppContext::build_for_test().await;
+        let directory = ctx.config.ipfs_cache_directory;
 
         let filename = caching_filename(
             "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata",
-            "tmp/ipfs",
+            &directory,
             Some("text/html".to_string()),
             true,
         )
@@ -225,12 +897,12 @@ mod tests {
 
         assert_eq!(
             filename,
-            "tmp/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/index.html"
+            format!("{directory}/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/index.html")
         );
 
-        assert!(Path::new(
-            "tmp/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata"
-        )
+        assert!(Path::new(&format!(
+            "{directory}/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata"
+        ))
         .is_dir());
 
         Ok(())
@@ -238,12 +910,12 @@ mod tests {
 
     #[tokio::test]
     async fn filename_for_non_html_file() -> Result<(), anyhow::Error> {
-        let ctx = Arc::new(AppContext::build().await);
-        delete_dir(ctx.clone()).await;
+        let ctx = AppContext::build_for_test().await;
+        let directory = ctx.config.ipfs_cache_directory;
 
         let filename = caching_filename(
             "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/3",
-            "tmp/ipfs",
+            &directory,
             Some("application/json".to_string()),
             true,
         )
@@ -251,12 +923,12 @@ mod tests {
 
         assert_eq!(
             filename,
-            "tmp/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/3"
+            format!("{directory}/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/3")
         );
 
-        assert!(Path::new(
-            "tmp/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata"
-        )
+        assert!(Path::new(&format!(
+            "{directory}/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata"
+        ))
         .is_dir());
 
         Ok(())
@@ -264,12 +936,12 @@ mod tests {
 
     #[tokio::test]
     async fn filename_for_html_file_without_extension() -> Result<(), anyhow::Error> {
-        let ctx = Arc::new(AppContext::build().await);
-        delete_dir(ctx.clone()).await;
+        let ctx = AppContext::build_for_test().await;
+        let directory = ctx.config.ipfs_cache_directory;
 
         let filename = caching_filename(
             "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/4",
-            "tmp/ipfs",
+            &directory,
             Some("text/html".to_string()),
             true,
         )
@@ -277,12 +949,12 @@ mod tests {
 
         assert_eq!(
             filename,
-            "tmp/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/4/index.html"
+            format!("{directory}/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/4/index.html")
         );
 
-        assert!(Path::new(
-            "tmp/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/4"
-        )
+        assert!(Path::new(&format!(
+            "{directory}/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/4"
+        ))
         .is_dir());
 
         Ok(())
@@ -290,12 +962,12 @@ mod tests {
 
     #[tokio::test]
     async fn filename_for_html_file_with_extension() -> Result<(), anyhow::Error> {
-        let ctx = Arc::new(AppContext::build().await);
-        delete_dir(ctx.clone()).await;
+        let ctx = AppContext::build_for_test().await;
+        let directory = ctx.config.ipfs_cache_directory;
 
         let filename = caching_filename(
             "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/5.html",
-            "tmp/ipfs",
+            &directory,
             Some("text/html".to_string()),
             true,
         )
@@ -303,22 +975,96 @@ mod tests {
 
         assert_eq!(
             filename,
-            "tmp/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/5.html"
+            format!("{directory}/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/5.html")
         );
 
-        assert!(Path::new(
-            "tmp/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/"
-        )
+        assert!(Path::new(&format!(
+            "{directory}/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/"
+        ))
         .is_dir());
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn delete_caching_one_file() -> Result<(), anyhow::Error> {
-        let ctx = Arc::new(AppContext::build().await);
+    async fn filename_for_duplicate_slashes() -> Result<(), anyhow::Error> {
+        let filename = caching_filename(
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344//",
+            "tmp/ipfs",
+            None,
+            false,
+        )
+        .await?;
 
-        delete_dir(ctx.clone()).await;
+        assert_eq!(
+            filename,
+            "tmp/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/index.html"
+        );
+
+        let filename = caching_filename(
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata//1",
+            "tmp/ipfs",
+            None,
+            false,
+        )
+        .await?;
+
+        assert_eq!(
+            filename,
+            "tmp/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/1"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn filename_for_invalid_segments() {
+        for ipfs_url in [
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/ ",
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/ /1",
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/../1",
+        ] {
+            let result = caching_filename(ipfs_url, "tmp/ipfs", None, false).await;
+
+            assert!(result.is_err(), "{ipfs_url} should be rejected");
+        }
+    }
+
+    #[tokio::test]
+    async fn flat_filename() -> Result<(), anyhow::Error> {
+        let filename = flat_caching_filename(
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/image.png",
+            "tmp/ipfs",
+            false,
+        )
+        .await?;
+
+        assert!(filename.starts_with("tmp/ipfs/"));
+        assert!(filename.ends_with(".png"));
+        assert_eq!(filename.matches('/').count(), 2);
+
+        let directory = flat_caching_filename(
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/",
+            "tmp/ipfs",
+            false,
+        )
+        .await?;
+        let file = flat_caching_filename(
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata",
+            "tmp/ipfs",
+            false,
+        )
+        .await?;
+
+        assert_ne!(directory, file);
+        assert_eq!(file.len(), "tmp/ipfs/".len() + 64);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_caching_one_file() -> Result<(), anyhow::Error> {
+        let ctx = Arc::new(AppContext::build_for_test().await);
 
         let ipfs_url =
             "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/81";
@@ -332,9 +1078,7 @@ mod tests {
 
     #[tokio::test]
     async fn delete_caching_multiple_files() -> Result<(), anyhow::Error> {
-        let ctx = Arc::new(AppContext::build().await);
-
-        delete_dir(ctx.clone()).await;
+        let ctx = Arc::new(AppContext::build_for_test().await);
 
         let ipfs_url =
             "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/81";
@@ -349,4 +1093,416 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn delete_caching_thumbnails() -> Result<(), anyhow::Error> {
+        let ctx = Arc::new(AppContext::build_for_test().await);
+
+        let ipfs_url =
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/thumbnails.png";
+        let filename = caching_path(&ctx, ipfs_url, None, true).await?;
+        let dimension = Dimension {
+            width: 100,
+            height: 100,
+        };
+        let (thumbnail, _) = thumbnail_filename(&ctx.config, &filename, &dimension, "png", None);
+
+        fs::write(&filename, b"image").await?;
+        fs::write(&thumbnail, b"thumbnail").await?;
+        record_thumbnail(&ctx.db, &filename, &thumbnail).await?;
+
+        delete_caching(ctx.clone(), ipfs_url).await?;
+
+        assert!(!Path::new(&filename).exists());
+        assert!(!Path::new(&thumbnail).exists());
+        let thumbnails = entity::thumbnail::Entity::find()
+            .filter(entity::thumbnail::Column::SourceFilename.eq(filename.as_str()))
+            .all(&ctx.db)
+            .await?;
+        assert!(thumbnails.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_caching_deeper_than_limit() -> Result<(), anyhow::Error> {
+        let mut ctx = AppContext::build_for_test().await;
+        let ipfs_url =
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/a/b/deep.json";
+        let filename = caching_path(&ctx, ipfs_url, None, true).await?;
+        fs::write(&filename, b"{}").await?;
+        update_entry(&ctx.db, ipfs_url, "application/json", 2).await?;
+
+        // Cached before the limit was lowered
+        ctx.config.max_path_segments = 1;
+        let ctx = Arc::new(ctx);
+        delete_caching(ctx.clone(), ipfs_url).await?;
+
+        assert!(!Path::new(&filename).exists());
+        assert!(get_caching(ctx, ipfs_url).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sharded_caching_path() -> Result<(), anyhow::Error> {
+        let mut ctx = AppContext::build_for_test().await;
+        ctx.config.cache_shard_depth = 2;
+        let ctx = Arc::new(ctx);
+        let directory = ctx.config.full_ipfs_cache_directory();
+
+        let ipfs_url =
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/sharded.json";
+        let filename = caching_path(&ctx, ipfs_url, None, true).await?;
+        assert_eq!(
+            filename,
+            format!("{directory}/id/zb/bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/sharded.json")
+        );
+
+        fs::write(&filename, b"{}").await?;
+        update_entry(&ctx.db, ipfs_url, "application/json", 2).await?;
+        assert_eq!(
+            get_caching(ctx.clone(), ipfs_url)
+                .await?
+                .and_then(|data| data.filename),
+            Some(filename.clone())
+        );
+
+        // Empty shard directories go with the file
+        delete_caching(ctx.clone(), ipfs_url).await?;
+        assert!(!Path::new(&format!("{directory}/id")).exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cache_file_mode() -> Result<(), anyhow::Error> {
+        let mut ctx = AppContext::build_for_test().await;
+        ctx.config.cache_file_mode = Some(0o640);
+        ctx.config.cache_shard_depth = 1;
+        let ctx = Arc::new(ctx);
+
+        let ipfs_url =
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/mode.json";
+        let filename = caching_path(&ctx, ipfs_url, None, true).await?;
+        fs::write(&filename, b"{}").await?;
+        apply_cache_file_mode(&ctx.config, &filename)?;
+
+        let mode = |path: &Path| {
+            std::fs::metadata(path)
+                .map(|metadata| metadata.permissions().mode() & 0o777)
+                .ok()
+        };
+        let directory = ctx.config.full_ipfs_cache_directory();
+        assert_eq!(mode(Path::new(&filename)), Some(0o640));
+        assert_eq!(mode(Path::new(&filename).parent().unwrap()), Some(0o750));
+        assert_eq!(mode(Path::new(&format!("{directory}/id"))), Some(0o750));
+        assert_eq!(mode(Path::new(&directory)), Some(0o750));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn buffered_stream_caching() -> Result<(), anyhow::Error> {
+        let mut ctx = AppContext::build_for_test().await;
+        ctx.config.write_buffer_size = 4;
+        let ctx = Arc::new(ctx);
+
+        // Chunks smaller, equal and larger than the buffer
+        let chunks = ["ab", "cdef", "0123456789", "g"]
+            .into_iter()
+            .map(|chunk| Ok::<_, reqwest::Error>(bytes::Bytes::from(chunk)));
+        let ipfs_url =
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/buffered.txt";
+        let data = set_stream_caching(
+            ctx,
+            ipfs_url,
+            None,
+            Box::pin(futures::stream::iter(chunks)),
+            1024,
+        )
+        .await?;
+
+        assert_eq!(
+            fs::read_to_string(data.filename.unwrap()).await?,
+            "abcdef0123456789g"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn decoded_cache_path() -> Result<(), anyhow::Error> {
+        let mut ctx = AppContext::build_for_test().await;
+        ctx.config.flat_cache = false;
+        ctx.config.cache_shard_depth = 0;
+        let directory = ctx.config.full_ipfs_cache_directory();
+        let cid = "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+        let ipfs_url = format!("ipfs://{cid}/My%20File/Caf%C3%A9%20%F0%9F%8E%A8.png");
+
+        let filename = caching_path(&ctx, &ipfs_url, None, false).await?;
+        assert_eq!(filename, format!("{directory}/{cid}/My File/Café 🎨.png"));
+        assert_eq!(
+            caching_path(
+                &ctx,
+                &format!("ipfs://{cid}/My File/Café 🎨.png"),
+                None,
+                false
+            )
+            .await?,
+            filename
+        );
+        assert_eq!(ipfs_url_from_path(&ctx.config, &filename), Some(ipfs_url));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_stale_partial_downloads() -> Result<(), anyhow::Error> {
+        let mut ctx = AppContext::build_for_test().await;
+        ctx.config.partial_max_age_hours = 1;
+        fs::create_dir_all(ctx.config.full_ipfs_cache_directory()).await?;
+
+        let stale = partial_path(
+            &ctx,
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/stale",
+        );
+        let fresh = partial_path(
+            &ctx,
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/fresh",
+        );
+        fs::write(&stale, b"stale").await?;
+        fs::write(&fresh, b"fresh").await?;
+        std::fs::File::options()
+            .write(true)
+            .open(&stale)?
+            .set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(7200))?;
+
+        assert!(delete_stale_partials(&ctx.config).await? >= 1);
+        assert!(!Path::new(&stale).exists());
+        assert!(Path::new(&fresh).exists());
+        fs::remove_file(&fresh).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recognize_temp_files() -> Result<(), anyhow::Error> {
+        let ctx = AppContext::build_for_test().await;
+        fs::create_dir_all(ctx.config.full_ipfs_cache_directory()).await?;
+
+        let tmp_file = cache_temp_file(&ctx)?;
+        assert!(is_temp_file(tmp_file.path()));
+        assert!(is_temp_file(Path::new(&partial_path(
+            &ctx,
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi"
+        ))));
+        assert!(!is_temp_file(Path::new(&format!(
+            "{}/bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi",
+            ctx.config.full_ipfs_cache_directory()
+        ))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn url_from_cache_path() {
+        let mut config = Settings::new().expect("Can't create configuration");
+        config.flat_cache = false;
+        config.cache_shard_depth = 1;
+        let directory = config.full_ipfs_cache_directory();
+        let cid = "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+
+        assert_eq!(
+            ipfs_url_from_path(&config, &format!("{directory}/di/{cid}/metadata/1")),
+            Some(format!("ipfs://{cid}/metadata/1"))
+        );
+        assert_eq!(
+            ipfs_url_from_path(&config, &format!("{directory}/di/{cid}")),
+            Some(format!("ipfs://{cid}"))
+        );
+        assert_eq!(
+            ipfs_url_from_path(&config, &format!("{directory}/di/{cid}/listing/index.html")),
+            Some(format!("ipfs://{cid}/listing/"))
+        );
+        assert_eq!(
+            ipfs_url_from_path(&config, &format!("{directory}/di")),
+            None
+        );
+        assert_eq!(ipfs_url_from_path(&config, "/elsewhere/file"), None);
+
+        config.flat_cache = true;
+        assert_eq!(
+            ipfs_url_from_path(&config, &format!("{directory}/di/{cid}")),
+            None
+        );
+    }
+
+    #[test]
+    fn enumerate_lookup_variants() {
+        let cid = "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+
+        assert_eq!(
+            lookup_variants(&format!("{cid}/dir")),
+            vec![format!("{cid}/dir"), format!("{cid}/dir/")]
+        );
+        assert_eq!(
+            lookup_variants(&format!("{cid}//dir///")),
+            vec![format!("{cid}/dir/"), format!("{cid}/dir")]
+        );
+        assert_eq!(
+            lookup_variants(cid),
+            vec![cid.to_string(), format!("{cid}/")]
+        );
+        // A file-like name can still be a directory
+        assert_eq!(
+            lookup_variants(&format!("{cid}/metadata.json//")),
+            vec![
+                format!("{cid}/metadata.json/"),
+                format!("{cid}/metadata.json")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn hash_cached_content() -> Result<(), anyhow::Error> {
+        let mut ctx = AppContext::build_for_test().await;
+        ctx.config.hash_content = true;
+        let ctx = Arc::new(ctx);
+
+        let chunks = ["hello ", "world"]
+            .into_iter()
+            .map(|chunk| Ok::<_, reqwest::Error>(bytes::Bytes::from(chunk)));
+        let data = set_stream_caching(
+            ctx,
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/hashed.txt",
+            None,
+            Box::pin(futures::stream::iter(chunks)),
+            1024,
+        )
+        .await?;
+
+        assert_eq!(
+            data.content_hash.as_deref(),
+            Some("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9")
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ignore_incomplete_caching() -> Result<(), anyhow::Error> {
+        let ctx = Arc::new(AppContext::build_for_test().await);
+
+        let ipfs_url =
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/empty.json";
+        let filename = caching_path(&ctx, ipfs_url, None, true).await?;
+
+        // An empty file without an entry may be a failed write
+        fs::write(&filename, b"").await?;
+        assert!(get_caching(ctx.clone(), ipfs_url).await?.is_none());
+
+        update_entry(&ctx.db, ipfs_url, "application/json", 0).await?;
+        assert!(get_caching(ctx.clone(), ipfs_url).await?.is_some());
+
+        // Truncated compared to the recorded size
+        let ipfs_url =
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/truncated.json";
+        let filename = caching_path(&ctx, ipfs_url, None, true).await?;
+        fs::write(&filename, b"{\"name\":").await?;
+        update_entry(&ctx.db, ipfs_url, "application/json", 18).await?;
+        assert!(get_caching(ctx.clone(), ipfs_url).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expire_old_caching() -> Result<(), anyhow::Error> {
+        let mut ctx = AppContext::build_for_test().await;
+        ctx.config.cache_max_age_seconds = Some(3600);
+        let ctx = Arc::new(ctx);
+
+        let ipfs_url =
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/old.json";
+        let filename = caching_path(&ctx, ipfs_url, None, true).await?;
+        fs::write(&filename, b"{}").await?;
+        update_entry(&ctx.db, ipfs_url, "application/json", 2).await?;
+
+        assert!(get_caching(ctx.clone(), ipfs_url).await?.is_some());
+
+        entity::ipfs_object::Entity::update_many()
+            .col_expr(
+                entity::ipfs_object::Column::CachedAt,
+                Expr::value(Utc::now().naive_utc() - Duration::days(1)),
+            )
+            .filter(entity::ipfs_object::Column::RemoteUrl.eq(ipfs_url))
+            .exec(&ctx.db)
+            .await?;
+
+        assert!(get_caching(ctx.clone(), ipfs_url).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_caching_with_huge_max_age() -> Result<(), anyhow::Error> {
+        let mut ctx = AppContext::build_for_test().await;
+        // Past the range `validate` accepts, it would overflow the expiry date
+        ctx.config.cache_max_age_seconds = Some(i64::MAX);
+        let ctx = Arc::new(ctx);
+
+        let ipfs_url =
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/forever.json";
+        let filename = caching_path(&ctx, ipfs_url, None, true).await?;
+        fs::write(&filename, b"{}").await?;
+        update_entry(&ctx.db, ipfs_url, "application/json", 2).await?;
+
+        assert!(get_caching(ctx.clone(), ipfs_url).await?.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuse_caching_without_free_inodes() -> Result<(), anyhow::Error> {
+        let mut ctx = AppContext::build_for_test().await;
+        ctx.config.min_free_inodes = u64::MAX;
+        let ctx = Arc::new(ctx);
+
+        let chunks = [Ok::<_, reqwest::Error>(bytes::Bytes::from("inodes"))];
+        let ipfs_url =
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/inodes.txt";
+        let error = set_stream_caching(
+            ctx,
+            ipfs_url,
+            None,
+            Box::pin(futures::stream::iter(chunks)),
+            1024,
+        )
+        .await
+        .unwrap_err();
+        assert!(error.downcast_ref::<DiskFull>().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn classify_disk_full() {
+        let error = classify_io_error(std::io::Error::from_raw_os_error(Errno::ENOSPC as i32));
+        assert!(error.downcast_ref::<DiskFull>().is_some());
+
+        let error = classify_io_error(std::io::Error::from_raw_os_error(Errno::EACCES as i32));
+        assert!(error.downcast_ref::<DiskFull>().is_none());
+    }
+
+    #[test]
+    fn encode_forwarded_headers() {
+        let headers = vec![
+            ("x-ipfs-path".to_string(), "/ipfs/bafy/1".to_string()),
+            ("x-ipfs-roots".to_string(), "bafy".to_string()),
+        ];
+
+        let encoded = encode_headers(&headers);
+        assert_eq!(decode_headers(encoded.as_deref()), headers);
+        assert_eq!(encode_headers(&[]), None);
+    }
 }
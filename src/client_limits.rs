@@ -0,0 +1,194 @@
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::HttpRequest;
+use bytes::Bytes;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::config::Settings;
+
+/// Above this many tracked clients, windows which ended are dropped
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Requests per client IP, see `client_requests_per_second` and
+/// `max_concurrent_requests_per_client`
+#[derive(Default)]
+pub struct ClientLimits {
+    /// Start of the current one second window and the requests counted in it
+    windows: DashMap<IpAddr, (Instant, u64)>,
+    concurrent: DashMap<IpAddr, usize>,
+}
+
+/// Holds a concurrent request slot of a client until dropped
+pub struct ClientGuard {
+    limits: Arc<ClientLimits>,
+    ip: IpAddr,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.limits
+            .concurrent
+            .remove_if_mut(&self.ip, |_, concurrent| {
+                *concurrent -= 1;
+                *concurrent == 0
+            });
+    }
+}
+
+/// Response body holding the `ClientGuard` of its request, so a client's slot is taken
+/// until the body is sent or dropped and not only until the response head is ready
+pub struct GuardedBody {
+    body: BoxBody,
+    _guard: Option<ClientGuard>,
+}
+
+impl GuardedBody {
+    pub fn new(body: BoxBody, guard: Option<ClientGuard>) -> Self {
+        GuardedBody {
+            body,
+            _guard: guard,
+        }
+    }
+}
+
+impl MessageBody for GuardedBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.get_mut().body).poll_next(cx)
+    }
+}
+
+impl ClientLimits {
+    /// Count a request from `ip`, or the seconds it should wait before retrying. Requests
+    /// refused for concurrency still count against the rate.
+    pub fn admit(self: &Arc<Self>, config: &Settings, ip: IpAddr) -> Result<ClientGuard, u64> {
+        if let Some(requests_per_second) = config.client_requests_per_second {
+            let now = Instant::now();
+            if self.windows.len() > MAX_TRACKED_CLIENTS {
+                self.windows
+                    .retain(|_, (start, _)| now.duration_since(*start) < Duration::from_secs(1));
+            }
+
+            let mut window = self.windows.entry(ip).or_insert((now, 0));
+            if now.duration_since(window.0) >= Duration::from_secs(1) {
+                *window = (now, 0);
+            }
+            if window.1 >= requests_per_second {
+                return Err(1);
+            }
+            window.1 += 1;
+        }
+
+        // Only counted once admitted, a refused client doesn't leave an entry behind
+        let max_concurrent = config
+            .max_concurrent_requests_per_client
+            .unwrap_or(usize::MAX);
+        match self.concurrent.entry(ip) {
+            Entry::Occupied(entry) if *entry.get() >= max_concurrent => return Err(1),
+            Entry::Occupied(mut entry) => *entry.get_mut() += 1,
+            Entry::Vacant(_) if max_concurrent == 0 => return Err(1),
+            Entry::Vacant(entry) => {
+                entry.insert(1);
+            }
+        }
+
+        Ok(ClientGuard {
+            limits: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// The last address of `client_ip_header` when set, the one appended by the trusted
+/// proxy, otherwise the peer address
+pub fn client_ip(config: &Settings, req: &HttpRequest) -> Option<IpAddr> {
+    config
+        .client_ip_header
+        .as_ref()
+        .and_then(|name| req.headers().get(name.as_str()))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok())
+        .or_else(|| req.peer_addr().map(|addr| addr.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn limit_client_requests() {
+        let mut config = Settings::new().expect("Can't create configuration");
+        config.client_requests_per_second = Some(4);
+        config.max_concurrent_requests_per_client = Some(2);
+        let limits = Arc::new(ClientLimits::default());
+        let ip = "10.0.0.1".parse().unwrap();
+        let other_ip = "10.0.0.2".parse().unwrap();
+
+        let first = limits.admit(&config, ip).expect("First request is limited");
+        let second = limits
+            .admit(&config, ip)
+            .expect("Second request is limited");
+        // Too many concurrent requests, until one of them is done. It still counts
+        // against the requests per second.
+        assert_eq!(limits.admit(&config, ip).err(), Some(1));
+        drop(first);
+        let third = limits.admit(&config, ip).expect("Third request is limited");
+        drop((second, third));
+        // Too many requests this second, other clients are counted apart
+        assert_eq!(limits.admit(&config, ip).err(), Some(1));
+        assert!(limits.admit(&config, other_ip).is_ok());
+        assert!(limits.concurrent.is_empty());
+
+        // Rejected by `validate`, but still never leaves a zero entry
+        config.client_requests_per_second = None;
+        config.max_concurrent_requests_per_client = Some(0);
+        assert_eq!(limits.admit(&config, ip).err(), Some(1));
+        assert!(limits.concurrent.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn hold_client_slot_until_body_sent() {
+        let mut config = Settings::new().expect("Can't create configuration");
+        config.client_requests_per_second = None;
+        config.max_concurrent_requests_per_client = Some(1);
+        let limits = Arc::new(ClientLimits::default());
+        let ip = "10.0.0.1".parse().unwrap();
+
+        let guard = limits.admit(&config, ip).expect("First request is limited");
+        let body = GuardedBody::new(BoxBody::new("streamed"), Some(guard));
+        assert_eq!(limits.admit(&config, ip).err(), Some(1));
+
+        let sent = actix_web::body::to_bytes(body).await.ok();
+        assert_eq!(sent, Some(Bytes::from_static(b"streamed")));
+        assert!(limits.admit(&config, ip).is_ok());
+    }
+
+    #[test]
+    fn trusted_client_ip_header() {
+        let mut config = Settings::new().expect("Can't create configuration");
+        let req = TestRequest::default()
+            .peer_addr("192.168.0.1:4000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "1.1.1.1, 10.0.0.1"))
+            .to_http_request();
+
+        config.client_ip_header = None;
+        assert_eq!(client_ip(&config, &req), "192.168.0.1".parse().ok());
+        config.client_ip_header = Some("X-Forwarded-For".to_string());
+        assert_eq!(client_ip(&config, &req), "10.0.0.1".parse().ok());
+    }
+}
@@ -1,18 +1,164 @@
 use config::{Config, ConfigError, Environment, File};
+use std::collections::HashMap;
+use tracing::warn;
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct Settings {
     pub ipfs_gateways: Vec<String>,
+    #[serde(default)]
+    pub gateway_settings: Vec<GatewaySettings>,
+    pub trusted_gateways: Vec<String>,
+    /// Sources tried in order after the cache, each success is cached
+    pub fetch_sources: Vec<FetchSource>,
+    /// Other ipfs-proxy instances or gateways, tried by the `fallback_proxies` source
+    #[serde(default)]
+    pub fallback_proxy_urls: Vec<String>,
+    pub allowed_cid_prefixes: Vec<String>,
+    pub convert_cid_v0: bool,
+    /// Redirect CIDv0 and other non canonical CIDs to their base32 CIDv1 url
+    pub redirect_to_canonical: bool,
     pub ipfs_cache_directory: String,
+    pub flat_cache: bool,
+    /// Levels of subdirectories spreading cached files by CID, 0 disables sharding
+    pub cache_shard_depth: usize,
+    pub caching_enabled: bool,
+    /// Permissions of written cache files, e.g. 0o644 for a static file server
+    pub cache_file_mode: Option<u32>,
+    /// Memory kept for small hot files in front of the disk cache, 0 disables it
+    pub mem_cache_bytes: usize,
+    /// Files above this are only cached on disk
+    pub mem_cache_max_entry_bytes: usize,
+    /// Gateway response headers passed on to clients, e.g. `X-Ipfs-Path`
+    #[serde(default)]
+    pub forward_headers: Vec<String>,
+    /// Url extensions whose gateway content type must match, or the next gateway is tried
+    #[serde(default)]
+    pub strict_content_type_extensions: Vec<String>,
+    /// Content types served without being written to the cache
+    #[serde(default)]
+    pub no_cache_content_types: Vec<String>,
+    pub max_concurrent_disk_writes: usize,
+    /// Gateway fetches at once, shared by the server and the fetch bin
+    pub max_concurrent_fetches: usize,
+    /// Bytes buffered before each write to a cache file
+    pub write_buffer_size: usize,
+    /// Record the SHA-256 of cached files, computed while they're written
+    pub hash_content: bool,
     pub user_agent: String,
     pub connect_timeout: u64,
+    pub max_redirects: usize,
+    /// HTTP version spoken to gateways, `gateway_settings` can override it per gateway
+    pub gateway_http_version: HttpVersion,
+    /// Idle connections kept open to each gateway
+    pub gateway_pool_max_idle_per_host: usize,
+    /// Requests in flight to a single gateway, others wait for one to finish. 0 is unlimited.
+    pub max_connections_per_gateway: usize,
+    pub accept_partial_content: bool,
+    /// Skip gateway responses whose final url, after redirects, doesn't address the
+    /// requested CID
+    pub check_response_cid: bool,
     pub pause_gateway_seconds: i64,
+    /// Query gateways one at a time, the next one after this delay without a response,
+    /// instead of all at once
+    pub hedge_delay_ms: Option<u64>,
+    /// Gateways queried because of `hedge_delay_ms`, later ones only replace failed gateways
+    pub max_hedges: usize,
+    /// Gateway requests made for a single fetch, across every source. 0 is unlimited.
+    pub max_fetch_attempts: usize,
+    /// Requests per gateway the success rate is computed over
+    pub gateway_stats_window: usize,
+    /// Attempts in the window before a gateway can be benched
+    pub bench_min_attempts: usize,
+    /// Gateways succeeding less often are benched, 0 disables it
+    pub bench_success_rate: f64,
+    pub bench_gateway_seconds: i64,
     pub delete_after_days: i64,
+    /// How often cache hits are written to the database, in one transaction
+    pub access_flush_seconds: u64,
+    /// Cached files older than this are fetched again, served forever when unset
+    pub cache_max_age_seconds: Option<i64>,
+    /// Overrides `cache_max_age_seconds` for some content types, e.g. metadata JSON
+    #[serde(default)]
+    pub content_type_max_age_seconds: HashMap<String, i64>,
+    /// Content types by url extension, for files the gateway sends without a content
+    /// type or as `application/octet-stream`, e.g. `glb = "model/gltf-binary"`
+    #[serde(default)]
+    pub extension_content_types: HashMap<String, String>,
+    /// Serve expired files with a `Warning` header when the gateways are unreachable,
+    /// time out or answer 5xx
+    pub serve_stale_on_error: bool,
     pub max_content_length: u64,
+    /// Keep interrupted downloads and resume them with a `Range` request
+    pub resumable_downloads: bool,
+    /// Interrupted downloads untouched for longer are deleted by the cleanup bin
+    pub partial_max_age_hours: u64,
+    pub warn_content_length: u64,
+    pub min_free_bytes: u64,
+    /// Free inodes below which `/health` fails and files aren't cached, 0 disables it
+    pub min_free_inodes: u64,
     pub server_port: u16,
+    /// Requests and gateway fetches taking longer are logged at warn, faster ones at debug
+    pub slow_request_threshold_ms: Option<u64>,
+    /// Requests with more query parameters, or longer values, are rejected with a 400
+    pub max_query_params: usize,
+    pub max_query_value_length: usize,
+    /// Urls nested deeper below their CID are rejected before touching the disk
+    pub max_path_segments: usize,
+    /// Responses smaller than this are sent uncompressed
+    pub compress_min_bytes: u64,
+    /// Content type prefixes worth compressing, media formats already are
+    pub compress_content_types: Vec<String>,
+    /// Requests per second allowed from a client IP, answered with a 429 above it
+    pub client_requests_per_second: Option<u64>,
+    /// Requests a client IP can have in progress at once
+    pub max_concurrent_requests_per_client: Option<usize>,
+    /// Header holding the client IP, e.g. `X-Forwarded-For`. Only set it behind a proxy
+    /// overwriting or appending to it, otherwise clients can pick their own IP.
+    pub client_ip_header: Option<String>,
+    /// Send a `Server-Timing` header with the cache, fetch, resize and total durations
+    pub server_timing: bool,
+    /// Bearer token for admin endpoints, they are disabled without it
+    pub admin_token: Option<String>,
+    /// Secret signing `size_token` query parameters, tokens are ignored when unset
+    pub size_token_secret: Option<String>,
+    /// Only serve urls carrying a `signature` made with `url_signing_secret`
+    pub require_signed_urls: bool,
+    pub url_signing_secret: Option<String>,
+    /// HTML template served on errors to clients accepting `text/html`
+    pub error_page_path: Option<String>,
+    /// Run pending migrations when the server starts instead of refusing to start
+    pub auto_migrate: bool,
     pub db_max_connections: u32,
     pub db_min_connections: u32,
     pub permitted_resize_dimensions: Vec<Dimension>,
+    /// Images smaller than this in either dimension are served without resizing
+    pub min_resizable_dimension: u32,
+    /// Directory for resized thumbnails, written next to the originals when unset
+    pub thumbnail_directory: Option<String>,
+    /// Bytes of thumbnails kept by the cleanup bin, least recently requested ones are
+    /// deleted above it
+    pub max_thumbnail_cache_bytes: Option<u64>,
+    pub pregenerate_thumbnails: bool,
+    /// Sizes resized in the background when an image is first cached, must be permitted
+    #[serde(default)]
+    pub eager_resize_dimensions: Vec<Dimension>,
+    pub max_concurrent_resizes: usize,
+    /// Output format preferred by `img-auto` among those the client accepts
+    pub resize_auto_policy: ResizeAutoPolicy,
+    /// JPEG quality of `img-auto` thumbnails, from 1 to 100
+    pub resize_auto_jpeg_quality: u8,
+}
+
+/// Format picked by `img-auto` when the client's `Accept` header allows several
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeAutoPolicy {
+    /// JPEG sources stay JPEG, anything else may have transparency so it's PNG
+    Original,
+    /// JPEG, smaller but without transparency
+    Lossy,
+    /// PNG
+    Lossless,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq, Eq)]
@@ -21,22 +167,231 @@ pub struct Dimension {
     pub height: u32,
 }
 
+/// Upstream stage of `fetch_sources`
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchSource {
+    /// `ipfs_gateways`, queried at once
+    Gateways,
+    /// `fallback_proxy_urls`, queried at once
+    FallbackProxies,
+}
+
+/// Options applying to a single gateway from `ipfs_gateways`, matched on `url`
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct GatewaySettings {
+    pub url: String,
+    /// PEM root certificate added when connecting to this gateway
+    pub ca_cert_path: Option<String>,
+    #[serde(default)]
+    pub danger_accept_invalid_certs: bool,
+    /// Appended to `url` with `{cid}` and `{path}` replaced, e.g. `/api/v0/cat?arg={cid}{path}`.
+    /// Defaults to `/{cid}{path}`.
+    pub path_template: Option<String>,
+    /// Replaces `gateway_http_version` for this gateway
+    pub http_version: Option<HttpVersion>,
+    /// Method of requests to this gateway. Defaults to POST for Kubo RPC `/api/v0/`
+    /// templates, GET otherwise.
+    pub method: Option<GatewayMethod>,
+}
+
+/// HTTP method of gateway requests
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GatewayMethod {
+    Get,
+    /// Required by the Kubo RPC API
+    Post,
+}
+
+/// HTTP version of gateway requests
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpVersion {
+    /// HTTP/2 when negotiated with ALPN over TLS, HTTP/1.1 otherwise
+    Auto,
+    /// Always HTTP/1.1, for gateways misbehaving over HTTP/2
+    Http1,
+    /// HTTP/2 with prior knowledge, also over plain HTTP
+    Http2,
+}
+
+/// Longest a gateway is blocked for, by `pause_gateway_seconds` or its `Retry-After`
+pub const MAX_GATEWAY_BLOCK_SECONDS: i64 = 24 * 3600;
+
+/// Longest `cache_max_age_seconds` and `content_type_max_age_seconds`, a hundred years
+pub const MAX_CACHE_MAX_AGE_SECONDS: i64 = 100 * 365 * 24 * 3600;
+
+/// Settings which can be changed while running with `PUT /config`
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeSettings {
+    pub ipfs_gateways: Vec<String>,
+    pub connect_timeout: u64,
+    pub max_redirects: usize,
+    pub pause_gateway_seconds: i64,
+    pub max_concurrent_fetches: usize,
+}
+
+impl From<&Settings> for RuntimeSettings {
+    fn from(settings: &Settings) -> Self {
+        RuntimeSettings {
+            ipfs_gateways: settings.ipfs_gateways.clone(),
+            connect_timeout: settings.connect_timeout,
+            max_redirects: settings.max_redirects,
+            pause_gateway_seconds: settings.pause_gateway_seconds,
+            max_concurrent_fetches: settings.max_concurrent_fetches,
+        }
+    }
+}
+
+/// A partial `RuntimeSettings`, any other setting is rejected
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeSettingsUpdate {
+    pub ipfs_gateways: Option<Vec<String>>,
+    pub connect_timeout: Option<u64>,
+    pub max_redirects: Option<usize>,
+    pub pause_gateway_seconds: Option<i64>,
+    pub max_concurrent_fetches: Option<usize>,
+}
+
+impl RuntimeSettings {
+    pub fn update(&self, update: RuntimeSettingsUpdate) -> Self {
+        RuntimeSettings {
+            ipfs_gateways: update
+                .ipfs_gateways
+                .unwrap_or_else(|| self.ipfs_gateways.clone()),
+            connect_timeout: update.connect_timeout.unwrap_or(self.connect_timeout),
+            max_redirects: update.max_redirects.unwrap_or(self.max_redirects),
+            pause_gateway_seconds: update
+                .pause_gateway_seconds
+                .unwrap_or(self.pause_gateway_seconds),
+            max_concurrent_fetches: update
+                .max_concurrent_fetches
+                .unwrap_or(self.max_concurrent_fetches),
+        }
+    }
+
+    /// Checked at startup with the other settings and on every `PUT /config`
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for gateway in &self.ipfs_gateways {
+            let is_http = reqwest::Url::parse(gateway)
+                .map(|url| matches!(url.scheme(), "http" | "https"))
+                .unwrap_or_default();
+            if !is_http {
+                return Err(ConfigError::Message(format!(
+                    "ipfs_gateways contains {gateway} which isn't an http(s) url"
+                )));
+            }
+        }
+
+        if self.connect_timeout == 0 {
+            return Err(ConfigError::Message(
+                "connect_timeout must be at least 1".to_string(),
+            ));
+        }
+
+        if !(0..=MAX_GATEWAY_BLOCK_SECONDS).contains(&self.pause_gateway_seconds) {
+            return Err(ConfigError::Message(format!(
+                "pause_gateway_seconds must be between 0 and {MAX_GATEWAY_BLOCK_SECONDS}"
+            )));
+        }
+
+        if self.max_concurrent_fetches == 0 {
+            return Err(ConfigError::Message(
+                "max_concurrent_fetches must be at least 1".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn full_directory(directory: &str) -> String {
+    if directory.starts_with('/') {
+        directory.to_string()
+    } else {
+        format!(
+            "{}/{}",
+            std::env::current_dir()
+                .expect("Can't get current directory")
+                .display(),
+            directory
+        )
+    }
+}
+
 impl Settings {
-    pub fn full_ipfs_cache_directory(&self) -> String {
-        if self.ipfs_cache_directory.starts_with('/') {
-            self.ipfs_cache_directory.clone()
-        } else {
-            format!(
-                "{}/{}",
-                std::env::current_dir()
-                    .expect("Can't get current directory")
-                    .display(),
-                self.ipfs_cache_directory
-            )
+    /// How long files of `content_type` are cached, forever when `None`
+    pub fn max_age_seconds(&self, content_type: &str) -> Option<i64> {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+
+        self.content_type_max_age_seconds
+            .iter()
+            .find(|(content_type, _)| content_type.eq_ignore_ascii_case(essence))
+            .map(|(_, max_age)| *max_age)
+            .or(self.cache_max_age_seconds)
+    }
+
+    /// `content_type` unless it's missing or generic, then the one of
+    /// `extension_content_types` for the extension of the url's last path segment
+    pub fn fallback_content_type(
+        &self,
+        ipfs_url: &str,
+        content_type: Option<String>,
+    ) -> Option<String> {
+        let generic = content_type
+            .as_deref()
+            .map(|content_type| content_type.split(';').next().unwrap_or_default().trim())
+            .map(|essence| {
+                essence.is_empty()
+                    || essence.eq_ignore_ascii_case(mime::APPLICATION_OCTET_STREAM.essence_str())
+            })
+            .unwrap_or(true);
+        if !generic {
+            return content_type;
         }
+
+        let extension = ipfs_url
+            .split(['?', '#'])
+            .next()
+            .and_then(|path| path.rsplit('/').next())
+            .and_then(|filename| std::path::Path::new(filename).extension())
+            .and_then(|extension| extension.to_str());
+        let Some(extension) = extension else {
+            return content_type;
+        };
+
+        self.extension_content_types
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+            .map(|(_, content_type)| content_type.clone())
+            .or(content_type)
+    }
+
+    pub fn gateway_settings(&self, ipfs_gateway: &str) -> Option<&GatewaySettings> {
+        self.gateway_settings
+            .iter()
+            .find(|gateway_settings| gateway_settings.url == ipfs_gateway)
+    }
+
+    pub fn full_ipfs_cache_directory(&self) -> String {
+        full_directory(&self.ipfs_cache_directory)
+    }
+
+    pub fn full_thumbnail_directory(&self) -> Option<String> {
+        self.thumbnail_directory
+            .as_ref()
+            .map(|directory| full_directory(directory))
     }
 
     pub fn new() -> Result<Self, ConfigError> {
+        Self::with_config_file(std::env::var("CONFIG_FILE").ok().as_deref())
+    }
+
+    /// Like `new` with `config_file` layered over the `config/` files and under the
+    /// environment, in any format the config crate knows from its extension (e.g. JSON)
+    pub fn with_config_file(config_file: Option<&str>) -> Result<Self, ConfigError> {
         let env_override = Environment::default().separator("__");
         let run_mode = if cfg!(test) {
             "test".to_string()
@@ -44,13 +399,369 @@ impl Settings {
             std::env::var("ENV").unwrap_or_else(|_| "development".to_string())
         };
 
-        let settings = Config::builder()
+        let mut builder = Config::builder()
             .add_source(File::with_name("config/config"))
             .add_source(File::with_name(&format!("config/{}", run_mode)).required(false))
-            .add_source(File::with_name("config/local").required(false))
-            .add_source(env_override)
-            .build()?;
+            .add_source(File::with_name("config/local").required(false));
+        if let Some(config_file) = config_file {
+            builder = builder.add_source(File::from(std::path::Path::new(config_file)));
+        }
+        let settings = builder.add_source(env_override).build()?;
+
+        let settings: Self = settings.try_deserialize()?;
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    /// Check settings which can't be expressed by deserialization alone
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_concurrent_disk_writes == 0 {
+            return Err(ConfigError::Message(
+                "max_concurrent_disk_writes must be at least 1".to_string(),
+            ));
+        }
+
+        RuntimeSettings::from(self).validate()?;
+
+        if self.require_signed_urls && self.url_signing_secret.is_none() {
+            return Err(ConfigError::Message(
+                "require_signed_urls needs a url_signing_secret".to_string(),
+            ));
+        }
+
+        if self.fetch_sources.is_empty() {
+            return Err(ConfigError::Message(
+                "fetch_sources needs at least one source".to_string(),
+            ));
+        }
+
+        if self.write_buffer_size == 0 {
+            return Err(ConfigError::Message(
+                "write_buffer_size must be at least 1".to_string(),
+            ));
+        }
+
+        for gateway_settings in &self.gateway_settings {
+            if let Some(ca_cert_path) = &gateway_settings.ca_cert_path {
+                if !std::path::Path::new(ca_cert_path).is_file() {
+                    return Err(ConfigError::Message(format!(
+                        "ca_cert_path {} for gateway {} doesn't exist",
+                        ca_cert_path, gateway_settings.url
+                    )));
+                }
+            }
+
+            if gateway_settings.danger_accept_invalid_certs {
+                warn!(
+                    "!!! TLS certificate verification is DISABLED for gateway {} !!!",
+                    gateway_settings.url
+                );
+            }
+        }
+
+        if self.convert_cid_v0 && self.redirect_to_canonical {
+            return Err(ConfigError::Message(
+                "convert_cid_v0 and redirect_to_canonical can't both be enabled".to_string(),
+            ));
+        }
+
+        if !(0.0..=1.0).contains(&self.bench_success_rate) {
+            return Err(ConfigError::Message(
+                "bench_success_rate must be between 0 and 1".to_string(),
+            ));
+        }
+
+        if self.bench_min_attempts > self.gateway_stats_window {
+            return Err(ConfigError::Message(
+                "bench_min_attempts can't be above gateway_stats_window".to_string(),
+            ));
+        }
+
+        if self.access_flush_seconds == 0 {
+            return Err(ConfigError::Message(
+                "access_flush_seconds must be at least 1".to_string(),
+            ));
+        }
+
+        let max_ages = self
+            .cache_max_age_seconds
+            .iter()
+            .chain(self.content_type_max_age_seconds.values());
+        for max_age in max_ages {
+            if !(0..=MAX_CACHE_MAX_AGE_SECONDS).contains(max_age) {
+                return Err(ConfigError::Message(format!(
+                    "cache max ages must be between 0 and {MAX_CACHE_MAX_AGE_SECONDS} seconds, not {max_age}"
+                )));
+            }
+        }
+
+        if self.warn_content_length >= self.max_content_length {
+            return Err(ConfigError::Message(
+                "warn_content_length must be below max_content_length".to_string(),
+            ));
+        }
+
+        if self.client_requests_per_second == Some(0) {
+            return Err(ConfigError::Message(
+                "client_requests_per_second must be at least 1".to_string(),
+            ));
+        }
+
+        if self.max_concurrent_requests_per_client == Some(0) {
+            return Err(ConfigError::Message(
+                "max_concurrent_requests_per_client must be at least 1".to_string(),
+            ));
+        }
+
+        if self.max_concurrent_resizes == 0 {
+            return Err(ConfigError::Message(
+                "max_concurrent_resizes must be at least 1".to_string(),
+            ));
+        }
+
+        if !(1..=100).contains(&self.resize_auto_jpeg_quality) {
+            return Err(ConfigError::Message(
+                "resize_auto_jpeg_quality must be between 1 and 100".to_string(),
+            ));
+        }
+
+        for dimension in &self.eager_resize_dimensions {
+            if !self.permitted_resize_dimensions.contains(dimension) {
+                return Err(ConfigError::Message(format!(
+                    "eager_resize_dimensions contains {}x{} which isn't permitted",
+                    dimension.width, dimension.height
+                )));
+            }
+        }
+
+        let mut seen = Vec::new();
+
+        for dimension in &self.permitted_resize_dimensions {
+            if dimension.width == 0 || dimension.height == 0 {
+                return Err(ConfigError::Message(format!(
+                    "permitted_resize_dimensions can't contain a zero dimension: {}x{}",
+                    dimension.width, dimension.height
+                )));
+            }
+
+            if seen.contains(&dimension) {
+                warn!(
+                    "permitted_resize_dimensions contains {}x{} more than once",
+                    dimension.width, dimension.height
+                );
+            } else {
+                seen.push(dimension);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layer_config_file() -> Result<(), anyhow::Error> {
+        let directory = tempfile::tempdir()?;
+        let config_file = directory.path().join("deploy.json");
+        std::fs::write(
+            &config_file,
+            r#"{"max_redirects": 7, "fallback_proxy_urls": ["https://proxy.test/ipfs"]}"#,
+        )?;
+
+        let settings = Settings::with_config_file(config_file.to_str())?;
+        assert_eq!(settings.max_redirects, 7);
+        assert_eq!(
+            settings.fallback_proxy_urls,
+            vec!["https://proxy.test/ipfs"]
+        );
+        // Other settings still come from config/
+        assert_eq!(
+            settings.ipfs_gateways,
+            Settings::new()
+                .expect("Can't create configuration")
+                .ipfs_gateways
+        );
+
+        assert!(Settings::with_config_file(Some("missing.json")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn content_type_by_extension() {
+        let mut settings = Settings::new().expect("Can't create configuration");
+        settings.extension_content_types =
+            HashMap::from([("glb".to_string(), "model/gltf-binary".to_string())]);
+        let url = "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/model.GLB";
+
+        assert_eq!(
+            settings.fallback_content_type(url, None),
+            Some("model/gltf-binary".to_string())
+        );
+        assert_eq!(
+            settings.fallback_content_type(url, Some("application/octet-stream".to_string())),
+            Some("model/gltf-binary".to_string())
+        );
+        // A specific content type is kept, unknown extensions keep the generic one
+        assert_eq!(
+            settings.fallback_content_type(url, Some("image/png".to_string())),
+            Some("image/png".to_string())
+        );
+        assert_eq!(
+            settings.fallback_content_type(
+                "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/scene.usdz",
+                Some("application/octet-stream".to_string())
+            ),
+            Some("application/octet-stream".to_string())
+        );
+    }
+
+    #[test]
+    fn max_age_by_content_type() {
+        let mut settings = Settings::new().expect("Can't create configuration");
+        settings.cache_max_age_seconds = None;
+        settings.content_type_max_age_seconds =
+            HashMap::from([("application/json".to_string(), 3600)]);
+
+        assert_eq!(
+            settings.max_age_seconds("Application/JSON; charset=utf-8"),
+            Some(3600)
+        );
+        assert_eq!(settings.max_age_seconds("image/png"), None);
+
+        settings.cache_max_age_seconds = Some(60);
+        assert_eq!(settings.max_age_seconds("image/png"), Some(60));
+    }
+
+    #[test]
+    fn reject_zero_dimension() {
+        let mut settings = Settings::new().expect("Can't create configuration");
+        settings.permitted_resize_dimensions = vec![Dimension {
+            width: 0,
+            height: 100,
+        }];
+
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn reject_eager_dimension_not_permitted() {
+        let mut settings = Settings::new().expect("Can't create configuration");
+        settings.eager_resize_dimensions = vec![Dimension {
+            width: 300,
+            height: 300,
+        }];
+
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn reject_bench_attempts_above_window() {
+        let mut settings = Settings::new().expect("Can't create configuration");
+        settings.gateway_stats_window = 10;
+        settings.bench_min_attempts = 11;
+        assert!(settings.validate().is_err());
+
+        settings.bench_min_attempts = 10;
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    fn reject_out_of_range_max_ages() {
+        let mut settings = Settings::new().expect("Can't create configuration");
+        settings.content_type_max_age_seconds = HashMap::new();
+        for max_age in [-1, MAX_CACHE_MAX_AGE_SECONDS + 1, i64::MAX] {
+            settings.cache_max_age_seconds = Some(max_age);
+            assert!(settings.validate().is_err());
+        }
+
+        settings.cache_max_age_seconds = Some(MAX_CACHE_MAX_AGE_SECONDS);
+        assert!(settings.validate().is_ok());
+
+        settings.content_type_max_age_seconds =
+            HashMap::from([("application/json".to_string(), i64::MAX)]);
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn reject_warn_content_length_above_max() {
+        let mut settings = Settings::new().expect("Can't create configuration");
+        settings.max_content_length = 100;
+        settings.warn_content_length = 100;
+        assert!(settings.validate().is_err());
+
+        settings.warn_content_length = 99;
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    fn reject_zero_client_limits() {
+        let mut settings = Settings::new().expect("Can't create configuration");
+        settings.client_requests_per_second = Some(0);
+        assert!(settings.validate().is_err());
+
+        settings.client_requests_per_second = Some(1);
+        settings.max_concurrent_requests_per_client = Some(0);
+        assert!(settings.validate().is_err());
+
+        settings.max_concurrent_requests_per_client = Some(1);
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    fn reject_signed_urls_without_secret() {
+        let mut settings = Settings::new().expect("Can't create configuration");
+        settings.require_signed_urls = true;
+        assert!(settings.validate().is_err());
+
+        settings.url_signing_secret = Some("secret".to_string());
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    fn reject_canonical_redirect_with_conversion() {
+        let mut settings = Settings::new().expect("Can't create configuration");
+        settings.convert_cid_v0 = true;
+        settings.redirect_to_canonical = true;
+
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn accept_duplicate_dimensions() {
+        let mut settings = Settings::new().expect("Can't create configuration");
+        settings.permitted_resize_dimensions = vec![
+            Dimension {
+                width: 100,
+                height: 100,
+            },
+            Dimension {
+                width: 100,
+                height: 100,
+            },
+        ];
+
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    fn update_runtime_settings() -> Result<(), serde_json::Error> {
+        let settings = Settings::new().expect("Can't create configuration");
+        let runtime = RuntimeSettings::from(&settings);
+
+        let update: RuntimeSettingsUpdate = serde_json::from_str(r#"{"connect_timeout": 5}"#)?;
+        let updated = runtime.update(update);
+        assert_eq!(updated.connect_timeout, 5);
+        assert_eq!(updated.ipfs_gateways, settings.ipfs_gateways);
+
+        let immutable =
+            serde_json::from_str::<RuntimeSettingsUpdate>(r#"{"ipfs_cache_directory": "/tmp"}"#);
+        assert!(immutable.is_err());
 
-        settings.try_deserialize()
+        Ok(())
     }
 }
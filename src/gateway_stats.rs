@@ -0,0 +1,75 @@
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use std::collections::VecDeque;
+
+use crate::config::Settings;
+
+lazy_static! {
+    /// Last `gateway_stats_window` request outcomes per gateway, true for a success
+    static ref OUTCOMES: DashMap<String, VecDeque<bool>> = Default::default();
+}
+
+/// Record a request to `ipfs_gateway`. Returns true when its success rate fell below
+/// `bench_success_rate`, its history is then cleared so it starts afresh once unbenched.
+pub fn record_outcome(config: &Settings, ipfs_gateway: &str, success: bool) -> bool {
+    let mut outcomes = OUTCOMES.entry(ipfs_gateway.to_string()).or_default();
+    outcomes.push_back(success);
+    while outcomes.len() > config.gateway_stats_window {
+        outcomes.pop_front();
+    }
+
+    let attempts = outcomes.len();
+    if attempts == 0 || attempts < config.bench_min_attempts {
+        return false;
+    }
+
+    let rate = outcomes.iter().filter(|success| **success).count() as f64 / attempts as f64;
+    if rate >= config.bench_success_rate {
+        return false;
+    }
+
+    outcomes.clear();
+    true
+}
+
+/// Success rate and number of attempts it's computed over, `None` before any attempt
+pub fn success_rate(ipfs_gateway: &str) -> Option<(f64, usize)> {
+    let outcomes = OUTCOMES.get(ipfs_gateway)?;
+    if outcomes.is_empty() {
+        return None;
+    }
+
+    let successes = outcomes.iter().filter(|success| **success).count();
+    Some((successes as f64 / outcomes.len() as f64, outcomes.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bench_failing_gateway() {
+        let mut config = Settings::new().expect("Can't create configuration");
+        config.gateway_stats_window = 4;
+        config.bench_min_attempts = 3;
+        config.bench_success_rate = 0.5;
+        let gateway = "http://bench.test/ipfs";
+
+        assert!(!record_outcome(&config, gateway, true));
+        assert!(!record_outcome(&config, gateway, false));
+        assert_eq!(success_rate(gateway), Some((0.5, 2)));
+        // 2 out of 3 successes is above the threshold
+        assert!(!record_outcome(&config, gateway, true));
+        assert!(!record_outcome(&config, gateway, false));
+        // The window only keeps the last 4 attempts, 1 success out of 4
+        assert!(record_outcome(&config, gateway, false));
+        assert_eq!(success_rate(gateway), None);
+
+        // A rate of 0 never benches
+        config.bench_success_rate = 0.0;
+        for _ in 0..4 {
+            assert!(!record_outcome(&config, gateway, false));
+        }
+        assert_eq!(success_rate(gateway), Some((0.0, 4)));
+    }
+}
@@ -1,36 +1,78 @@
 use anyhow::anyhow;
 use anyhow::Context;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use cid::Cid;
+use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
-use futures::stream::FuturesUnordered;
-use futures::StreamExt;
+use futures::future::{BoxFuture, Shared};
+use futures::{FutureExt, Stream, StreamExt};
 use lazy_static::lazy_static;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use reqwest::redirect::Policy;
 use reqwest_middleware::ClientBuilder;
 #[allow(unused_imports)]
 use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
 use reqwest_tracing::TracingMiddleware;
+use sha2::{Digest, Sha256};
 use std::fs;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
 use std::time::Instant;
-use tokio::task::JoinHandle;
-use tracing::{debug, error, info};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn};
 
 use crate::app_context::AppContext;
-use crate::caching::delete_caching;
-use crate::caching::get_caching;
 use crate::caching::set_stream_caching;
 use crate::caching::Data;
-use entity::ipfs_object::update_entry;
+use crate::caching::{encode_headers, is_uncached_content_type, set_stream_uncached};
+use crate::caching::{get_caching, get_stale_caching};
+use crate::caching::{partial_path, set_stream_resumable};
+use crate::config::{FetchSource, GatewayMethod, HttpVersion, Settings, MAX_GATEWAY_BLOCK_SECONDS};
+use crate::gateway_stats;
+use crate::metrics::{record_fetch_coalesced, record_fetch_leader, record_large_file};
+use crate::telemetry::log_duration;
+use crate::thumbnails::pregenerate_thumbnails;
+use entity::ipfs_object::refresh_entry;
 
 lazy_static! {
     static ref BLOCKED_GATEWAYS: tokio::sync::Mutex<DashMap<String, DateTime<Utc>>> =
         Default::default();
+    static ref IN_FLIGHT: DashMap<(String, u64), InFlight> = Default::default();
 }
 
-#[tracing::instrument(skip_all)]
+/// Gateway fetch of a url awaited by every concurrent request for it, removed from
+/// `IN_FLIGHT` once done
+type InFlight = Shared<BoxFuture<'static, Result<Data, Arc<anyhow::Error>>>>;
+
 pub async fn fetch_ipfs_data(ctx: Arc<AppContext>, ipfs_url: &str) -> Result<Data, anyhow::Error> {
+    let max_content_length = ctx.config.max_content_length;
+    fetch_ipfs_data_with_limit(ctx, ipfs_url, max_content_length).await
+}
+
+/// Like `fetch_ipfs_data` with a size limit replacing `max_content_length`, e.g. granted
+/// by a `size_token`
+#[tracing::instrument(skip_all)]
+pub async fn fetch_ipfs_data_with_limit(
+    ctx: Arc<AppContext>,
+    ipfs_url: &str,
+    max_content_length: u64,
+) -> Result<Data, anyhow::Error> {
+    if !ctx.config.caching_enabled {
+        return Err(anyhow!(
+            "Caching is disabled, can't fetch {ipfs_url} to disk"
+        ));
+    }
+
+    let mut ipfs_url = normalize_ipfs_url(ipfs_url);
+    if ctx.config.convert_cid_v0 {
+        ipfs_url = convert_cid_v0_to_v1(&ipfs_url)?;
+    }
+    let ipfs_url = ipfs_url.as_str();
     let base_uri = check_ipfs_url(ipfs_url)?;
+    check_path_segments(&ctx.config, &base_uri)?;
+    check_allowed_cid(&ctx.config, ipfs_url)?;
 
     match get_caching(ctx.clone(), ipfs_url).await {
         Err(error) => {
@@ -38,21 +80,8 @@ pub async fn fetch_ipfs_data(ctx: Arc<AppContext>, ipfs_url: &str) -> Result<Dat
         }
         Ok(cached_data) => {
             if let Some(cached_data) = cached_data {
-                let content_length = cached_data
-                    .filename
-                    .as_ref()
-                    .and_then(|f| fs::metadata(f).map(|t| t.len()).ok())
-                    .unwrap_or_default();
-                let content_type = cached_data.content_type.clone().unwrap_or_default();
-                let ipfs_url = ipfs_url.to_string();
-
-                tokio::spawn(async move {
-                    if let Err(error) =
-                        update_entry(&ctx.db, &ipfs_url, &content_type, content_length as i64).await
-                    {
-                        error!("Error updating sqlite: {}", error);
-                    }
-                });
+                // Written in batches, see `access_times`
+                ctx.access_times.record(ipfs_url);
 
                 debug!("Return cached data");
                 return Ok(cached_data);
@@ -60,47 +89,454 @@ pub async fn fetch_ipfs_data(ctx: Arc<AppContext>, ipfs_url: &str) -> Result<Dat
         }
     }
 
-    // We stop using gateways who gave us a 429 too many requests
-    let blocked_gateways = BLOCKED_GATEWAYS.lock().await;
+    // Concurrent requests for the same url and size limit share a single gateway fetch
+    let started = Instant::now();
+    let key = (ipfs_url.to_string(), max_content_length);
+    let (in_flight, leader) = match IN_FLIGHT.entry(key.clone()) {
+        Entry::Occupied(entry) => (entry.get().clone(), false),
+        Entry::Vacant(entry) => {
+            let ctx = ctx.clone();
+            let fetch = async move {
+                // A panic would poison the shared future left in `IN_FLIGHT` for every
+                // later request, it fails this fetch only
+                let result =
+                    AssertUnwindSafe(fetch_or_stale(ctx, &key.0, &base_uri, max_content_length))
+                        .catch_unwind()
+                        .await
+                        .unwrap_or_else(|_| Err(anyhow!("Fetching {} panicked", key.0)));
+                IN_FLIGHT.remove(&key);
+                result.map_err(Arc::new)
+            };
+            (entry.insert(fetch.boxed().shared()).clone(), true)
+        }
+    };
 
-    let urls: Vec<String> = ctx
-        .config
-        .ipfs_gateways
-        .iter()
-        .filter(|ipfs_gateway| match blocked_gateways.get(*ipfs_gateway) {
-            None => true,
-            Some(utc_time) => {
-                let diff = Utc::now() - *utc_time;
-                diff.num_seconds() >= ctx.config.pause_gateway_seconds
+    if leader {
+        record_fetch_leader();
+    } else {
+        record_fetch_coalesced();
+    }
+
+    let result = match in_flight.await {
+        // The file is removed once served, every request needs its own
+        Ok(data) if data.uncached && !leader => {
+            let base_uri = check_ipfs_url(ipfs_url)?;
+            fetch_or_stale(ctx, ipfs_url, &base_uri, max_content_length).await
+        }
+        Ok(data) => Ok(data),
+        Err(error) => Err(unshare_error(error)),
+    };
+
+    result.map(|mut data| {
+        data.fetch_duration = Some(started.elapsed());
+        data
+    })
+}
+
+/// Fetch `ipfs_url` from the gateways, or serve its stale entry with
+/// `serve_stale_on_error` when they couldn't answer
+async fn fetch_or_stale(
+    ctx: Arc<AppContext>,
+    ipfs_url: &str,
+    base_uri: &str,
+    max_content_length: u64,
+) -> Result<Data, anyhow::Error> {
+    // Cache hits aren't bounded, only requests to the gateways
+    let permit = ctx.fetches.acquire().await?;
+    let result = fetch_and_cache(ctx.clone(), ipfs_url, base_uri, max_content_length).await;
+    drop(permit);
+
+    match result {
+        Err(error)
+            if ctx.config.serve_stale_on_error && has_cause::<GatewaysUnavailable>(&error) =>
+        {
+            match get_stale_caching(ctx.clone(), ipfs_url).await {
+                Ok(Some(mut stale)) => {
+                    warn!("Serving stale {ipfs_url}, can't fetch it again: {error}");
+                    stale.headers.push((
+                        "Warning".to_string(),
+                        "110 - \"Response is Stale\"".to_string(),
+                    ));
+                    Ok(stale)
+                }
+                _ => Err(error),
             }
-        })
-        .map(|ipfs_gateway| format!("{}/{}", ipfs_gateway, base_uri))
-        .collect::<Vec<String>>();
+        }
+        result => result,
+    }
+}
+
+/// Error of a fetch shared by concurrent requests for the same url. The fetch error is
+/// its source, see `has_cause`.
+#[derive(Debug)]
+pub struct SharedFetchError(Arc<anyhow::Error>);
+
+impl std::fmt::Display for SharedFetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SharedFetchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&**self.0)
+    }
+}
+
+/// The fetch error itself once no other request holds it
+fn unshare_error(error: Arc<anyhow::Error>) -> anyhow::Error {
+    Arc::try_unwrap(error).unwrap_or_else(|error| SharedFetchError(error).into())
+}
+
+/// Whether `error` or one of its causes is a `T`, shared fetch errors included
+pub fn has_cause<T: std::error::Error + 'static>(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<T>())
+}
+
+/// Fetch the first of `cids` which can be fetched, for files pinned under several CIDs
+pub async fn fetch_first_available(
+    ctx: Arc<AppContext>,
+    cids: &[String],
+) -> Result<Data, anyhow::Error> {
+    let mut last_error = anyhow!("No CID to fetch");
+
+    for cid in cids {
+        match fetch_ipfs_data(ctx.clone(), &format!("ipfs://{cid}")).await {
+            Ok(data) => return Ok(data),
+            Err(error) => {
+                warn!("Can't fetch {cid}, trying next CID: {error}");
+                last_error = error;
+            }
+        }
+    }
+
+    Err(last_error)
+}
 
-    let mut futures = urls
-        .clone()
-        .into_iter()
-        .map(|url| {
+/// Fetch from the gateways and write the response to the cache
+async fn fetch_and_cache(
+    ctx: Arc<AppContext>,
+    ipfs_url: &str,
+    base_uri: &str,
+    max_content_length: u64,
+) -> Result<Data, anyhow::Error> {
+    let (mut result, trusted) = fetch_from_sources(
+        ctx.clone(),
+        ipfs_url,
+        base_uri,
+        max_content_length,
+        ctx.config.resumable_downloads,
+        |response, permit| {
             let ctx = ctx.clone();
-            tokio::spawn(async move {
-                let client = reqwest::ClientBuilder::new()
-                    .user_agent(&ctx.config.user_agent.clone())
-                    .connect_timeout(std::time::Duration::from_millis(ctx.config.connect_timeout))
-                    .timeout(std::time::Duration::from_millis(ctx.config.connect_timeout))
-                    .build()?;
-                let client_with_middleware = ClientBuilder::new(client)
-                    .with(TracingMiddleware::default())
-                    .build();
-
-                client_with_middleware.get(url).send().await
-            })
+            async move {
+                let _permit = permit;
+                let trusted = is_trusted_gateway(&ctx.config, response.url());
+
+                let content_type = response
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok().map(|t| t.to_string()));
+
+                check_extension_content_type(&ctx.config, ipfs_url, content_type.as_deref())?;
+
+                let headers = forwarded_headers(&ctx, response.headers());
+                let resume_from = resumed_offset(&response);
+
+                let stream = Box::pin(response.bytes_stream());
+                let mut result = if is_uncached_content_type(&ctx, content_type.as_deref()) {
+                    set_stream_uncached(ctx, ipfs_url, content_type, stream, max_content_length)
+                        .await?
+                } else if ctx.config.resumable_downloads {
+                    set_stream_resumable(
+                        ctx,
+                        ipfs_url,
+                        content_type,
+                        stream,
+                        max_content_length,
+                        resume_from,
+                    )
+                    .await?
+                } else {
+                    set_stream_caching(ctx, ipfs_url, content_type, stream, max_content_length)
+                        .await?
+                };
+                result.headers = headers;
+
+                Ok((result, trusted))
+            }
+        },
+    )
+    .await?;
+
+    // Our own gateways are trusted, public ones get their content checked
+    if !trusted {
+        validate_content_type(&mut result);
+    }
+    result.content_type = ctx
+        .config
+        .fallback_content_type(ipfs_url, result.content_type.take());
+
+    let content_length = result
+        .filename
+        .as_ref()
+        .and_then(|f| fs::metadata(f).map(|t| t.len()).ok())
+        .unwrap_or_default();
+
+    if content_length > ctx.config.warn_content_length {
+        warn!(
+            "File {} is {} bytes, above warn_content_length {}",
+            ipfs_url, content_length, ctx.config.warn_content_length
+        );
+        record_large_file();
+    }
+
+    if result.uncached {
+        return Ok(result);
+    }
+
+    // A listing fetched without the trailing slash is recorded under the `/` url, which
+    // `get_caching` falls back to for both forms
+    let is_listing = !ipfs_url.ends_with('/')
+        && !ipfs_url.ends_with("/index.html")
+        && result
+            .filename
+            .as_ref()
+            .map(|filename| filename.ends_with("/index.html"))
+            .unwrap_or_default();
+    let entry_url = if is_listing {
+        format!("{ipfs_url}/")
+    } else {
+        ipfs_url.to_string()
+    };
+
+    refresh_entry(
+        &ctx.db,
+        &entry_url,
+        &result.content_type.clone().unwrap_or_default(),
+        content_length as i64,
+        encode_headers(&result.headers),
+        result.content_hash.clone(),
+    )
+    .await?;
+
+    if ctx.config.pregenerate_thumbnails {
+        let dimensions = ctx.config.permitted_resize_dimensions.clone();
+        pregenerate_thumbnails(ctx.clone(), &result, dimensions);
+    } else if !ctx.config.eager_resize_dimensions.is_empty() {
+        let dimensions = ctx.config.eager_resize_dimensions.clone();
+        pregenerate_thumbnails(ctx.clone(), &result, dimensions);
+    }
+
+    Ok(result)
+}
+
+/// Gateway response of `stream_ipfs_data`, its fetch stays counted in
+/// `max_concurrent_fetches` and `max_connections_per_gateway` until the body is
+/// streamed or dropped
+pub struct StreamedResponse {
+    pub response: reqwest::Response,
+    permits: Vec<OwnedSemaphorePermit>,
+}
+
+impl StreamedResponse {
+    pub fn bytes_stream(self) -> impl Stream<Item = reqwest::Result<bytes::Bytes>> {
+        let permits = self.permits;
+        self.response.bytes_stream().map(move |chunk| {
+            let _ = &permits;
+            chunk
         })
-        .collect::<FuturesUnordered<JoinHandle<_>>>();
+    }
+}
+
+/// Fetch from the gateways without caching, the response body is left for the
+/// caller to stream. Used when `caching_enabled` is false.
+#[tracing::instrument(skip_all)]
+pub async fn stream_ipfs_data(
+    ctx: Arc<AppContext>,
+    ipfs_url: &str,
+) -> Result<StreamedResponse, anyhow::Error> {
+    let mut ipfs_url = normalize_ipfs_url(ipfs_url);
+    if ctx.config.convert_cid_v0 {
+        ipfs_url = convert_cid_v0_to_v1(&ipfs_url)?;
+    }
+    let base_uri = check_ipfs_url(&ipfs_url)?;
+    check_path_segments(&ctx.config, &base_uri)?;
+    check_allowed_cid(&ctx.config, &ipfs_url)?;
+    let max_content_length = ctx.config.max_content_length;
+
+    let permit = ctx.fetches.clone().acquire_owned().await?;
+    let (response, gateway_permit) = fetch_from_sources(
+        ctx,
+        &ipfs_url,
+        &base_uri,
+        max_content_length,
+        false,
+        |response, gateway_permit| async move { Ok((response, gateway_permit)) },
+    )
+    .await?;
+
+    Ok(StreamedResponse {
+        response,
+        permits: std::iter::once(permit).chain(gateway_permit).collect(),
+    })
+}
+
+/// Try each of `fetch_sources` in order until one hands a response to `on_response`.
+/// With `resumable`, a partial download left by an earlier attempt is resumed.
+async fn fetch_from_sources<F, Fut, T>(
+    ctx: Arc<AppContext>,
+    ipfs_url: &str,
+    base_uri: &str,
+    max_content_length: u64,
+    resumable: bool,
+    mut on_response: F,
+) -> Result<T, anyhow::Error>
+where
+    F: FnMut(reqwest::Response, Option<OwnedSemaphorePermit>) -> Fut,
+    Fut: Future<Output = Result<T, anyhow::Error>>,
+{
+    let mut last_error = anyhow!("No source to fetch {ipfs_url} from");
+    let mut attempts_left = match ctx.config.max_fetch_attempts {
+        0 => usize::MAX,
+        max_fetch_attempts => max_fetch_attempts,
+    };
+
+    for source in &ctx.config.fetch_sources {
+        let ipfs_gateways = match source {
+            FetchSource::Gateways => ctx.runtime.load().ipfs_gateways.clone(),
+            FetchSource::FallbackProxies => ctx.config.fallback_proxy_urls.clone(),
+        };
+        if ipfs_gateways.is_empty() {
+            continue;
+        }
+        if attempts_left == 0 {
+            return Err(FetchBudgetExhausted(ctx.config.max_fetch_attempts).into());
+        }
+
+        // Left behind by an interrupted download, only its missing end is requested
+        let range_start = if resumable {
+            fs::metadata(partial_path(&ctx, ipfs_url))
+                .map(|metadata| metadata.len())
+                .unwrap_or_default()
+        } else {
+            0
+        };
+
+        match fetch_from_gateways(
+            ctx.clone(),
+            &ipfs_gateways,
+            ipfs_url,
+            base_uri,
+            max_content_length,
+            range_start,
+            &mut attempts_left,
+            &mut on_response,
+        )
+        .await
+        {
+            Ok(value) => return Ok(value),
+            Err(error) => {
+                warn!("Can't fetch {ipfs_url} from {source:?}: {error}");
+                last_error = error;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Query every non-blocked gateway of `ipfs_gateways` at once, or in turn with
+/// `hedge_delay_ms`, and hand successful responses to `on_response` in the order they
+/// arrive. When reading a body fails (e.g. a truncated stream) the next gateway response
+/// is tried, any other error is returned. A non zero `range_start` asks the gateways
+/// for the end of the file only, to resume a partial download. Each request takes one of
+/// `attempts_left`, gateways left once they're spent aren't queried.
+#[allow(clippy::too_many_arguments)]
+async fn fetch_from_gateways<F, Fut, T>(
+    ctx: Arc<AppContext>,
+    ipfs_gateways: &[String],
+    ipfs_url: &str,
+    base_uri: &str,
+    max_content_length: u64,
+    range_start: u64,
+    attempts_left: &mut usize,
+    mut on_response: F,
+) -> Result<T, anyhow::Error>
+where
+    F: FnMut(reqwest::Response, Option<OwnedSemaphorePermit>) -> Fut,
+    Fut: Future<Output = Result<T, anyhow::Error>>,
+{
+    let runtime = ctx.runtime.load_full();
+
+    // We stop using gateways who gave us a 429 too many requests
+    let gateways: Vec<(String, String)> = {
+        let blocked_gateways = BLOCKED_GATEWAYS.lock().await;
+
+        // Gateways whose pause has elapsed are unblocked, the map only keeps blocked ones
+        blocked_gateways.retain(|ipfs_gateway, unblock_at| {
+            let blocked = Utc::now() < *unblock_at;
+            if !blocked {
+                info!("gateway {} is unblocked", ipfs_gateway);
+            }
+            blocked
+        });
+
+        ipfs_gateways
+            .iter()
+            .filter(|ipfs_gateway| !blocked_gateways.contains_key(*ipfs_gateway))
+            .map(|ipfs_gateway| {
+                (
+                    ipfs_gateway.clone(),
+                    gateway_url(&ctx.config, ipfs_gateway, base_uri),
+                )
+            })
+            .collect()
+    };
+    let urls = gateways
+        .iter()
+        .map(|(_, url)| url.clone())
+        .collect::<Vec<String>>();
+
+    // Dropping the set aborts the requests still running once a response is picked
+    let mut pending = gateways.into_iter();
+    let mut requests = JoinSet::new();
+    let hedge_delay = ctx
+        .config
+        .hedge_delay_ms
+        .map(std::time::Duration::from_millis);
+    if hedge_delay.is_some() {
+        if let Some((ipfs_gateway, url)) = next_gateway(&mut pending, attempts_left) {
+            spawn_gateway_request(&mut requests, &ctx, ipfs_gateway, url, range_start);
+        }
+    } else {
+        while let Some((ipfs_gateway, url)) = next_gateway(&mut pending, attempts_left) {
+            spawn_gateway_request(&mut requests, &ctx, ipfs_gateway, url, range_start);
+        }
+    }
 
     debug!("fetching {urls:?}");
     let now = Instant::now();
-    while let Some(result) = futures.next().await {
-        let value = result?; // a potential stream error
+    let mut outcomes = Vec::new();
+    let mut not_found: Vec<String> = Vec::new();
+    let mut hedges = 0;
+    loop {
+        let hedge_delay = hedge_delay
+            .filter(|_| hedges < ctx.config.max_hedges && pending.len() > 0 && *attempts_left > 0);
+        let result = tokio::select! {
+            result = requests.join_next() => match result {
+                Some(result) => result,
+                None => break,
+            },
+            _ = tokio::time::sleep(hedge_delay.unwrap_or_default()), if hedge_delay.is_some() => {
+                if let Some((ipfs_gateway, url)) = next_gateway(&mut pending, attempts_left) {
+                    debug!("No response within {hedge_delay:?}, hedging with {ipfs_gateway}");
+                    spawn_gateway_request(&mut requests, &ctx, ipfs_gateway, url, range_start);
+                    hedges += 1;
+                }
+                continue;
+            }
+        };
+        let (ipfs_gateway, value, permit) = result?; // a potential stream error
 
         match value {
             Ok(response) => {
@@ -109,76 +545,109 @@ pub async fn fetch_ipfs_data(ctx: Arc<AppContext>, ipfs_url: &str) -> Result<Dat
 
                 // Some IPFS gateway returns 404 because they don't have the data in cache.
                 match status {
-                    reqwest::StatusCode::OK => {
+                    // A misconfigured gateway may redirect to another object
+                    status
+                        if status.is_success()
+                            && ctx.config.check_response_cid
+                            && !response_references_cid(&url, base_uri) =>
+                    {
+                        warn!("{url} doesn't address {base_uri}, trying next gateway");
+                        outcomes.push("wrong cid".to_string());
+                        record_gateway_outcome(&ctx, ipfs_gateways, &ipfs_gateway, false).await;
+                    }
+                    // We never send Range, a 206 covering the whole file is as good as a 200
+                    reqwest::StatusCode::OK | reqwest::StatusCode::PARTIAL_CONTENT
+                        if status == reqwest::StatusCode::OK
+                            || (ctx.config.accept_partial_content
+                                && is_full_content(&response))
+                            || (range_start > 0 && resumed_offset(&response) == range_start) =>
+                    {
                         if let Some(content_length) = response.content_length() {
-                            if content_length > ctx.config.max_content_length {
-                                return Err(anyhow!(
-                                    "File is {} bytes, maximum allowed is {}",
-                                    content_length,
-                                    ctx.config.max_content_length
-                                ));
+                            if content_length > max_content_length {
+                                return Err(ContentTooLarge {
+                                    size: content_length,
+                                    max_content_length,
+                                    aborted: false,
+                                }
+                                .into());
                             }
                         }
 
-                        let content_type = response
-                            .headers()
-                            .get(reqwest::header::CONTENT_TYPE)
-                            .and_then(|value| value.to_str().ok().map(|t| t.to_string()));
-
-                        let stream = Box::pin(response.bytes_stream());
-                        let result =
-                            set_stream_caching(ctx.clone(), ipfs_url, content_type, stream).await?;
-
-                        let content_length = result
-                            .filename
-                            .as_ref()
-                            .and_then(|f| fs::metadata(f).map(|t| t.len()).ok())
-                            .unwrap_or_default();
-
-                        if content_length > ctx.config.max_content_length {
-                            delete_caching(ctx.clone(), ipfs_url).await?;
-                            return Err(anyhow!(
-                                "File is {} bytes, maximum allowed is {}. Fetched and deleting cached file.",
-                                content_length,
-                                ctx.config.max_content_length
-                            ));
-                        }
+                        // The slot is handed over with the response, it's kept until
+                        // the body is read
+                        match on_response(response, permit).await {
+                            Ok(value) => {
+                                record_gateway_outcome(&ctx, ipfs_gateways, &ipfs_gateway, true)
+                                    .await;
+                                // They lack a file another gateway has
+                                for not_found in &not_found {
+                                    record_gateway_outcome(&ctx, ipfs_gateways, not_found, false)
+                                        .await;
+                                }
+                                log_duration(
+                                    ctx.config.slow_request_threshold_ms,
+                                    now.elapsed(),
+                                    &format!("[{}] Fetched {ipfs_url} from {url}", status.as_u16()),
+                                );
 
-                        info!(
-                            "[{}] [{:.3?}] Fetched {} from {}",
+                                return Ok(value);
+                            }
+                            Err(error) if error.downcast_ref::<reqwest::Error>().is_some() => {
+                                error!("Reading {url} failed, trying next gateway: {error}");
+                                outcomes.push("truncated".to_string());
+                                record_gateway_outcome(&ctx, ipfs_gateways, &ipfs_gateway, false)
+                                    .await;
+                            }
+                            Err(error) if error.downcast_ref::<ContentTypeMismatch>().is_some() => {
+                                warn!("Ignoring {url}, trying next gateway: {error}");
+                                outcomes.push("mismatch".to_string());
+                                record_gateway_outcome(&ctx, ipfs_gateways, &ipfs_gateway, false)
+                                    .await;
+                            }
+                            Err(error) => return Err(error),
+                        }
+                    }
+                    reqwest::StatusCode::PARTIAL_CONTENT => {
+                        outcomes.push(status.as_u16().to_string());
+                        record_gateway_outcome(&ctx, ipfs_gateways, &ipfs_gateway, false).await;
+                        warn!(
+                            "[{}] [{:.3?}] ignoring partial content from {url}",
                             status.as_u16(),
-                            now.elapsed(),
-                            &ipfs_url,
-                            &url,
+                            now.elapsed()
                         );
-
-                        update_entry(
-                            &ctx.db,
-                            ipfs_url,
-                            &result.content_type.clone().unwrap_or_default(),
-                            content_length as i64,
-                        )
-                        .await?;
-
-                        return Ok(result);
                     }
                     reqwest::StatusCode::TOO_MANY_REQUESTS => {
-                        if let Some(host) = url.host() {
-                            let host = host.to_string();
-                            for ipfs_gateway in &ctx.config.ipfs_gateways {
-                                if ipfs_gateway.contains(&host) {
-                                    error!(
-                                        "gateway {} returned 429. Adding to block list",
-                                        ipfs_gateway
-                                    );
-                                    let blocked_gateways = BLOCKED_GATEWAYS.lock().await;
-
-                                    blocked_gateways.insert(ipfs_gateway.clone(), Utc::now());
-                                }
+                        outcomes.push(status.as_u16().to_string());
+                        let unblock_at = response
+                            .headers()
+                            .get(reqwest::header::RETRY_AFTER)
+                            .and_then(|value| value.to_str().ok())
+                            .and_then(|value| parse_retry_after(value, Utc::now()))
+                            .or_else(|| block_until(Utc::now(), runtime.pause_gateway_seconds))
+                            .unwrap_or_else(Utc::now);
+
+                        for ipfs_gateway in ipfs_gateways {
+                            if is_gateway_url(ipfs_gateway, &url) {
+                                error!(
+                                    "gateway {} returned 429. Adding to block list until {}",
+                                    ipfs_gateway, unblock_at
+                                );
+                                let blocked_gateways = BLOCKED_GATEWAYS.lock().await;
+
+                                blocked_gateways.insert(ipfs_gateway.clone(), unblock_at);
                             }
                         }
                     }
+                    // Only a failure once another gateway serves the file, a bogus CID
+                    // must not bench every gateway
+                    reqwest::StatusCode::NOT_FOUND => {
+                        outcomes.push(status.as_u16().to_string());
+                        not_found.push(ipfs_gateway);
+                        debug!("[404] [{:.3?}] fetched {url}", now.elapsed());
+                    }
                     _ => {
+                        outcomes.push(status.as_u16().to_string());
+                        record_gateway_outcome(&ctx, ipfs_gateways, &ipfs_gateway, false).await;
                         debug!(
                             "[{}] [{:.3?}] fetched {url}",
                             status.as_u16(),
@@ -189,92 +658,2206 @@ pub async fn fetch_ipfs_data(ctx: Arc<AppContext>, ipfs_url: &str) -> Result<Dat
             }
             Err(error) => {
                 info!("failed fetching: {error}");
+                outcomes.push(error_outcome(&error));
+                record_gateway_outcome(&ctx, ipfs_gateways, &ipfs_gateway, false).await;
+            }
+        }
+
+        // When hedging, a failed gateway is replaced right away
+        if hedge_delay.is_some() {
+            if let Some((ipfs_gateway, url)) = next_gateway(&mut pending, attempts_left) {
+                spawn_gateway_request(&mut requests, &ctx, ipfs_gateway, url, range_start);
             }
         }
     }
 
-    error!("Couldn't fetch any url: {urls:?}");
-    Err(anyhow!("Couldn't fetch any url: {urls:?}"))
+    let summary = outcome_summary(&outcomes);
+    if pending.len() > 0 {
+        error!("Gave up on {ipfs_url} with gateways left to try ({summary}): {urls:?}");
+        return Err(FetchBudgetExhausted(ctx.config.max_fetch_attempts).into());
+    }
+    error!("Couldn't fetch any url ({summary}): {urls:?}");
+    if !outcomes.is_empty() && outcomes.iter().all(|outcome| outcome == "404") {
+        return Err(NotFoundOnGateways(ipfs_url.to_string()).into());
+    }
+    if outcomes
+        .iter()
+        .all(|outcome| is_unavailable_outcome(outcome))
+    {
+        return Err(GatewaysUnavailable { summary, urls }.into());
+    }
+    Err(anyhow!("Couldn't fetch any url ({summary}): {urls:?}"))
 }
 
-/// Check if the IPFS urls seems correct, return the base uri
-pub fn check_ipfs_url(ipfs_url: &str) -> Result<String, anyhow::Error> {
-    let ipfs_string = "ipfs://";
+/// Outcomes of gateways which couldn't answer: unreachable, timed out, failing with a
+/// 5xx or cutting the body short
+fn is_unavailable_outcome(outcome: &str) -> bool {
+    matches!(outcome, "timeout" | "connect error" | "error" | "truncated")
+        || outcome.starts_with('5')
+}
 
-    let base_uri = if let Some(stripped) = ipfs_url.strip_prefix(ipfs_string) {
-        stripped.to_string()
-    } else {
-        return Err(anyhow!("Not an IPFS URL: {ipfs_url}"));
-    };
+/// Next gateway to query, `None` once they're all queried or `attempts_left` is spent
+fn next_gateway(
+    pending: &mut std::vec::IntoIter<(String, String)>,
+    attempts_left: &mut usize,
+) -> Option<(String, String)> {
+    if *attempts_left == 0 {
+        return None;
+    }
 
-    let splits = base_uri.split('/').collect::<Vec<&str>>();
-    let first = match splits.first() {
-        Some(first) => first,
-        None => {
-            return Err(anyhow!(
-                "Not an IPFS URL: {ipfs_url}, no CID on first split"
-            ));
+    let next = pending.next()?;
+    *attempts_left -= 1;
+    Some(next)
+}
+
+/// Result of a gateway request, with its `max_connections_per_gateway` slot
+type GatewayResponse = (
+    String,
+    Result<reqwest::Response, anyhow::Error>,
+    Option<OwnedSemaphorePermit>,
+);
+
+/// Request `url` from `ipfs_gateway` in the background, the result is tagged with the gateway
+fn spawn_gateway_request(
+    requests: &mut JoinSet<GatewayResponse>,
+    ctx: &Arc<AppContext>,
+    ipfs_gateway: String,
+    url: String,
+    range_start: u64,
+) {
+    let ctx = ctx.clone();
+    requests.spawn(async move {
+        let permit = match ctx.config.max_connections_per_gateway {
+            0 => None,
+            max_connections => {
+                let connections = ctx
+                    .gateway_connections
+                    .entry(ipfs_gateway.clone())
+                    .or_insert_with(|| Arc::new(Semaphore::new(max_connections)))
+                    .clone();
+                connections.acquire_owned().await.ok()
+            }
+        };
+
+        let response = async {
+            let client = gateway_client(&ctx, &ipfs_gateway)?;
+            let client_with_middleware = ClientBuilder::new(client)
+                .with(TracingMiddleware::default())
+                .build();
+
+            let method = gateway_method(&ctx.config, &ipfs_gateway);
+            let mut request = client_with_middleware.request(method, url);
+            if range_start > 0 {
+                request = request.header(reqwest::header::RANGE, format!("bytes={range_start}-"));
+            }
+
+            Ok::<_, anyhow::Error>(request.send().await?)
         }
-    };
+        .await;
 
-    // Check if CID is good
-    Cid::try_from(first.to_string()).with_context(|| format!("CID is invalid for {}", ipfs_url))?;
+        (ipfs_gateway, response, permit)
+    });
+}
 
-    Ok(base_uri)
+/// Track the gateway success rate, benching it like a 429 when it falls too low unless
+/// it's the last of `ipfs_gateways` which isn't blocked
+async fn record_gateway_outcome(
+    ctx: &AppContext,
+    ipfs_gateways: &[String],
+    ipfs_gateway: &str,
+    success: bool,
+) {
+    if !gateway_stats::record_outcome(&ctx.config, ipfs_gateway, success) {
+        return;
+    }
+
+    let blocked_gateways = BLOCKED_GATEWAYS.lock().await;
+    let now = Utc::now();
+    let others_active = ipfs_gateways.iter().any(|other| {
+        other != ipfs_gateway
+            && blocked_gateways
+                .get(other)
+                .map(|unblock_at| *unblock_at <= now)
+                .unwrap_or(true)
+    });
+    if !others_active {
+        warn!(
+            "gateway {} success rate is below {}, kept as the last active gateway",
+            ipfs_gateway, ctx.config.bench_success_rate
+        );
+        return;
+    }
+
+    let unblock_at = now + Duration::seconds(ctx.config.bench_gateway_seconds);
+    error!(
+        "gateway {} success rate is below {}. Adding to block list until {}",
+        ipfs_gateway, ctx.config.bench_success_rate, unblock_at
+    );
+    blocked_gateways.insert(ipfs_gateway.to_string(), unblock_at);
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use sea_orm::entity::prelude::*;
+#[derive(Debug, serde::Serialize)]
+pub struct GatewayStatus {
+    pub url: String,
+    /// RFC 3339 date until which the gateway isn't used
+    pub blocked_until: Option<String>,
+    pub success_rate: Option<f64>,
+    pub attempts: usize,
+}
 
-    #[tokio::test]
-    async fn fetch_json() -> Result<(), anyhow::Error> {
-        let ctx = Arc::new(AppContext::build().await);
-        let remote_url =
-            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/1";
-        let result = fetch_ipfs_data(ctx.clone(), remote_url).await?;
+/// Block list and success rate of the current gateways
+pub async fn gateway_statuses(ctx: &AppContext) -> Vec<GatewayStatus> {
+    let blocked_gateways = BLOCKED_GATEWAYS.lock().await;
+    let now = Utc::now();
 
-        let ipfs_object = entity::ipfs_object::Entity::find()
-            .filter(entity::ipfs_object::Column::RemoteUrl.eq(remote_url))
-            .one(&ctx.db)
-            .await?
-            .expect("Can't find ipfs object");
-        assert_eq!(ipfs_object.content_type, "application/json");
+    ctx.runtime
+        .load()
+        .ipfs_gateways
+        .iter()
+        .map(|ipfs_gateway| {
+            let rate = gateway_stats::success_rate(ipfs_gateway);
+            GatewayStatus {
+                url: ipfs_gateway.clone(),
+                blocked_until: blocked_gateways
+                    .get(ipfs_gateway)
+                    .map(|unblock_at| *unblock_at)
+                    .filter(|unblock_at| *unblock_at > now)
+                    .map(|unblock_at| unblock_at.to_rfc3339()),
+                success_rate: rate.map(|(rate, _)| rate),
+                attempts: rate.map(|(_, attempts)| attempts).unwrap_or_default(),
+            }
+        })
+        .collect()
+}
 
-        let expected = Data {
-            content_type: Some("application/json".to_string()),
-            filename: Some(
-                "tmp/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/1"
-                    .to_string(),
-            ),
-        };
-        assert_eq!(result, expected);
+/// Short label of a failed gateway request for the exhaustion summary
+fn error_outcome(error: &anyhow::Error) -> String {
+    let reqwest_error = match error.downcast_ref::<reqwest_middleware::Error>() {
+        Some(reqwest_middleware::Error::Reqwest(error)) => Some(error),
+        _ => error.downcast_ref::<reqwest::Error>(),
+    };
 
-        let result = fetch_ipfs_data(
-            ctx,
-            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/1",
-        )
-        .await?;
-        assert_eq!(result, expected);
+    match reqwest_error {
+        Some(error) if error.is_timeout() => "timeout",
+        Some(error) if error.is_connect() => "connect error",
+        _ => "error",
+    }
+    .to_string()
+}
 
-        Ok(())
+/// Count identical outcomes, e.g. "3x404, 1x429, 2x timeout"
+fn outcome_summary(outcomes: &[String]) -> String {
+    if outcomes.is_empty() {
+        return "no gateway available".to_string();
     }
 
-    #[tokio::test]
-    async fn fetch_large_file() {
-        let mut ctx = AppContext::build().await;
-        ctx.config.max_content_length = 1;
-        let ctx = Arc::new(ctx);
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for outcome in outcomes {
+        match counts.iter_mut().find(|(label, _)| label == outcome) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((outcome, 1)),
+        }
+    }
 
-        let remote_url =
-            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/1";
+    counts
+        .iter()
+        .map(|(label, count)| {
+            if label.parse::<u16>().is_ok() {
+                format!("{count}x{label}")
+            } else {
+                format!("{count}x {label}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
 
-        let result = fetch_ipfs_data(ctx.clone(), remote_url).await;
+/// `seconds` from `now`, at most `MAX_GATEWAY_BLOCK_SECONDS`. `None` when negative.
+fn block_until(now: DateTime<Utc>, seconds: i64) -> Option<DateTime<Utc>> {
+    if seconds < 0 {
+        return None;
+    }
 
-        assert_eq!(
-            result.err().expect("Expected error").to_string(),
-            "File is 1023 bytes, maximum allowed is 1"
-        );
+    now.checked_add_signed(Duration::seconds(seconds.min(MAX_GATEWAY_BLOCK_SECONDS)))
+}
+
+/// `Retry-After` is either delta-seconds or an HTTP-date, capped to
+/// `MAX_GATEWAY_BLOCK_SECONDS`. `None` when invalid or negative.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    let latest = block_until(now, MAX_GATEWAY_BLOCK_SECONDS)?;
+
+    match value.parse::<i64>() {
+        Ok(seconds) => block_until(now, seconds),
+        // Too large for an i64, the cap applies
+        Err(_) if !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_digit()) => {
+            Some(latest)
+        }
+        Err(_) => DateTime::parse_from_rfc2822(value)
+            .ok()
+            .map(|date| date.with_timezone(&Utc).min(latest)),
+    }
+}
+
+/// The gateway headers listed in `forward_headers`
+pub fn forwarded_headers(
+    ctx: &AppContext,
+    headers: &reqwest::header::HeaderMap,
+) -> Vec<(String, String)> {
+    ctx.config
+        .forward_headers
+        .iter()
+        .filter_map(|name| {
+            let value = headers.get(name.as_str())?.to_str().ok()?;
+            Some((name.to_lowercase(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Start, end and total length from a `Content-Range: bytes <start>-<end>/<total>` header
+fn content_range(response: &reqwest::Response) -> Option<(u64, u64, u64)> {
+    let (range, total) = response
+        .headers()
+        .get(reqwest::header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes ")?
+        .split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+
+    Some((start.parse().ok()?, end.parse().ok()?, total.parse().ok()?))
+}
+
+/// A 206 response is complete without `Content-Range` or when the range spans the whole file
+fn is_full_content(response: &reqwest::Response) -> bool {
+    if !response
+        .headers()
+        .contains_key(reqwest::header::CONTENT_RANGE)
+    {
+        return true;
+    }
+
+    matches!(content_range(response), Some((0, end, total)) if end + 1 == total)
+}
+
+/// Offset a response body starts at, non zero for a 206 resuming a partial download
+fn resumed_offset(response: &reqwest::Response) -> u64 {
+    if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        return 0;
+    }
+
+    content_range(response)
+        .map(|(start, _, _)| start)
+        .unwrap_or_default()
+}
+
+/// Responses from `trusted_gateways` skip content validation. The final url must be on
+/// one of them, a redirect elsewhere isn't trusted.
+fn is_trusted_gateway(config: &Settings, url: &reqwest::Url) -> bool {
+    config
+        .trusted_gateways
+        .iter()
+        .any(|trusted_gateway| is_gateway_url(trusted_gateway, url))
+}
+
+/// Whether `url` has the exact host and port of `ipfs_gateway`
+fn is_gateway_url(ipfs_gateway: &str, url: &reqwest::Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+
+    reqwest::Url::parse(ipfs_gateway)
+        .map(|gateway| {
+            gateway.host_str() == Some(host)
+                && gateway.port_or_known_default() == url.port_or_known_default()
+        })
+        .unwrap_or_default()
+}
+
+/// Replace the content type sent by the gateway when the cached bytes say otherwise
+fn validate_content_type(data: &mut Data) {
+    let Some(filename) = &data.filename else {
+        return;
+    };
+
+    if let Ok(Some(kind)) = infer::get_from_path(filename) {
+        let declared = data
+            .content_type
+            .as_deref()
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|content_type| content_type.trim());
+
+        if declared != Some(kind.mime_type()) {
+            warn!(
+                "Gateway sent content type {:?} for {}, content is {}",
+                declared,
+                filename,
+                kind.mime_type()
+            );
+            data.content_type = Some(kind.mime_type().to_string());
+        }
+    }
+}
+
+/// The gateway content type doesn't match a `strict_content_type_extensions` url,
+/// usually an error page served for a missing file
+#[derive(Debug)]
+pub struct ContentTypeMismatch {
+    pub content_type: String,
+    pub extension: String,
+}
+
+impl std::fmt::Display for ContentTypeMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Gateway sent content type {} for a .{} file",
+            self.content_type, self.extension
+        )
+    }
+}
+
+impl std::error::Error for ContentTypeMismatch {}
+
+/// Reject a declared content type which `mime_guess` doesn't associate with the url
+/// extension, for extensions listed in `strict_content_type_extensions`
+fn check_extension_content_type(
+    config: &Settings,
+    ipfs_url: &str,
+    content_type: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    let Some(extension) = ipfs_url
+        .rsplit('/')
+        .next()
+        .and_then(|filename| std::path::Path::new(filename).extension())
+        .and_then(|extension| extension.to_str())
+        .filter(|extension| {
+            config
+                .strict_content_type_extensions
+                .iter()
+                .any(|strict| strict.eq_ignore_ascii_case(extension))
+        })
+    else {
+        return Ok(());
+    };
+    let Some(content_type) = content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|content_type| content_type.trim())
+    else {
+        return Ok(());
+    };
+
+    if mime_guess::from_ext(extension)
+        .iter()
+        .any(|mime| mime.essence_str().eq_ignore_ascii_case(content_type))
+    {
+        return Ok(());
+    }
+
+    Err(ContentTypeMismatch {
+        content_type: content_type.to_string(),
+        extension: extension.to_lowercase(),
+    }
+    .into())
+}
+
+/// Url of `base_uri` on a gateway, `{gateway}/{cid}{path}` unless it has a `path_template`
+fn gateway_url(config: &Settings, ipfs_gateway: &str, base_uri: &str) -> String {
+    let template = config
+        .gateway_settings(ipfs_gateway)
+        .and_then(|gateway_settings| gateway_settings.path_template.as_deref());
+    let Some(template) = template else {
+        return format!("{ipfs_gateway}/{base_uri}");
+    };
+
+    let (cid, path) = base_uri.split_at(base_uri.find('/').unwrap_or(base_uri.len()));
+    let in_query = matches!(
+        (template.find('?'), template.find("{path}")),
+        (Some(query), Some(path)) if query < path
+    );
+    let path = if in_query {
+        path.split('/')
+            .map(|segment| {
+                let decoded = percent_decode_str(segment).decode_utf8_lossy();
+                utf8_percent_encode(&decoded, QUERY_SEGMENT).to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    } else {
+        path.to_string()
+    };
+
+    format!(
+        "{ipfs_gateway}{}",
+        template.replace("{cid}", cid).replace("{path}", &path)
+    )
+}
+
+/// Method of requests to `ipfs_gateway`, POST for Kubo RPC templates unless configured
+fn gateway_method(config: &Settings, ipfs_gateway: &str) -> reqwest::Method {
+    let Some(gateway_settings) = config.gateway_settings(ipfs_gateway) else {
+        return reqwest::Method::GET;
+    };
+    let is_rpc = gateway_settings
+        .path_template
+        .as_deref()
+        .is_some_and(|template| template.starts_with("/api/v0/"));
+
+    match gateway_settings.method {
+        Some(GatewayMethod::Get) => reqwest::Method::GET,
+        Some(GatewayMethod::Post) => reqwest::Method::POST,
+        None if is_rpc => reqwest::Method::POST,
+        None => reqwest::Method::GET,
+    }
+}
+
+/// Identity CID of empty content, gateways answer it without fetching anything
+const EMPTY_IDENTITY_CID: &str = "bafkqaaa";
+
+/// Request the empty identity CID from `ipfs_gateway` to check it's reachable and serving
+pub async fn check_gateway(ctx: &AppContext, ipfs_gateway: &str) -> Result<(), anyhow::Error> {
+    let client = gateway_client(ctx, ipfs_gateway)?;
+    let url = gateway_url(&ctx.config, ipfs_gateway, EMPTY_IDENTITY_CID);
+    let method = gateway_method(&ctx.config, ipfs_gateway);
+    let status = client.request(method, &url).send().await?.status();
+
+    if !status.is_success() {
+        return Err(anyhow!("{url} answered {status}"));
+    }
+
+    Ok(())
+}
+
+/// The HTTP client for a gateway, applying its TLS settings. It's built once and shared,
+/// unless the connect timeout or redirect limit changed since.
+fn gateway_client(ctx: &AppContext, ipfs_gateway: &str) -> Result<reqwest::Client, anyhow::Error> {
+    let runtime = ctx.runtime.load();
+    let built_for = (runtime.connect_timeout, runtime.max_redirects);
+    if let Some(client) = ctx.gateway_clients.get(ipfs_gateway) {
+        if client.0 == built_for {
+            return Ok(client.1.clone());
+        }
+    }
+
+    let mut builder = reqwest::ClientBuilder::new()
+        .user_agent(ctx.config.user_agent.clone())
+        .connect_timeout(std::time::Duration::from_millis(runtime.connect_timeout))
+        .timeout(std::time::Duration::from_millis(runtime.connect_timeout))
+        .redirect(redirect_policy(runtime.max_redirects))
+        .pool_max_idle_per_host(ctx.config.gateway_pool_max_idle_per_host);
+
+    let gateway_settings = ctx.config.gateway_settings(ipfs_gateway);
+    let http_version = gateway_settings
+        .and_then(|gateway_settings| gateway_settings.http_version)
+        .unwrap_or(ctx.config.gateway_http_version);
+    builder = match http_version {
+        HttpVersion::Auto => builder,
+        HttpVersion::Http1 => builder.http1_only(),
+        HttpVersion::Http2 => builder.http2_prior_knowledge(),
+    };
+
+    if let Some(gateway_settings) = gateway_settings {
+        if let Some(ca_cert_path) = &gateway_settings.ca_cert_path {
+            let pem = fs::read(ca_cert_path)
+                .with_context(|| format!("Can't read ca_cert_path {ca_cert_path}"))?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+
+        if gateway_settings.danger_accept_invalid_certs {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+    }
+
+    let client = builder.build()?;
+    ctx.gateway_clients
+        .insert(ipfs_gateway.to_string(), (built_for, client.clone()));
+
+    Ok(client)
+}
+
+/// Follow at most `max_redirects` redirects, logging each one followed
+fn redirect_policy(max_redirects: usize) -> Policy {
+    Policy::custom(move |attempt| {
+        if attempt.previous().len() > max_redirects {
+            attempt.error(format!("too many redirects, maximum is {max_redirects}"))
+        } else {
+            if let Some(previous) = attempt.previous().last() {
+                info!("Following redirect from {} to {}", previous, attempt.url());
+            }
+            attempt.follow()
+        }
+    })
+}
+
+/// Characters percent-encoded in the path segments of canonical urls: the path segment
+/// set of the URL standard, `%` so decoding is unambiguous and `\` which gateways may
+/// read as a slash
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}')
+    .add(b'%')
+    .add(b'/')
+    .add(b'\\');
+
+/// Characters escaped in a path segment put into a query string by a `path_template`
+const QUERY_SEGMENT: &AsciiSet = &PATH_SEGMENT.add(b'&').add(b'+').add(b'=').add(b';');
+
+/// Canonical form of a decoded path segment, as sent to the gateways
+pub fn encode_path_segment(segment: &str) -> String {
+    utf8_percent_encode(segment, PATH_SEGMENT).to_string()
+}
+
+/// Path segment as named in the cache directory. Segments which aren't UTF-8 or would
+/// add a level to the path once decoded are rejected.
+pub fn decode_path_segment(segment: &str) -> Result<String, anyhow::Error> {
+    let decoded = percent_decode_str(segment)
+        .decode_utf8()
+        .with_context(|| format!("Path segment {segment:?} isn't UTF-8 once decoded"))?;
+
+    if decoded.contains(['/', '\0']) {
+        return Err(anyhow!(
+            "Path segment {segment:?} has a slash or a NUL once decoded"
+        ));
+    }
+
+    Ok(decoded.into_owned())
+}
+
+/// Canonicalize an IPFS url so the same content always maps to the same cache entry:
+/// lowercase the scheme, collapse duplicate slashes and percent-encode path segments the
+/// same way whether they arrived encoded or not. A trailing slash is kept whatever the
+/// last segment looks like, `foo.json/` can be a directory. CIDs are case-sensitive and
+/// left untouched.
+pub fn normalize_ipfs_url(ipfs_url: &str) -> String {
+    let ipfs_string = "ipfs://";
+
+    let Some(base_uri) = ipfs_url
+        .get(..ipfs_string.len())
+        .filter(|scheme| scheme.eq_ignore_ascii_case(ipfs_string))
+        .map(|_| &ipfs_url[ipfs_string.len()..])
+    else {
+        return ipfs_url.to_string();
+    };
+
+    let is_directory = base_uri.ends_with('/');
+    let splits = base_uri
+        .split('/')
+        .filter(|split| !split.is_empty())
+        .enumerate()
+        .map(
+            |(index, split)| match percent_decode_str(split).decode_utf8() {
+                Ok(decoded) if index > 0 => encode_path_segment(&decoded),
+                // Invalid segments are left for `check_ipfs_url` to reject
+                _ => split.to_string(),
+            },
+        )
+        .collect::<Vec<String>>();
+
+    let mut normalized = format!("{ipfs_string}{}", splits.join("/"));
+    if is_directory {
+        normalized.push('/');
+    }
+
+    normalized
+}
+
+/// Rewrite a CIDv0 (`Qm...`) url to its base32 CIDv1 form so both forms share one cache entry
+pub fn convert_cid_v0_to_v1(ipfs_url: &str) -> Result<String, anyhow::Error> {
+    let base_uri = check_ipfs_url(ipfs_url)?;
+    let (first, path) = base_uri.split_at(base_uri.find('/').unwrap_or(base_uri.len()));
+
+    let cid = Cid::try_from(first).with_context(|| format!("CID is invalid for {}", ipfs_url))?;
+    if cid.version() != cid::Version::V0 {
+        return Ok(ipfs_url.to_string());
+    }
+
+    let cid = cid
+        .into_v1()
+        .with_context(|| format!("Can't convert CID to v1 for {}", ipfs_url))?;
+
+    Ok(format!("ipfs://{cid}{path}"))
+}
+
+/// Whether the final url of a response still addresses the CID of `base_uri`, in path
+/// (`/ipfs/<cid>`) or subdomain (`<cid>.ipfs.host`) form. Subdomains hold the base32
+/// CIDv1, so it's matched too.
+fn response_references_cid(url: &reqwest::Url, base_uri: &str) -> bool {
+    let cid = base_uri.split('/').next().unwrap_or_default();
+    let mut cids = vec![cid.to_string()];
+    if let Ok(canonical) = canonical_cid(cid) {
+        cids.push(canonical);
+    }
+
+    let subdomain = url
+        .host_str()
+        .and_then(|host| host.split('.').next())
+        .unwrap_or_default();
+
+    cids.iter().any(|cid| {
+        subdomain.eq_ignore_ascii_case(cid)
+            || url
+                .path_segments()
+                .map(|mut segments| segments.any(|segment| segment == cid))
+                .unwrap_or_default()
+    })
+}
+
+/// Base32 CIDv1 form of `cid`, what `redirect_to_canonical` redirects to
+pub fn canonical_cid(cid: &str) -> Result<String, anyhow::Error> {
+    let parsed = Cid::try_from(cid).with_context(|| format!("CID is invalid: {cid}"))?;
+    let parsed = parsed
+        .into_v1()
+        .with_context(|| format!("Can't convert CID to v1: {cid}"))?;
+
+    Ok(parsed.to_string())
+}
+
+/// Multicodec of raw blocks, whose CID hashes the file content itself
+const RAW_CODEC: u64 = 0x55;
+const SHA2_256: u64 = 0x12;
+
+/// Whether `bytes` hash to `cid`. `None` unless it's a raw sha2-256 CID, others hash the
+/// UnixFS DAG built from the file rather than the file.
+pub fn content_matches_cid(cid: &str, bytes: &[u8]) -> Option<bool> {
+    let cid = Cid::try_from(cid).ok()?;
+    if cid.codec() != RAW_CODEC || cid.hash().code() != SHA2_256 {
+        return None;
+    }
+
+    Some(cid.hash().digest() == Sha256::digest(bytes).as_slice())
+}
+
+/// The requested CID isn't in `allowed_cid_prefixes`
+#[derive(Debug)]
+pub struct CidNotAllowed(pub String);
+
+impl std::fmt::Display for CidNotAllowed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CID is not allowed: {}", self.0)
+    }
+}
+
+impl std::error::Error for CidNotAllowed {}
+
+/// Every gateway queried answered 404
+#[derive(Debug)]
+pub struct NotFoundOnGateways(pub String);
+
+impl std::fmt::Display for NotFoundOnGateways {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Every gateway answered 404 for {}", self.0)
+    }
+}
+
+impl std::error::Error for NotFoundOnGateways {}
+
+/// No gateway could answer, they were unreachable, timed out or failed with a 5xx
+#[derive(Debug)]
+pub struct GatewaysUnavailable {
+    pub summary: String,
+    pub urls: Vec<String>,
+}
+
+impl std::fmt::Display for GatewaysUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Couldn't fetch any url ({}): {:?}",
+            self.summary, self.urls
+        )
+    }
+}
+
+impl std::error::Error for GatewaysUnavailable {}
+
+/// The file is larger than the size limit, as announced by the gateway or found while
+/// writing it
+#[derive(Debug)]
+pub struct ContentTooLarge {
+    /// Announced size, or the bytes received when the download was aborted
+    pub size: u64,
+    pub max_content_length: u64,
+    pub aborted: bool,
+}
+
+impl std::fmt::Display for ContentTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.aborted {
+            write!(
+                f,
+                "File is more than {} bytes, maximum allowed is {}. Aborted while fetching.",
+                self.size, self.max_content_length
+            )
+        } else {
+            write!(
+                f,
+                "File is {} bytes, maximum allowed is {}",
+                self.size, self.max_content_length
+            )
+        }
+    }
+}
+
+impl std::error::Error for ContentTooLarge {}
+
+/// Every gateway request allowed by `max_fetch_attempts` was made without success
+#[derive(Debug)]
+pub struct FetchBudgetExhausted(pub usize);
+
+impl std::fmt::Display for FetchBudgetExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Gave up after max_fetch_attempts {} gateway requests",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for FetchBudgetExhausted {}
+
+/// Reject CIDs outside `allowed_cid_prefixes`, an empty list allows everything
+pub fn check_allowed_cid(config: &Settings, ipfs_url: &str) -> Result<(), anyhow::Error> {
+    if config.allowed_cid_prefixes.is_empty() {
+        return Ok(());
+    }
+
+    let base_uri = check_ipfs_url(ipfs_url)?;
+    let cid = base_uri.split('/').next().unwrap_or_default();
+
+    if config
+        .allowed_cid_prefixes
+        .iter()
+        .any(|prefix| cid.starts_with(prefix))
+    {
+        Ok(())
+    } else {
+        Err(CidNotAllowed(cid.to_string()).into())
+    }
+}
+
+/// Reject paths nested deeper than `max_path_segments` below the CID, they'd make deep
+/// cache directories and filenames longer than the filesystem accepts
+pub fn check_path_segments(config: &Settings, base_uri: &str) -> Result<(), anyhow::Error> {
+    let segments = base_uri
+        .split('/')
+        .skip(1)
+        .filter(|segment| !segment.is_empty())
+        .count();
+
+    if segments > config.max_path_segments {
+        return Err(anyhow!(
+            "Path of {base_uri} has {segments} segments, maximum allowed is {}",
+            config.max_path_segments
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check if the IPFS urls seems correct, return the base uri
+pub fn check_ipfs_url(ipfs_url: &str) -> Result<String, anyhow::Error> {
+    let ipfs_string = "ipfs://";
+    let ipfs_url = &normalize_ipfs_url(ipfs_url);
+
+    let base_uri = if let Some(stripped) = ipfs_url.strip_prefix(ipfs_string) {
+        stripped.to_string()
+    } else {
+        return Err(anyhow!("Not an IPFS URL: {ipfs_url}"));
+    };
+
+    let splits = base_uri.split('/').collect::<Vec<&str>>();
+    let first = match splits.first() {
+        Some(first) => first,
+        None => {
+            return Err(anyhow!(
+                "Not an IPFS URL: {ipfs_url}, no CID on first split"
+            ));
+        }
+    };
+
+    // Check if CID is good
+    Cid::try_from(first.to_string()).with_context(|| format!("CID is invalid for {}", ipfs_url))?;
+
+    if splits.iter().any(|split| *split == "." || *split == "..") {
+        return Err(anyhow!("Not an IPFS URL: {ipfs_url}, path traversal"));
+    }
+
+    for split in splits.iter().skip(1) {
+        decode_path_segment(split).with_context(|| format!("Not an IPFS URL: {ipfs_url}"))?;
+    }
+
+    Ok(base_uri)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caching::{caching_path, delete_caching};
+    use crate::config::{GatewaySettings, RuntimeSettings};
+    use crate::test_support::{
+        http_response, mock_context, mock_context_with, mock_gateway, mock_gateway_with,
+    };
+    use chrono::TimeZone;
+    use entity::ipfs_object::update_entry;
+    use sea_orm::entity::prelude::*;
+    use sea_orm::sea_query::Expr;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn fetch_gzip_encoded() -> Result<(), anyhow::Error> {
+        let body = br#"{"name":"gzip"}"#;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(body)?;
+        let gateway = mock_gateway(
+            http_response(
+                "200 OK",
+                &[
+                    ("Content-Type", "application/json"),
+                    ("Content-Encoding", "gzip"),
+                ],
+                &encoder.finish()?,
+            ),
+            0,
+        )
+        .await;
+        let ctx = mock_context(vec![gateway]).await;
+
+        let remote_url =
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/gzip/1";
+        let result = fetch_ipfs_data(ctx.clone(), remote_url).await?;
+
+        let filename = result.filename.expect("Expected a filename");
+        assert_eq!(fs::read(&filename)?, body);
+
+        delete_caching(ctx, remote_url).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_after_truncated_stream() -> Result<(), anyhow::Error> {
+        let truncated =
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 100\r\n\r\ntruncated"
+                .to_vec();
+        let body = b"complete";
+        let ctx = mock_context(vec![
+            mock_gateway(truncated, 0).await,
+            mock_gateway(
+                http_response("200 OK", &[("Content-Type", "text/plain")], body),
+                500,
+            )
+            .await,
+        ])
+        .await;
+
+        let remote_url =
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/truncated/1";
+        let result = fetch_ipfs_data(ctx.clone(), remote_url).await?;
+
+        let filename = result.filename.expect("Expected a filename");
+        assert_eq!(fs::read(&filename)?, body);
+
+        delete_caching(ctx, remote_url).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_partial_content() -> Result<(), anyhow::Error> {
+        let body = b"complete body";
+        let ctx = mock_context(vec![
+            mock_gateway(
+                http_response(
+                    "206 Partial Content",
+                    &[
+                        ("Content-Type", "text/plain"),
+                        ("Content-Range", "bytes 0-12/13"),
+                    ],
+                    body,
+                ),
+                0,
+            )
+            .await,
+        ])
+        .await;
+
+        let remote_url =
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/partial/1";
+        let result = fetch_ipfs_data(ctx.clone(), remote_url).await?;
+
+        let filename = result.filename.expect("Expected a filename");
+        assert_eq!(fs::read(&filename)?, body);
+
+        delete_caching(ctx, remote_url).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limit_concurrent_fetches() -> Result<(), anyhow::Error> {
+        let gateway = mock_gateway(
+            http_response("200 OK", &[("Content-Type", "text/plain")], b"limited"),
+            300,
+        )
+        .await;
+        let ctx = mock_context_with(vec![gateway], |config| {
+            config.max_concurrent_fetches = 1;
+        })
+        .await;
+
+        let remote_urls = [
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/limited/1",
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/limited/2",
+        ];
+        let started = Instant::now();
+        let (first, second) = tokio::join!(
+            fetch_ipfs_data(ctx.clone(), remote_urls[0]),
+            fetch_ipfs_data(ctx.clone(), remote_urls[1])
+        );
+        first?;
+        second?;
+        // One after the other, not both at once
+        assert!(started.elapsed() >= std::time::Duration::from_millis(600));
+
+        for remote_url in remote_urls {
+            delete_caching(ctx.clone(), remote_url).await?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limit_concurrent_streams() -> Result<(), anyhow::Error> {
+        let gateway = mock_gateway(
+            http_response("200 OK", &[("Content-Type", "text/plain")], b"streamed"),
+            0,
+        )
+        .await;
+        let ctx = mock_context_with(vec![gateway], |config| {
+            config.max_concurrent_fetches = 1;
+        })
+        .await;
+
+        let streamed = stream_ipfs_data(
+            ctx.clone(),
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/streamed/1",
+        )
+        .await?;
+        // The body isn't sent yet, the fetch slot is still taken
+        let second = stream_ipfs_data(
+            ctx.clone(),
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/streamed/2",
+        );
+        tokio::pin!(second);
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(200), &mut second)
+                .await
+                .is_err()
+        );
+
+        let body: Vec<_> = streamed.bytes_stream().collect().await;
+        assert_eq!(body.len(), 1);
+        second.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bench_gateways_missing_served_files() -> Result<(), anyhow::Error> {
+        let not_found = mock_gateway(http_response("404 Not Found", &[], b"not found"), 0).await;
+        let other_not_found =
+            mock_gateway(http_response("404 Not Found", &[], b"not found"), 0).await;
+        let found = mock_gateway(
+            http_response("200 OK", &[("Content-Type", "text/plain")], b"found"),
+            200,
+        )
+        .await;
+        let failing = mock_gateway(http_response("500 Internal Server Error", &[], b""), 0).await;
+        let bench = |config: &mut Settings| {
+            config.gateway_stats_window = 2;
+            config.bench_min_attempts = 1;
+            config.bench_success_rate = 0.5;
+        };
+        let blocked = |ctx: Arc<AppContext>, gateway: String| async move {
+            gateway_statuses(&ctx)
+                .await
+                .into_iter()
+                .any(|status| status.url == gateway && status.blocked_until.is_some())
+        };
+        let cid = "bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344";
+
+        // A CID no gateway has doesn't count against them
+        let ctx = mock_context_with(vec![not_found.clone(), other_not_found.clone()], bench).await;
+        for index in 0..3 {
+            let remote_url = format!("ipfs://{cid}/bogus/{index}");
+            assert!(fetch_ipfs_data(ctx.clone(), &remote_url).await.is_err());
+        }
+        assert!(!blocked(ctx.clone(), not_found.clone()).await);
+        assert!(!blocked(ctx.clone(), other_not_found.clone()).await);
+
+        // It does once another gateway serves the file
+        let ctx = mock_context_with(vec![not_found.clone(), found], bench).await;
+        let remote_url = format!("ipfs://{cid}/served");
+        fetch_ipfs_data(ctx.clone(), &remote_url).await?;
+        assert!(blocked(ctx.clone(), not_found).await);
+        delete_caching(ctx, &remote_url).await?;
+
+        // The last active gateway is never benched
+        let ctx = mock_context_with(vec![failing.clone()], bench).await;
+        for index in 0..3 {
+            let remote_url = format!("ipfs://{cid}/failing/{index}");
+            assert!(fetch_ipfs_data(ctx.clone(), &remote_url).await.is_err());
+        }
+        assert!(!blocked(ctx, failing).await);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn share_failed_fetch() -> Result<(), anyhow::Error> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let gateway = mock_gateway_with(300, move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            http_response("404 Not Found", &[], b"not found")
+        })
+        .await;
+        let ctx = mock_context(vec![gateway]).await;
+
+        let remote_url =
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/shared";
+        let started = Instant::now();
+        let results =
+            futures::future::join_all((0..3).map(|_| fetch_ipfs_data(ctx.clone(), remote_url)))
+                .await;
+
+        // One gateway request, its error handed to every request
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(started.elapsed() < std::time::Duration::from_millis(600));
+        for result in results {
+            let error = result.expect_err("Expected error");
+            assert!(has_cause::<NotFoundOnGateways>(&error));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_percent_encoded_path() -> Result<(), anyhow::Error> {
+        let request_lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received = request_lines.clone();
+        let gateway = mock_gateway_with(0, move |request| {
+            let request = String::from_utf8_lossy(request);
+            let request_line = request.lines().next().unwrap_or_default().to_string();
+            received.lock().unwrap().push(request_line);
+            http_response("200 OK", &[("Content-Type", "image/png")], b"png")
+        })
+        .await;
+        let ctx = mock_context(vec![gateway]).await;
+
+        let cid = "bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344";
+        let encoded = format!("ipfs://{cid}/My%20File/Caf%C3%A9%20%F0%9F%8E%A8%23.png");
+        let result =
+            fetch_ipfs_data(ctx.clone(), &format!("ipfs://{cid}/My File/Café 🎨#.png")).await?;
+
+        assert_eq!(
+            *request_lines.lock().unwrap(),
+            vec![format!(
+                "GET /ipfs/{cid}/My%20File/Caf%C3%A9%20%F0%9F%8E%A8%23.png HTTP/1.1"
+            )]
+        );
+        let filename = result.filename.expect("Expected a filename");
+        assert!(filename.ends_with(&format!("{cid}/My File/Café 🎨#.png")));
+        let entry = entity::ipfs_object::Entity::find()
+            .filter(entity::ipfs_object::Column::RemoteUrl.eq(encoded.as_str()))
+            .one(&ctx.db)
+            .await?;
+        assert!(entry.is_some());
+
+        // The encoded form is the same entry, served from the cache
+        let cached = fetch_ipfs_data(ctx.clone(), &encoded).await?;
+        assert_eq!(cached.filename, Some(filename));
+
+        delete_caching(ctx, &encoded).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn validate_untrusted_content_type() -> Result<(), anyhow::Error> {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let gateway = mock_gateway(
+            http_response("200 OK", &[("Content-Type", "text/plain")], png),
+            0,
+        )
+        .await;
+
+        let ctx = mock_context(vec![gateway.clone()]).await;
+        let remote_url =
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/untrusted/1";
+        let result = fetch_ipfs_data(ctx.clone(), remote_url).await?;
+        assert_eq!(result.content_type, Some("image/png".to_string()));
+        delete_caching(ctx, remote_url).await?;
+
+        let ctx = mock_context_with(vec![gateway.clone()], |config| {
+            config.trusted_gateways = vec![gateway];
+        })
+        .await;
+        let remote_url =
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/trusted/1";
+        let result = fetch_ipfs_data(ctx.clone(), remote_url).await?;
+        assert_eq!(result.content_type, Some("text/plain".to_string()));
+        delete_caching(ctx, remote_url).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_json() -> Result<(), anyhow::Error> {
+        let ctx = Arc::new(AppContext::build_for_test().await);
+        let remote_url =
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/1";
+        let result = fetch_ipfs_data(ctx.clone(), remote_url).await?;
+
+        let ipfs_object = entity::ipfs_object::Entity::find()
+            .filter(entity::ipfs_object::Column::RemoteUrl.eq(remote_url))
+            .one(&ctx.db)
+            .await?
+            .expect("Can't find ipfs object");
+        assert_eq!(ipfs_object.content_type, "application/json");
+
+        let expected = Data {
+            content_type: Some("application/json".to_string()),
+            filename: Some(format!(
+                "{}/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/1",
+                ctx.config.ipfs_cache_directory
+            )),
+            uncached: false,
+            bytes: None,
+            headers: Vec::new(),
+            content_hash: None,
+            fetch_duration: result.fetch_duration,
+        };
+        assert_eq!(result, expected);
+
+        let result = fetch_ipfs_data(
+            ctx,
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/1",
+        )
+        .await?;
+        assert_eq!(result, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn normalize_collapses_slashes() {
+        assert_eq!(
+            normalize_ipfs_url(
+                "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344//metadata///1"
+            ),
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/1"
+        );
+    }
+
+    #[test]
+    fn normalize_keeps_directory_slash() {
+        assert_eq!(
+            normalize_ipfs_url(
+                "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata//"
+            ),
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/"
+        );
+        assert_eq!(
+            normalize_ipfs_url(
+                "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/"
+            ),
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/"
+        );
+    }
+
+    #[test]
+    fn normalize_keeps_file_like_directory_slash() {
+        assert_eq!(
+            normalize_ipfs_url(
+                "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/foo.json//"
+            ),
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/foo.json/"
+        );
+        assert_eq!(
+            normalize_ipfs_url(
+                "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/image.png"
+            ),
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/image.png"
+        );
+    }
+
+    #[test]
+    fn match_trusted_gateway_host() {
+        let mut config = Settings::new().expect("Can't create configuration");
+        config.trusted_gateways = vec![
+            "https://cloudflare-ipfs.com/ipfs".to_string(),
+            "http://127.0.0.1:8080".to_string(),
+        ];
+        let trusted = |url: &str| is_trusted_gateway(&config, &reqwest::Url::parse(url).unwrap());
+
+        assert!(trusted("https://cloudflare-ipfs.com/ipfs/bafy"));
+        assert!(trusted("https://cloudflare-ipfs.com:443/ipfs/bafy"));
+        assert!(trusted("http://127.0.0.1:8080/ipfs/bafy"));
+        assert!(!trusted("https://s.com/ipfs/bafy"));
+        assert!(!trusted("https://ipfs.com/ipfs/bafy"));
+        assert!(!trusted("https://cloudflare-ipfs.com.evil.net/ipfs/bafy"));
+        assert!(!trusted("http://127.0.0.1:8081/ipfs/bafy"));
+        assert!(!trusted("http://127.0.0.1/ipfs/bafy"));
+    }
+
+    #[test]
+    fn normalize_percent_encoding() {
+        let cid = "bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344";
+        let canonical = format!("ipfs://{cid}/My%20File/Caf%C3%A9%20%F0%9F%8E%A8%23%3F%25.png");
+
+        for ipfs_url in [
+            format!("ipfs://{cid}/My File/Café 🎨#?%.png"),
+            format!("ipfs://{cid}/My%20File/Caf%C3%A9 🎨%23%3F%25.png"),
+            format!("ipfs://{cid}/My%20File/Caf%c3%a9%20%f0%9f%8e%a8%23%3f%25.png"),
+            canonical.clone(),
+        ] {
+            assert_eq!(normalize_ipfs_url(&ipfs_url), canonical);
+        }
+
+        // Unencoded characters are left alone, `%` without hex digits is literal
+        assert_eq!(
+            normalize_ipfs_url(&format!("ipfs://{cid}/a-b_c~(1)+é/100%.json")),
+            format!("ipfs://{cid}/a-b_c~(1)+%C3%A9/100%25.json")
+        );
+    }
+
+    #[test]
+    fn reject_invalid_encoded_segments() {
+        let cid = "bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344";
+
+        for path in ["%2E%2E/metadata", "a%2Fb", "a%00b", "%FF.png"] {
+            assert!(
+                check_ipfs_url(&format!("ipfs://{cid}/{path}")).is_err(),
+                "{path} was accepted"
+            );
+        }
+        // Only whole segments of dots are traversal
+        assert_eq!(
+            check_ipfs_url(&format!("ipfs://{cid}/%2E%2E.png")).unwrap(),
+            format!("{cid}/...png")
+        );
+    }
+
+    #[test]
+    fn normalize_scheme_but_not_cid() {
+        assert_eq!(
+            normalize_ipfs_url("IPFS://QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG"),
+            "ipfs://QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG"
+        );
+        assert_eq!(
+            normalize_ipfs_url("https://ipfs.io/ipfs//foo"),
+            "https://ipfs.io/ipfs//foo"
+        );
+    }
+
+    #[test]
+    fn reject_path_traversal() {
+        assert!(check_ipfs_url(
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/../metadata"
+        )
+        .is_err());
+        assert!(check_ipfs_url(
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/./1"
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn skip_caching_content_type() -> Result<(), anyhow::Error> {
+        let listing = b"<html><body>Index of /ipfs/listing</body></html>";
+        let gateway = mock_gateway(
+            http_response(
+                "200 OK",
+                &[("Content-Type", "text/html; charset=utf-8")],
+                listing,
+            ),
+            0,
+        )
+        .await;
+        let ctx = mock_context_with(vec![gateway], |config| {
+            config.no_cache_content_types = vec!["text/html".to_string()];
+        })
+        .await;
+
+        let remote_url =
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/listing/";
+        let result = fetch_ipfs_data(ctx.clone(), remote_url).await?;
+        let filename = result.filename.expect("No filename");
+
+        assert!(result.uncached);
+        assert_eq!(fs::read(&filename)?, listing);
+        assert!(get_caching(ctx.clone(), remote_url).await?.is_none());
+        let ipfs_object = entity::ipfs_object::Entity::find()
+            .filter(entity::ipfs_object::Column::RemoteUrl.eq(normalize_ipfs_url(remote_url)))
+            .one(&ctx.db)
+            .await?;
+        assert!(ipfs_object.is_none());
+
+        fs::remove_file(filename)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keep_forwarded_headers() -> Result<(), anyhow::Error> {
+        let gateway = mock_gateway(
+            http_response(
+                "200 OK",
+                &[
+                    ("Content-Type", "application/json"),
+                    (
+                        "X-Ipfs-Path",
+                        "/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/headers",
+                    ),
+                    ("X-Other", "dropped"),
+                ],
+                br#"{"name":"headers"}"#,
+            ),
+            0,
+        )
+        .await;
+        let ctx = mock_context_with(vec![gateway], |config| {
+            config.forward_headers = vec!["X-Ipfs-Path".to_string()];
+        })
+        .await;
+
+        let remote_url =
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/headers";
+        let expected = vec![(
+            "x-ipfs-path".to_string(),
+            "/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/headers".to_string(),
+        )];
+
+        let result = fetch_ipfs_data(ctx.clone(), remote_url).await?;
+        assert_eq!(result.headers, expected);
+
+        let cached = get_caching(ctx.clone(), remote_url)
+            .await?
+            .expect("Not cached");
+        assert_eq!(cached.headers, expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_with_raised_limit() -> Result<(), anyhow::Error> {
+        let body = br#"{"name":"large"}"#;
+        let gateway = mock_gateway(
+            http_response("200 OK", &[("Content-Type", "application/json")], body),
+            0,
+        )
+        .await;
+        let ctx = mock_context_with(vec![gateway], |config| {
+            config.max_content_length = 1;
+        })
+        .await;
+
+        let remote_url = "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/large";
+        assert!(fetch_ipfs_data(ctx.clone(), remote_url).await.is_err());
+
+        let result = fetch_ipfs_data_with_limit(ctx.clone(), remote_url, 1024).await?;
+        let filename = result.filename.expect("No filename");
+        assert_eq!(fs::read(&filename)?, body);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resume_partial_download() -> Result<(), anyhow::Error> {
+        let remote_url =
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/resumed";
+
+        // The body is cut before Content-Length, the part received is kept
+        let truncated =
+            b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 18\r\n\r\n{\"name\":"
+                .to_vec();
+        let gateway = mock_gateway(truncated, 0).await;
+        let ctx = mock_context_with(vec![gateway], |config| {
+            config.resumable_downloads = true;
+        })
+        .await;
+
+        assert!(fetch_ipfs_data(ctx.clone(), remote_url).await.is_err());
+        let partial = partial_path(&ctx, remote_url);
+        assert_eq!(fs::read(&partial)?, br#"{"name":"#);
+
+        // Only the missing end is sent
+        let gateway = mock_gateway(
+            http_response(
+                "206 Partial Content",
+                &[
+                    ("Content-Type", "application/json"),
+                    ("Content-Range", "bytes 8-17/18"),
+                ],
+                br#""resumed"}"#,
+            ),
+            0,
+        )
+        .await;
+        ctx.runtime.store(Arc::new(RuntimeSettings {
+            ipfs_gateways: vec![gateway],
+            ..(**ctx.runtime.load()).clone()
+        }));
+
+        let result = fetch_ipfs_data(ctx.clone(), remote_url).await?;
+        let filename = result.filename.expect("No filename");
+        assert_eq!(fs::read(&filename)?, br#"{"name":"resumed"}"#);
+        assert!(fs::metadata(&partial).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skip_mismatched_content_type() -> Result<(), anyhow::Error> {
+        let image = b"not really a png";
+        let ctx = mock_context_with(
+            vec![
+                mock_gateway(
+                    http_response(
+                        "200 OK",
+                        &[("Content-Type", "text/html")],
+                        b"<html>Not found</html>",
+                    ),
+                    0,
+                )
+                .await,
+                mock_gateway(
+                    http_response("200 OK", &[("Content-Type", "image/png")], image),
+                    500,
+                )
+                .await,
+            ],
+            |config| {
+                config.strict_content_type_extensions = vec!["png".to_string()];
+            },
+        )
+        .await;
+
+        let remote_url =
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/mismatch/image.png";
+        let result = fetch_ipfs_data(ctx.clone(), remote_url).await?;
+
+        assert_eq!(result.content_type.as_deref(), Some("image/png"));
+        assert_eq!(fs::read(result.filename.expect("No filename"))?, image);
+
+        let config = &ctx.config;
+        assert!(check_extension_content_type(config, remote_url, Some("image/png")).is_ok());
+        assert!(check_extension_content_type(config, remote_url, None).is_ok());
+        assert!(check_extension_content_type(config, "ipfs://cid/page", Some("text/html")).is_ok());
+        assert!(
+            check_extension_content_type(config, "ipfs://cid/a.PNG", Some("text/html")).is_err()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_alternate_cid() -> Result<(), anyhow::Error> {
+        let gateway = mock_gateway(
+            http_response(
+                "200 OK",
+                &[("Content-Type", "application/json")],
+                br#"{"name":"alternate"}"#,
+            ),
+            0,
+        )
+        .await;
+        // The first CID is rejected, so the alternate one is fetched
+        let ctx = mock_context_with(vec![gateway], |config| {
+            config.allowed_cid_prefixes =
+                vec!["bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi".to_string()];
+        })
+        .await;
+
+        let cids = vec![
+            "bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/alternate".to_string(),
+            "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/alternate".to_string(),
+        ];
+        let data = fetch_first_available(ctx.clone(), &cids).await?;
+        let filename = data.filename.expect("No filename");
+
+        assert_eq!(fs::read_to_string(&filename)?, r#"{"name":"alternate"}"#);
+        delete_caching(ctx, &format!("ipfs://{}", cids[1])).await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn summarize_outcomes() {
+        let outcomes = ["404", "timeout", "404", "429", "404", "timeout"]
+            .iter()
+            .map(|outcome| outcome.to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(outcome_summary(&outcomes), "3x404, 2x timeout, 1x429");
+        assert_eq!(outcome_summary(&[]), "no gateway available");
+    }
+
+    #[test]
+    fn retry_after() {
+        let now = Utc::now();
+
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(now + Duration::seconds(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now),
+            Some(Utc.with_ymd_and_hms(2015, 10, 21, 7, 28, 0).unwrap())
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+
+        // Gateways can't block themselves for ever nor make the addition overflow
+        let latest = Some(now + Duration::seconds(MAX_GATEWAY_BLOCK_SECONDS));
+        assert_eq!(parse_retry_after("9999999999999", now), latest);
+        assert_eq!(parse_retry_after("99999999999999999999999", now), latest);
+        assert_eq!(parse_retry_after(&i64::MAX.to_string(), now), latest);
+        assert_eq!(
+            parse_retry_after("Fri, 31 Dec 9999 23:59:59 GMT", now),
+            latest
+        );
+        assert_eq!(parse_retry_after("-120", now), None);
+        assert_eq!(parse_retry_after(&i64::MIN.to_string(), now), None);
+    }
+
+    #[tokio::test]
+    async fn block_gateway_on_huge_retry_after() -> Result<(), anyhow::Error> {
+        let remote_url = "ipfs://bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku/retry";
+
+        for retry_after in ["99999999999999999", "-5"] {
+            let gateway = mock_gateway(
+                http_response(
+                    "429 Too Many Requests",
+                    &[("Retry-After", retry_after)],
+                    b"",
+                ),
+                0,
+            )
+            .await;
+            let ctx = mock_context(vec![gateway.clone()]).await;
+
+            // Twice, a panic would be left in the shared fetch for the next request
+            for _ in 0..2 {
+                assert!(fetch_ipfs_data(ctx.clone(), remote_url).await.is_err());
+            }
+            let unblock_at = BLOCKED_GATEWAYS
+                .lock()
+                .await
+                .get(&gateway)
+                .map(|unblock_at| *unblock_at);
+            let latest = Utc::now() + Duration::seconds(MAX_GATEWAY_BLOCK_SECONDS);
+            assert!(unblock_at.is_some_and(|unblock_at| unblock_at <= latest));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn allowed_cid_prefixes() {
+        let mut settings = Settings::new().expect("Can't create configuration");
+        let allowed =
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/1";
+        let rejected = "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/1";
+
+        assert!(check_allowed_cid(&settings, rejected).is_ok());
+
+        settings.allowed_cid_prefixes =
+            vec!["bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344".to_string()];
+
+        assert!(check_allowed_cid(&settings, allowed).is_ok());
+        let error = check_allowed_cid(&settings, rejected).expect_err("Expected error");
+        assert!(error.downcast_ref::<CidNotAllowed>().is_some());
+    }
+
+    #[test]
+    fn max_path_segments() {
+        let mut settings = Settings::new().expect("Can't create configuration");
+        settings.max_path_segments = 2;
+        let cid = "bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344";
+
+        assert!(check_path_segments(&settings, cid).is_ok());
+        assert!(check_path_segments(&settings, &format!("{cid}/metadata/1")).is_ok());
+        assert!(check_path_segments(&settings, &format!("{cid}/metadata/1/")).is_ok());
+        assert!(check_path_segments(&settings, &format!("{cid}/a/b/c")).is_err());
+    }
+
+    #[test]
+    fn convert_cid_v0() -> Result<(), anyhow::Error> {
+        assert_eq!(
+            convert_cid_v0_to_v1(
+                "ipfs://QmbWqxBEKC3P8tqsKc98xmWNzrzDtRLMiMPL8wBuTGsMnR/metadata/1"
+            )?,
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/metadata/1"
+        );
+        assert_eq!(
+            convert_cid_v0_to_v1(
+                "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi"
+            )?,
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn gateway_path_template() {
+        let mut config = Settings::new().expect("Can't create configuration");
+        config.gateway_settings = vec![GatewaySettings {
+            url: "http://127.0.0.1:5001".to_string(),
+            ca_cert_path: None,
+            danger_accept_invalid_certs: false,
+            path_template: Some("/api/v0/cat?arg={cid}{path}".to_string()),
+            http_version: None,
+            method: None,
+        }];
+
+        assert_eq!(
+            gateway_url(&config, "https://ipfs.io/ipfs", "bafy/metadata/1"),
+            "https://ipfs.io/ipfs/bafy/metadata/1"
+        );
+        assert_eq!(
+            gateway_url(&config, "http://127.0.0.1:5001", "bafy/metadata/1"),
+            "http://127.0.0.1:5001/api/v0/cat?arg=bafy/metadata/1"
+        );
+        assert_eq!(
+            gateway_url(&config, "http://127.0.0.1:5001", "bafy"),
+            "http://127.0.0.1:5001/api/v0/cat?arg=bafy"
+        );
+        assert_eq!(
+            gateway_url(
+                &config,
+                "http://127.0.0.1:5001",
+                "bafy/a&b=c+d/caf%C3%A9%20x"
+            ),
+            "http://127.0.0.1:5001/api/v0/cat?arg=bafy/a%26b%3Dc%2Bd/caf%C3%A9%20x"
+        );
+    }
+
+    #[tokio::test]
+    async fn post_to_rpc_gateway() -> Result<(), anyhow::Error> {
+        // Like the Kubo RPC API, only answer POST requests
+        let gateway = mock_gateway_with(0, |request| {
+            if request.starts_with(b"POST /api/v0/cat?arg=") {
+                http_response("200 OK", &[("Content-Type", "text/plain")], b"from rpc")
+            } else {
+                http_response("405 Method Not Allowed", &[], b"")
+            }
+        })
+        .await;
+        let gateway = gateway.trim_end_matches("/ipfs").to_string();
+        let remote_url = "ipfs://bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku/rpc";
+        let rpc_settings = |method| GatewaySettings {
+            url: gateway.clone(),
+            ca_cert_path: None,
+            danger_accept_invalid_certs: false,
+            path_template: Some("/api/v0/cat?arg={cid}{path}".to_string()),
+            http_version: None,
+            method,
+        };
+
+        let ctx = mock_context_with(vec![gateway.clone()], |config| {
+            config.gateway_settings = vec![rpc_settings(Some(GatewayMethod::Get))];
+        })
+        .await;
+        assert!(fetch_ipfs_data(ctx, remote_url).await.is_err());
+
+        let ctx = mock_context_with(vec![gateway.clone()], |config| {
+            config.gateway_settings = vec![rpc_settings(None)];
+        })
+        .await;
+        let result = fetch_ipfs_data(ctx.clone(), remote_url).await?;
+        let filename = result.filename.expect("Expected a filename");
+        assert_eq!(fs::read(&filename)?, b"from rpc");
+        assert!(check_gateway(&ctx, &gateway).await.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn canonical_cid_form() -> Result<(), anyhow::Error> {
+        let canonical = "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+
+        assert_eq!(canonical_cid(canonical)?, canonical);
+        assert_eq!(
+            canonical_cid("QmbWqxBEKC3P8tqsKc98xmWNzrzDtRLMiMPL8wBuTGsMnR")?,
+            canonical
+        );
+        assert_eq!(canonical_cid(&canonical.to_uppercase())?, canonical);
+        assert!(canonical_cid("not-a-cid").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn match_raw_cid_content() -> Result<(), anyhow::Error> {
+        let content = b"raw block";
+        let multihash = cid::multihash::Multihash::wrap(SHA2_256, &Sha256::digest(content))?;
+        let cid = Cid::new_v1(RAW_CODEC, multihash).to_string();
+
+        assert_eq!(content_matches_cid(&cid, content), Some(true));
+        assert_eq!(content_matches_cid(&cid, b"corrupted"), Some(false));
+        // dag-pb CIDs hash the UnixFS DAG, they can't be checked against the file
+        assert_eq!(
+            content_matches_cid(
+                "bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344",
+                content
+            ),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_large_file() {
+        let mut ctx = AppContext::build_for_test().await;
+        ctx.config.max_content_length = 1;
+        let ctx = Arc::new(ctx);
+
+        let remote_url =
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/metadata/1";
+
+        let result = fetch_ipfs_data(ctx.clone(), remote_url).await;
+
+        assert_eq!(
+            result.expect_err("Expected error").to_string(),
+            "File is 1023 bytes, maximum allowed is 1"
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_from_fallback_proxy() -> Result<(), anyhow::Error> {
+        let gateway = mock_gateway(http_response("404 Not Found", &[], b"not found"), 0).await;
+        let body = br#"{"name":"fallback"}"#;
+        let fallback = mock_gateway(
+            http_response("200 OK", &[("Content-Type", "application/json")], body),
+            0,
+        )
+        .await;
+        let remote_url =
+            "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/fallback";
+
+        // Without the fallback stage the gateway 404 is final
+        let ctx = mock_context_with(vec![gateway.clone()], |config| {
+            config.fetch_sources = vec![FetchSource::Gateways];
+            config.fallback_proxy_urls = vec![fallback.clone()];
+        })
+        .await;
+        assert!(fetch_ipfs_data(ctx, remote_url).await.is_err());
+
+        let ctx = mock_context_with(vec![gateway], |config| {
+            config.fetch_sources = vec![FetchSource::Gateways, FetchSource::FallbackProxies];
+            config.fallback_proxy_urls = vec![fallback];
+        })
+        .await;
+        let result = fetch_ipfs_data(ctx.clone(), remote_url).await?;
+        assert_eq!(fs::read(result.filename.expect("No filename"))?, body);
+        assert!(get_caching(ctx, remote_url).await?.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_empty_file() -> Result<(), anyhow::Error> {
+        let gateway = mock_gateway(
+            http_response("200 OK", &[("Content-Type", "text/plain")], b""),
+            0,
+        )
+        .await;
+        let ctx = mock_context(vec![gateway]).await;
+
+        // The raw CID of zero bytes
+        let remote_url = "ipfs://bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku";
+        let result = fetch_ipfs_data(ctx.clone(), remote_url).await?;
+        let filename = result.filename.expect("No filename");
+        assert_eq!(fs::read(&filename)?, b"");
+
+        let cached = get_caching(ctx, remote_url)
+            .await?
+            .expect("Empty file isn't cached");
+        assert_eq!(cached.filename, Some(filename));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hedge_slow_gateways() -> Result<(), anyhow::Error> {
+        let response =
+            |body: &[u8]| http_response("200 OK", &[("Content-Type", "text/plain")], body);
+        let slow = mock_gateway(response(b"slow gateway"), 300).await;
+        let fast = mock_gateway(response(b"fast gateway"), 0).await;
+        let not_found = mock_gateway(http_response("404 Not Found", &[], b"not found"), 0).await;
+
+        // The first gateway answers before the delay, the second is never queried
+        let ctx = mock_context_with(vec![slow.clone(), fast.clone()], |config| {
+            config.hedge_delay_ms = Some(2000);
+        })
+        .await;
+        let result = fetch_ipfs_data(
+            ctx,
+            "ipfs://bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku/first",
+        )
+        .await?;
+        assert_eq!(fs::read(result.filename.unwrap())?, b"slow gateway");
+
+        // Past the delay the next gateway is queried too
+        let ctx = mock_context_with(vec![slow, fast.clone()], |config| {
+            config.hedge_delay_ms = Some(50);
+        })
+        .await;
+        let result = fetch_ipfs_data(
+            ctx,
+            "ipfs://bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku/hedged",
+        )
+        .await?;
+        assert_eq!(fs::read(result.filename.unwrap())?, b"fast gateway");
+
+        // A failure doesn't wait for the delay
+        let ctx = mock_context_with(vec![not_found, fast], |config| {
+            config.hedge_delay_ms = Some(60_000);
+        })
+        .await;
+        let result = fetch_ipfs_data(
+            ctx,
+            "ipfs://bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku/failed",
+        )
+        .await?;
+        assert_eq!(fs::read(result.filename.unwrap())?, b"fast gateway");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serve_stale_on_error() -> Result<(), anyhow::Error> {
+        let failing = mock_gateway(http_response("502 Bad Gateway", &[], b"bad gateway"), 0).await;
+        let not_found = mock_gateway(http_response("404 Not Found", &[], b"not found"), 0).await;
+        let remote_url = "ipfs://bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/stale";
+
+        // Only gateways failing to answer get the stale file served, a 404 is an answer
+        for (gateway, serve_stale, served) in [
+            (&failing, false, false),
+            (&failing, true, true),
+            (&not_found, true, false),
+        ] {
+            let ctx = mock_context_with(vec![gateway.clone()], |config| {
+                config.cache_max_age_seconds = Some(3600);
+                config.serve_stale_on_error = serve_stale;
+            })
+            .await;
+
+            let filename = caching_path(&ctx, remote_url, None, true).await?;
+            fs::write(&filename, br#"{"name":"stale"}"#)?;
+            update_entry(&ctx.db, remote_url, "application/json", 16).await?;
+            entity::ipfs_object::Entity::update_many()
+                .col_expr(
+                    entity::ipfs_object::Column::CachedAt,
+                    Expr::value(Utc::now().naive_utc() - Duration::days(1)),
+                )
+                .exec(&ctx.db)
+                .await?;
+
+            let result = fetch_ipfs_data(ctx, remote_url).await;
+            if !served {
+                assert!(result.is_err());
+                continue;
+            }
+
+            let result = result?;
+            assert_eq!(result.filename, Some(filename));
+            assert!(result.headers.contains(&(
+                "Warning".to_string(),
+                "110 - \"Response is Stale\"".to_string()
+            )));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limit_connections_per_gateway() -> Result<(), anyhow::Error> {
+        let gateway = mock_gateway(
+            http_response(
+                "200 OK",
+                &[("Content-Type", "text/plain")],
+                b"one at a time",
+            ),
+            300,
+        )
+        .await;
+        let ctx = mock_context_with(vec![gateway], |config| {
+            config.max_connections_per_gateway = 1;
+        })
+        .await;
+
+        let now = Instant::now();
+        let (first, second) = tokio::join!(
+            fetch_ipfs_data(
+                ctx.clone(),
+                "ipfs://bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku/1"
+            ),
+            fetch_ipfs_data(
+                ctx.clone(),
+                "ipfs://bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku/2"
+            ),
+        );
+        first?;
+        second?;
+        assert!(now.elapsed() >= std::time::Duration::from_millis(600));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limit_streamed_connections_per_gateway() -> Result<(), anyhow::Error> {
+        let gateway = mock_gateway(
+            http_response("200 OK", &[("Content-Type", "text/plain")], b"streamed"),
+            0,
+        )
+        .await;
+        let ctx = mock_context_with(vec![gateway], |config| {
+            config.max_connections_per_gateway = 1;
+        })
+        .await;
+
+        let streamed = stream_ipfs_data(
+            ctx.clone(),
+            "ipfs://bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku/streamed/1",
+        )
+        .await?;
+        // The connection is busy until the first body is read
+        let second = stream_ipfs_data(
+            ctx.clone(),
+            "ipfs://bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku/streamed/2",
+        );
+        tokio::pin!(second);
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(200), &mut second)
+                .await
+                .is_err()
+        );
+
+        drop(streamed);
+        second.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gateway_http_version() -> Result<(), anyhow::Error> {
+        // The mock gateway only speaks HTTP/1.1
+        let gateway = mock_gateway(
+            http_response(
+                "200 OK",
+                &[("Content-Type", "text/plain")],
+                b"over http/1.1",
+            ),
+            0,
+        )
+        .await;
+        let remote_url = "ipfs://bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku/h1";
+
+        let ctx = mock_context_with(vec![gateway.clone()], |config| {
+            config.gateway_http_version = HttpVersion::Http2;
+        })
+        .await;
+        assert!(fetch_ipfs_data(ctx, remote_url).await.is_err());
+
+        let ctx = mock_context_with(vec![gateway.clone()], |config| {
+            config.gateway_http_version = HttpVersion::Http2;
+            config.gateway_settings = vec![GatewaySettings {
+                url: gateway,
+                ca_cert_path: None,
+                danger_accept_invalid_certs: false,
+                path_template: None,
+                http_version: Some(HttpVersion::Http1),
+                method: None,
+            }];
+        })
+        .await;
+        fetch_ipfs_data(ctx, remote_url).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_gateway_status() {
+        let working = mock_gateway(http_response("200 OK", &[], b""), 0).await;
+        let failing = mock_gateway(http_response("502 Bad Gateway", &[], b"bad gateway"), 0).await;
+        let ctx = mock_context(vec![]).await;
+
+        assert!(check_gateway(&ctx, &working).await.is_ok());
+        assert!(check_gateway(&ctx, &failing).await.is_err());
+        assert!(check_gateway(&ctx, "http://127.0.0.1:1/ipfs")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn cache_directory_listing_once() -> Result<(), anyhow::Error> {
+        let listing = mock_gateway(
+            http_response(
+                "200 OK",
+                &[("Content-Type", "text/html; charset=utf-8")],
+                b"<html><body>Index of /ipfs/bafy</body></html>",
+            ),
+            0,
+        )
+        .await;
+        let directory = "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+
+        for (fetched, other) in [
+            (directory.to_string(), format!("{directory}/")),
+            (format!("{directory}/"), directory.to_string()),
+        ] {
+            let ctx = mock_context(vec![listing.clone()]).await;
+            let result = fetch_ipfs_data(ctx.clone(), &fetched).await?;
+            let filename = result.filename.expect("No filename");
+            assert!(filename.ends_with("/index.html"));
+
+            for url in [&fetched, &other] {
+                let cached = get_caching(ctx.clone(), url)
+                    .await?
+                    .expect("Listing isn't cached");
+                assert_eq!(cached.filename.as_ref(), Some(&filename));
+                assert_eq!(
+                    cached.content_type.as_deref(),
+                    Some("text/html; charset=utf-8")
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn match_response_cid() {
+        let base_uri = "QmbWqxBEKC3P8tqsKc98xmWNzrzDtRLMiMPL8wBuTGsMnR/1.json";
+        let url = |url: &str| reqwest::Url::parse(url).unwrap();
+
+        assert!(response_references_cid(
+            &url("https://ipfs.io/ipfs/QmbWqxBEKC3P8tqsKc98xmWNzrzDtRLMiMPL8wBuTGsMnR/1.json"),
+            base_uri
+        ));
+        assert!(response_references_cid(
+            &url("https://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi.ipfs.dweb.link/1.json"),
+            base_uri
+        ));
+        assert!(!response_references_cid(
+            &url("https://ipfs.io/ipfs/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/1.json"),
+            base_uri
+        ));
+    }
+
+    #[tokio::test]
+    async fn skip_response_for_another_cid() -> Result<(), anyhow::Error> {
+        let other = mock_gateway(
+            http_response(
+                "200 OK",
+                &[("Content-Type", "text/plain")],
+                b"another object",
+            ),
+            0,
+        )
+        .await;
+        let redirect = mock_gateway(
+            http_response(
+                "302 Found",
+                &[(
+                    "Location",
+                    &format!(
+                        "{other}/bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/1.txt"
+                    ),
+                )],
+                b"",
+            ),
+            0,
+        )
+        .await;
+        let right = mock_gateway(
+            http_response(
+                "200 OK",
+                &[("Content-Type", "text/plain")],
+                b"requested object",
+            ),
+            50,
+        )
+        .await;
+        let ctx = mock_context_with(vec![redirect, right], |config| {
+            config.check_response_cid = true;
+        })
+        .await;
+
+        let result = fetch_ipfs_data(
+            ctx,
+            "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi/1.txt",
+        )
+        .await?;
+        assert_eq!(fs::read(result.filename.unwrap())?, b"requested object");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exhaust_fetch_attempts() -> Result<(), anyhow::Error> {
+        let not_found = mock_gateway(http_response("404 Not Found", &[], b"not found"), 0).await;
+        let found = mock_gateway(
+            http_response(
+                "200 OK",
+                &[("Content-Type", "text/plain")],
+                b"second attempt",
+            ),
+            0,
+        )
+        .await;
+        let remote_url =
+            "ipfs://bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku/budget";
+
+        // Within a source and across sources
+        for fallback in [false, true] {
+            let (gateways, fallback_proxy_urls) = if fallback {
+                (vec![not_found.clone()], vec![found.clone()])
+            } else {
+                (vec![not_found.clone(), found.clone()], vec![])
+            };
+
+            for (max_fetch_attempts, fetched) in [(1, false), (2, true)] {
+                let ctx = mock_context_with(gateways.clone(), |config| {
+                    config.max_fetch_attempts = max_fetch_attempts;
+                    config.fallback_proxy_urls = fallback_proxy_urls.clone();
+                })
+                .await;
+
+                let result = fetch_ipfs_data(ctx, remote_url).await;
+                if fetched {
+                    result?;
+                } else {
+                    let error = result.expect_err("Expected error");
+                    assert!(error.downcast_ref::<FetchBudgetExhausted>().is_some());
+                }
+            }
+        }
+
+        Ok(())
     }
 }
@@ -1,8 +1,20 @@
+pub mod access_times;
 pub mod actix_server;
 pub mod app_context;
 pub mod caching;
+pub mod client_limits;
 pub mod config;
+pub mod gateway_stats;
 pub mod ipfs_client;
+pub mod mem_cache;
+pub mod metrics;
+pub mod openapi;
+pub mod signing;
+pub mod size_token;
 pub mod telemetry;
+#[cfg(test)]
+mod test_support;
+pub mod thumbnails;
+pub mod url_signature;
 
 pub use app_context::AppContext;
@@ -0,0 +1,136 @@
+use bytes::Bytes;
+use chrono::{NaiveDateTime, Utc};
+use hashlink::LruCache;
+use std::sync::Mutex;
+
+#[derive(Clone)]
+pub struct MemEntry {
+    pub content_type: Option<String>,
+    pub filename: String,
+    pub bytes: Bytes,
+    pub headers: Vec<(String, String)>,
+    /// From the max age of its content type, the entry is a miss afterwards
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+struct Entries {
+    lru: LruCache<String, MemEntry>,
+    bytes: usize,
+}
+
+/// Bounded LRU of small cached files, keyed by normalized IPFS url, so hot files
+/// like metadata JSON are served without touching the disk
+pub struct MemCache {
+    entries: Mutex<Entries>,
+    max_bytes: usize,
+    max_entry_bytes: usize,
+}
+
+impl MemCache {
+    pub fn new(max_bytes: usize, max_entry_bytes: usize) -> Self {
+        MemCache {
+            entries: Mutex::new(Entries {
+                lru: LruCache::new_unbounded(),
+                bytes: 0,
+            }),
+            max_bytes,
+            max_entry_bytes,
+        }
+    }
+
+    /// Whether a file of `size` bytes would be kept, always false when disabled
+    pub fn accepts(&self, size: usize) -> bool {
+        size <= self.max_entry_bytes && size <= self.max_bytes
+    }
+
+    pub fn get(&self, ipfs_url: &str) -> Option<MemEntry> {
+        let mut entries = self.entries.lock().expect("Memory cache lock poisoned");
+
+        let expired = entries
+            .lru
+            .get(ipfs_url)
+            .map(|entry| matches!(entry.expires_at, Some(expires_at) if expires_at < Utc::now().naive_utc()))?;
+
+        if expired {
+            if let Some(entry) = entries.lru.remove(ipfs_url) {
+                entries.bytes -= entry.bytes.len();
+            }
+            return None;
+        }
+
+        entries.lru.get(ipfs_url).cloned()
+    }
+
+    pub fn insert(&self, ipfs_url: &str, entry: MemEntry) {
+        if !self.accepts(entry.bytes.len()) {
+            return;
+        }
+
+        let mut entries = self.entries.lock().expect("Memory cache lock poisoned");
+
+        entries.bytes += entry.bytes.len();
+        if let Some(previous) = entries.lru.insert(ipfs_url.to_string(), entry) {
+            entries.bytes -= previous.bytes.len();
+        }
+
+        while entries.bytes > self.max_bytes {
+            match entries.lru.remove_lru() {
+                Some((_, evicted)) => entries.bytes -= evicted.bytes.len(),
+                None => break,
+            }
+        }
+    }
+
+    pub fn remove(&self, ipfs_url: &str) {
+        let mut entries = self.entries.lock().expect("Memory cache lock poisoned");
+
+        if let Some(entry) = entries.lru.remove(ipfs_url) {
+            entries.bytes -= entry.bytes.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(bytes: &'static [u8]) -> MemEntry {
+        MemEntry {
+            content_type: Some("application/json".to_string()),
+            filename: "tmp/ipfs/entry".to_string(),
+            bytes: Bytes::from_static(bytes),
+            headers: Vec::new(),
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let mem_cache = MemCache::new(8, 4);
+
+        mem_cache.insert("ipfs://a", entry(b"aaaa"));
+        mem_cache.insert("ipfs://b", entry(b"bbbb"));
+        assert!(mem_cache.get("ipfs://a").is_some());
+
+        mem_cache.insert("ipfs://c", entry(b"cccc"));
+        assert!(mem_cache.get("ipfs://a").is_some());
+        assert!(mem_cache.get("ipfs://b").is_none());
+        assert!(mem_cache.get("ipfs://c").is_some());
+
+        mem_cache.insert("ipfs://large", entry(b"large"));
+        assert!(mem_cache.get("ipfs://large").is_none());
+
+        mem_cache.remove("ipfs://a");
+        assert!(mem_cache.get("ipfs://a").is_none());
+    }
+
+    #[test]
+    fn expire_entries() {
+        let mem_cache = MemCache::new(8, 4);
+        let mut expired = entry(b"old");
+        expired.expires_at = Some(Utc::now().naive_utc() - chrono::Duration::seconds(1));
+
+        mem_cache.insert("ipfs://old", expired);
+        assert!(mem_cache.get("ipfs://old").is_none());
+    }
+}
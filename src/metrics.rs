@@ -0,0 +1,29 @@
+use lazy_static::lazy_static;
+use opentelemetry::{global, metrics::Counter, Context};
+
+lazy_static! {
+    static ref LARGE_FILES: Counter<u64> = global::meter("ipfs-proxy")
+        .u64_counter("large_files_total")
+        .with_description("Fetched files above warn_content_length")
+        .init();
+    static ref FETCH_COALESCED: Counter<u64> = global::meter("ipfs-proxy")
+        .u64_counter("fetch_coalesced_total")
+        .with_description("Fetches which joined an in-flight fetch of the same url")
+        .init();
+    static ref FETCH_LEADER: Counter<u64> = global::meter("ipfs-proxy")
+        .u64_counter("fetch_leader_total")
+        .with_description("Fetches which initiated a gateway fetch")
+        .init();
+}
+
+pub fn record_large_file() {
+    LARGE_FILES.add(&Context::current(), 1, &[]);
+}
+
+pub fn record_fetch_coalesced() {
+    FETCH_COALESCED.add(&Context::current(), 1, &[]);
+}
+
+pub fn record_fetch_leader() {
+    FETCH_LEADER.add(&Context::current(), 1, &[]);
+}
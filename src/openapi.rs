@@ -0,0 +1,236 @@
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+
+use crate::config::Settings;
+use crate::thumbnails::MAX_DPR;
+
+/// OpenAPI 3 description of the routes served by `actix_server`, kept by hand so update
+/// it along with the routes. Resize parameters list the `permitted_resize_dimensions`.
+pub fn openapi_document(config: &Settings) -> Value {
+    let widths = config
+        .permitted_resize_dimensions
+        .iter()
+        .map(|dimension| dimension.width)
+        .collect::<BTreeSet<_>>();
+    let heights = config
+        .permitted_resize_dimensions
+        .iter()
+        .map(|dimension| dimension.height)
+        .collect::<BTreeSet<_>>();
+    let dimensions = config
+        .permitted_resize_dimensions
+        .iter()
+        .map(|dimension| format!("{}x{}", dimension.width, dimension.height))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let admin = json!([{ "adminToken": [] }]);
+    let file_responses = json!({
+        "200": { "description": "The file, with the content type of the cached file" },
+        "206": { "description": "The requested ranges of the file" },
+        "400": { "description": "Invalid url or query parameters" },
+        "403": { "description": "Blocked CID, or missing or invalid signature" },
+        "404": { "description": "Every gateway answered 404" },
+        "413": { "description": "Larger than max_content_length, or the size_token limit" },
+        "502": { "description": "No gateway could serve the file" },
+        "507": { "description": "The cache is out of disk space" }
+    });
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "ipfs-proxy",
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "components": {
+            "securitySchemes": {
+                "adminToken": { "type": "http", "scheme": "bearer" }
+            }
+        },
+        "paths": {
+            "/ipfs/{ipfs_file}": {
+                "get": {
+                    "summary": "Fetch an IPFS file through the cache, resized on request",
+                    "parameters": [
+                        {
+                            "name": "ipfs_file", "in": "path", "required": true,
+                            "description": "CID followed by an optional path",
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "img-width", "in": "query",
+                            "description": format!("Resize width, along with img-height, one of {dimensions}"),
+                            "schema": { "type": "integer", "enum": widths }
+                        },
+                        {
+                            "name": "img-height", "in": "query",
+                            "description": "Resize height, along with img-width",
+                            "schema": { "type": "integer", "enum": heights }
+                        },
+                        {
+                            "name": "img-format", "in": "query",
+                            "schema": { "type": "string", "enum": ["png", "jpeg"], "default": "png" }
+                        },
+                        {
+                            "name": "img-auto", "in": "query",
+                            "description": "Without img-format, the resize output is JPEG or PNG by the Accept header and resize_auto_policy, varying on Accept",
+                            "schema": { "type": "string", "enum": ["1", "true"] }
+                        },
+                        {
+                            "name": "dpr", "in": "query",
+                            "description": "Device pixel ratio multiplying the resize dimensions",
+                            "schema": { "type": "number", "minimum": 1, "maximum": MAX_DPR }
+                        },
+                        {
+                            "name": "path", "in": "query",
+                            "description": "Subpath appended to the CID",
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "size_token", "in": "query",
+                            "description": "Signed size limit above max_content_length for this CID",
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "content-type", "in": "query",
+                            "description": "Content type served instead of the cached one, only for admins",
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "signature", "in": "query",
+                            "required": config.require_signed_urls,
+                            "description": "`<expires_at>.<hex hmac>` of the path and resize parameters",
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "responses": file_responses
+                },
+                "options": {
+                    "summary": "Allowed methods in the Allow header, and this path item",
+                    "responses": { "200": { "description": "The methods and query parameters of this route" } }
+                }
+            },
+            "/asset/{key}": {
+                "get": {
+                    "summary": "Serve the first CID of an asset which can be fetched",
+                    "parameters": [
+                        { "name": "key", "in": "path", "required": true, "schema": { "type": "string" } }
+                    ],
+                    "responses": file_responses
+                },
+                "put": {
+                    "summary": "Set the CIDs of an asset, tried in order",
+                    "security": admin,
+                    "parameters": [
+                        { "name": "key", "in": "path", "required": true, "schema": { "type": "string" } }
+                    ],
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": {
+                                    "type": "object",
+                                    "required": ["cids"],
+                                    "properties": {
+                                        "cids": { "type": "array", "items": { "type": "string" } }
+                                    }
+                                }
+                            }
+                        }
+                    },
+                    "responses": {
+                        "200": { "description": "The recorded CIDs" },
+                        "400": { "description": "Empty or invalid CIDs" },
+                        "403": { "description": "Admin token required" }
+                    }
+                }
+            },
+            "/health": {
+                "get": {
+                    "summary": "Whether the cache directory is writable and has free space",
+                    "responses": {
+                        "200": { "description": "Healthy, with the disk usage" },
+                        "503": { "description": "Unhealthy, with the disk usage" }
+                    }
+                }
+            },
+            "/config": {
+                "put": {
+                    "summary": "Update the runtime settings",
+                    "security": admin,
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": {
+                                    "type": "object",
+                                    "properties": {
+                                        "ipfs_gateways": { "type": "array", "items": { "type": "string" } },
+                                        "connect_timeout": { "type": "integer" },
+                                        "max_redirects": { "type": "integer" },
+                                        "pause_gateway_seconds": { "type": "integer" },
+                                        "max_concurrent_fetches": { "type": "integer" }
+                                    }
+                                }
+                            }
+                        }
+                    },
+                    "responses": {
+                        "200": { "description": "The runtime settings now in use" },
+                        "400": { "description": "Invalid settings, e.g. empty ipfs_gateways" },
+                        "403": { "description": "Admin token required" }
+                    }
+                }
+            },
+            "/gateways": {
+                "get": {
+                    "summary": "Status of each gateway",
+                    "security": admin,
+                    "responses": {
+                        "200": { "description": "Gateway statuses" },
+                        "403": { "description": "Admin token required" }
+                    }
+                }
+            },
+            "/metrics": {
+                "get": {
+                    "summary": "Prometheus metrics",
+                    "responses": { "200": { "description": "Metrics in the Prometheus text format" } }
+                }
+            },
+            "/openapi.json": {
+                "get": {
+                    "summary": "This document",
+                    "responses": { "200": { "description": "OpenAPI 3 document" } }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Dimension;
+
+    #[test]
+    fn list_permitted_dimensions() {
+        let mut config = Settings::new().expect("Can't create configuration");
+        config.permitted_resize_dimensions = vec![
+            Dimension {
+                width: 200,
+                height: 100,
+            },
+            Dimension {
+                width: 100,
+                height: 100,
+            },
+        ];
+
+        let document = openapi_document(&config);
+        let parameters = &document["paths"]["/ipfs/{ipfs_file}"]["get"]["parameters"];
+        assert_eq!(parameters[1]["name"], "img-width");
+        assert_eq!(parameters[1]["schema"]["enum"], json!([100, 200]));
+        assert_eq!(parameters[2]["schema"]["enum"], json!([100]));
+    }
+}
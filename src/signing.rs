@@ -0,0 +1,42 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(secret: &str, message: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(message.as_bytes());
+    mac
+}
+
+/// `<expires_at>.<hex hmac>` of `message`, which should include `expires_at`
+pub fn sign(secret: &str, message: &str, expires_at: i64) -> String {
+    let signature = mac(secret, message).finalize().into_bytes();
+
+    format!("{expires_at}.{}", hex::encode(signature))
+}
+
+/// Expiry of a `<expires_at>.<hex hmac>` signature, `None` when malformed
+pub fn expires_at(signed: &str) -> Option<i64> {
+    signed.split_once('.')?.0.parse().ok()
+}
+
+/// Whether `signed` is an unexpired signature made by `sign` of the message built for
+/// its expiry
+pub fn verify(secret: &str, signed: &str, message: impl FnOnce(i64) -> String) -> bool {
+    let Some((expires_at, signature_hex)) = signed.split_once('.') else {
+        return false;
+    };
+    let (Ok(expires_at), Ok(signature_bytes)) =
+        (expires_at.parse::<i64>(), hex::decode(signature_hex))
+    else {
+        return false;
+    };
+
+    expires_at >= Utc::now().timestamp()
+        && mac(secret, &message(expires_at))
+            .verify_slice(&signature_bytes)
+            .is_ok()
+}
@@ -0,0 +1,91 @@
+use crate::config::Settings;
+use crate::signing;
+
+fn message(cid: &str, max_content_length: u64, expires_at: i64) -> String {
+    format!("{cid}:{max_content_length}:{expires_at}")
+}
+
+/// Token allowing `cid` to be fetched up to `max_content_length` bytes until
+/// `expires_at` (unix seconds), formatted as `<max_content_length>.<expires_at>.<hex hmac>`
+pub fn sign_size_token(
+    secret: &str,
+    cid: &str,
+    max_content_length: u64,
+    expires_at: i64,
+) -> String {
+    let signed = signing::sign(
+        secret,
+        &message(cid, max_content_length, expires_at),
+        expires_at,
+    );
+
+    format!("{max_content_length}.{signed}")
+}
+
+/// The size limit granted to `cid` by `token`, `None` when no secret is configured or
+/// the token is malformed, expired or signed for another CID
+pub fn verify_size_token(config: &Settings, cid: &str, token: &str) -> Option<u64> {
+    let secret = config.size_token_secret.as_deref()?;
+
+    let (max_content_length, signed) = token.split_once('.')?;
+    let max_content_length = max_content_length.parse::<u64>().ok()?;
+
+    signing::verify(secret, signed, |expires_at| {
+        message(cid, max_content_length, expires_at)
+    })
+    .then_some(max_content_length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    const CID: &str = "bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344";
+
+    fn config(secret: Option<&str>) -> Settings {
+        let mut config = Settings::new().expect("Can't create configuration");
+        config.size_token_secret = secret.map(|secret| secret.to_string());
+        config
+    }
+
+    #[test]
+    fn verify_token() {
+        let config = config(Some("secret"));
+        let expires_at = Utc::now().timestamp() + 60;
+        let token = sign_size_token("secret", CID, 1_000_000_000, expires_at);
+
+        assert_eq!(verify_size_token(&config, CID, &token), Some(1_000_000_000));
+        // Signed for another CID, with another secret or expired
+        assert_eq!(
+            verify_size_token(
+                &config,
+                "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi",
+                &token
+            ),
+            None
+        );
+        assert_eq!(
+            verify_size_token(
+                &config,
+                CID,
+                &sign_size_token("other", CID, 1_000_000_000, expires_at)
+            ),
+            None
+        );
+        assert_eq!(
+            verify_size_token(
+                &config,
+                CID,
+                &sign_size_token("secret", CID, 1_000_000_000, expires_at - 120)
+            ),
+            None
+        );
+        // Tampered limit
+        let tampered = token.replacen("1000000000", "2000000000", 1);
+        assert_eq!(verify_size_token(&config, CID, &tampered), None);
+        assert_eq!(verify_size_token(&config, CID, "garbage"), None);
+        // Ignored without a configured secret
+        assert_eq!(verify_size_token(&self::config(None), CID, &token), None);
+    }
+}
@@ -1,4 +1,10 @@
-use tracing::{subscriber::set_global_default, Subscriber};
+use opentelemetry::sdk::{
+    export::metrics::aggregation,
+    metrics::{controllers, processors, selectors},
+};
+use opentelemetry_prometheus::PrometheusExporter;
+use std::time::Duration;
+use tracing::{debug, info, subscriber::set_global_default, warn, Subscriber};
 
 #[allow(unused_imports)]
 use tracing_subscriber::{fmt::format::FmtSpan, layer::SubscriberExt, EnvFilter, Registry};
@@ -22,3 +28,30 @@ pub fn get_subscriber(level: &str) -> impl Subscriber + Send + Sync {
 pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     set_global_default(subscriber).expect("Failed to set subscriber");
 }
+
+/// Install the global meter provider, metrics are then exported with the returned exporter.
+/// It should only be called once!
+pub fn init_metrics() -> PrometheusExporter {
+    let controller = controllers::basic(
+        processors::factory(
+            selectors::simple::inexpensive(),
+            aggregation::cumulative_temporality_selector(),
+        )
+        .with_memory(true),
+    )
+    .build();
+
+    opentelemetry_prometheus::exporter(controller).init()
+}
+
+/// Log a timed request or fetch: at warn above `slow_request_threshold_ms`, at debug
+/// below it, and at info when no threshold is set
+pub fn log_duration(slow_request_threshold_ms: Option<u64>, elapsed: Duration, message: &str) {
+    match slow_request_threshold_ms {
+        Some(threshold) if elapsed > Duration::from_millis(threshold) => {
+            warn!("Slow [{elapsed:.3?}] {message}")
+        }
+        Some(_) => debug!("[{elapsed:.3?}] {message}"),
+        None => info!("[{elapsed:.3?}] {message}"),
+    }
+}
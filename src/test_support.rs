@@ -0,0 +1,109 @@
+//! Mock gateways, contexts and apps shared by the tests of every module
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{web, App, Error};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+
+use crate::actix_server::{config_app, make_app};
+use crate::config::{RuntimeSettings, Settings};
+use crate::AppContext;
+
+/// Serve `response` as raw HTTP to every connection after `delay` milliseconds,
+/// returns the gateway url
+pub async fn mock_gateway(response: Vec<u8>, delay: u64) -> String {
+    mock_gateway_with(delay, move |_| response.clone()).await
+}
+
+/// Like `mock_gateway`, the response built by `respond` from the raw request
+pub async fn mock_gateway_with(
+    delay: u64,
+    respond: impl Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Can't bind mock gateway");
+    let port = listener.local_addr().expect("No local address").port();
+    let respond = Arc::new(respond);
+
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let respond = respond.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0; 4096];
+                let read = socket.read(&mut buffer).await.unwrap_or_default();
+                if read == 0 {
+                    return;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+                socket.write_all(&respond(&buffer[..read])).await.ok();
+                socket.shutdown().await.ok();
+            });
+        }
+    });
+
+    format!("http://127.0.0.1:{port}/ipfs")
+}
+
+pub fn http_response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 {status}\r\nConnection: close\r\n");
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// A black `width`x`height` PNG served with its content type
+pub fn png_response(width: u32, height: u32) -> Vec<u8> {
+    let mut png = std::io::Cursor::new(Vec::new());
+    image::RgbImage::new(width, height)
+        .write_to(&mut png, image::ImageOutputFormat::Png)
+        .expect("Can't encode PNG");
+
+    http_response(
+        "200 OK",
+        &[("Content-Type", "image/png")],
+        &png.into_inner(),
+    )
+}
+
+pub async fn mock_context(gateways: Vec<String>) -> Arc<AppContext> {
+    mock_context_with(gateways, |_| {}).await
+}
+
+/// A test context fetching from `gateways`, its runtime settings following `configure`
+pub async fn mock_context_with(
+    gateways: Vec<String>,
+    configure: impl FnOnce(&mut Settings),
+) -> Arc<AppContext> {
+    let mut ctx = AppContext::build_for_test().await;
+    ctx.config.ipfs_gateways = gateways;
+    configure(&mut ctx.config);
+    ctx.fetches = Arc::new(Semaphore::new(ctx.config.max_concurrent_fetches));
+    ctx.runtime
+        .store(Arc::new(RuntimeSettings::from(&ctx.config)));
+
+    Arc::new(ctx)
+}
+
+/// The server's app without compression or slow request logging, for `init_service`
+pub fn test_app(
+    ctx: Arc<AppContext>,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Response = ServiceResponse<impl MessageBody>,
+        Config = (),
+        InitError = (),
+        Error = Error,
+    >,
+> {
+    make_app(0, vec![], None).configure(config_app(web::Data::from(ctx)))
+}
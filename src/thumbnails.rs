@@ -0,0 +1,536 @@
+use futures::future::join_all;
+use image::codecs::jpeg::JpegEncoder;
+use image::DynamicImage;
+use sea_orm::{entity::prelude::*, Condition, QueryOrder, QuerySelect};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{debug, error, info};
+
+use crate::caching::{apply_cache_file_mode, remove_empty_parents, Data};
+use crate::config::{Dimension, ResizeAutoPolicy, Settings};
+use crate::AppContext;
+use entity::thumbnail::record_thumbnail;
+
+/// Returns the thumbnail filename and its content type for the requested format,
+/// under `thumbnail_directory` with the same relative path as the original if set.
+/// A JPEG `quality` other than the encoder default is part of the name.
+pub fn thumbnail_filename(
+    config: &Settings,
+    filename: &str,
+    dimension: &Dimension,
+    requested_file_format: &str,
+    quality: Option<u8>,
+) -> (String, String) {
+    let cache_directory = config.full_ipfs_cache_directory();
+    let filename = match (
+        config.full_thumbnail_directory(),
+        filename.strip_prefix(&cache_directory),
+    ) {
+        (Some(thumbnail_directory), Some(relative)) => format!("{thumbnail_directory}{relative}"),
+        _ => filename.to_string(),
+    };
+
+    match requested_file_format {
+        "jpeg" => (
+            match quality {
+                Some(quality) => format!(
+                    "{}-{}x{}-q{quality}.jpeg",
+                    filename, dimension.width, dimension.height
+                ),
+                None => format!("{}-{}x{}.jpeg", filename, dimension.width, dimension.height),
+            },
+            "image/jpeg".to_string(),
+        ),
+        _ => (
+            format!("{}-{}x{}.png", filename, dimension.width, dimension.height),
+            "image/png".to_string(),
+        ),
+    }
+}
+
+/// The original's file name for a name `thumbnail_filename` produces,
+/// `<original>-<width>x<height>[-q<quality>].<format>`, `None` for any other name
+pub fn thumbnail_source(name: &str) -> Option<&str> {
+    let (stem, format) = name.rsplit_once('.')?;
+    let stem = match format {
+        "png" => stem,
+        "jpeg" => match stem.rsplit_once('-') {
+            Some((rest, quality))
+                if quality
+                    .strip_prefix('q')
+                    .is_some_and(|quality| quality.parse::<u8>().is_ok()) =>
+            {
+                rest
+            }
+            _ => stem,
+        },
+        _ => return None,
+    };
+    let (source, dimension) = stem.rsplit_once('-')?;
+    let (width, height) = dimension.split_once('x')?;
+
+    (width.parse::<u32>().is_ok() && height.parse::<u32>().is_ok()).then_some(source)
+}
+
+/// Output format for `img-auto`: the client's preferred one of JPEG and PNG by `Accept`
+/// quality values, `policy` breaking ties. Without `Accept` or when neither is
+/// acceptable, the policy's choice.
+pub fn auto_format(
+    policy: ResizeAutoPolicy,
+    content_type: &str,
+    accept: Option<&str>,
+) -> &'static str {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    let candidates = match policy {
+        ResizeAutoPolicy::Original if essence.eq_ignore_ascii_case("image/jpeg") => ["jpeg", "png"],
+        ResizeAutoPolicy::Original | ResizeAutoPolicy::Lossless => ["png", "jpeg"],
+        ResizeAutoPolicy::Lossy => ["jpeg", "png"],
+    };
+    let Some(accept) = accept else {
+        return candidates[0];
+    };
+
+    let mut best = (candidates[0], 0.0);
+    for format in candidates {
+        let quality = accept_quality(accept, &format!("image/{format}"));
+        if quality > best.1 {
+            best = (format, quality);
+        }
+    }
+    best.0
+}
+
+/// Quality value `accept` gives `mime`, from its most specific matching media range
+fn accept_quality(accept: &str, mime: &str) -> f32 {
+    let kind = mime.split('/').next().unwrap_or_default();
+    let mut best: Option<(u8, f32)> = None;
+
+    for entry in accept.split(',') {
+        let mut params = entry.split(';');
+        let range = params.next().unwrap_or_default().trim();
+        let specificity = if range.eq_ignore_ascii_case(mime) {
+            2
+        } else if range.eq_ignore_ascii_case(&format!("{kind}/*")) {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|quality| quality.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if best.is_none_or(|(best, _)| specificity > best) {
+            best = Some((specificity, quality));
+        }
+    }
+
+    best.map(|(_, quality)| quality).unwrap_or_default()
+}
+
+/// Highest device pixel ratio honored, larger values are clamped
+pub const MAX_DPR: u32 = 3;
+
+/// Device pixel ratio from the `dpr` query parameter, 1 when missing or invalid
+pub fn parse_dpr(dpr: Option<&str>) -> u32 {
+    dpr.and_then(|dpr| dpr.parse::<f32>().ok())
+        .filter(|dpr| dpr.is_finite())
+        .map(|dpr| (dpr.round() as u32).clamp(1, MAX_DPR))
+        .unwrap_or(1)
+}
+
+/// Resizing is skipped for sources already smaller than the requested size, which
+/// would be upscaled, or below the `min_resizable_dimension` floor
+pub fn worth_resizing(
+    source_width: u32,
+    source_height: u32,
+    dimension: &Dimension,
+    min_resizable_dimension: u32,
+) -> bool {
+    if source_width < min_resizable_dimension || source_height < min_resizable_dimension {
+        return false;
+    }
+
+    source_width > dimension.width || source_height > dimension.height
+}
+
+/// Resize `filename` into `thumbnail_filename` unless it already exists. The decoded
+/// pixels are re-encoded without any EXIF, ICC profile or text chunk of the original.
+#[tracing::instrument]
+pub fn create_thumbnail(
+    config: &Settings,
+    filename: &str,
+    thumbnail_filename: &str,
+    dimension: &Dimension,
+    quality: Option<u8>,
+) -> Result<(), anyhow::Error> {
+    if Path::new(thumbnail_filename).exists() {
+        return Ok(());
+    }
+
+    let directory = Path::new(thumbnail_filename)
+        .parent()
+        .unwrap_or_else(|| Path::new("."));
+    std::fs::create_dir_all(directory)?;
+
+    debug!(
+        "Resizing image {} to {}x{}",
+        filename, dimension.width, dimension.height
+    );
+    let img = image::open(filename)?;
+    let thumbnail = img.resize(
+        dimension.width,
+        dimension.height,
+        image::imageops::FilterType::Lanczos3,
+    );
+    // Written aside then renamed, so a concurrent request for the same size never
+    // serves a partial thumbnail. The suffix keeps the extension `save` encodes from.
+    let extension = Path::new(thumbnail_filename)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("png");
+    let tmp_file = tempfile::Builder::new()
+        .prefix(".tmp")
+        .suffix(&format!(".{extension}"))
+        .tempfile_in(directory)?;
+    match quality {
+        Some(quality) if extension == "jpeg" => {
+            let mut writer = std::io::BufWriter::new(tmp_file.as_file());
+            JpegEncoder::new_with_quality(&mut writer, quality)
+                .encode_image(&DynamicImage::ImageRgb8(thumbnail.to_rgb8()))?;
+            writer.flush()?;
+        }
+        _ => thumbnail.save(tmp_file.path())?,
+    }
+    tmp_file.persist(thumbnail_filename)?;
+    apply_cache_file_mode(config, thumbnail_filename)?;
+
+    Ok(())
+}
+
+/// Generate thumbnails for a freshly cached image, so the first request for each
+/// size is a hit and the original can be evicted sooner.
+pub fn pregenerate_thumbnails(ctx: Arc<AppContext>, data: &Data, dimensions: Vec<Dimension>) {
+    let (Some(filename), Some(content_type)) = (data.filename.clone(), data.content_type.clone())
+    else {
+        return;
+    };
+
+    if !content_type.starts_with("image/") {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let Ok(_permit) = ctx.resizes.clone().acquire_owned().await else {
+            return;
+        };
+
+        let task_ctx = ctx.clone();
+        let source_filename = filename.clone();
+        let thumbnails = tokio::task::spawn_blocking(move || {
+            let mut thumbnails = vec![];
+            for dimension in &dimensions {
+                let (thumbnail_filename, _) =
+                    thumbnail_filename(&task_ctx.config, &filename, dimension, "png", None);
+
+                if let Err(error) = create_thumbnail(
+                    &task_ctx.config,
+                    &filename,
+                    &thumbnail_filename,
+                    dimension,
+                    None,
+                ) {
+                    error!("Couldn't pregenerate thumbnail for {}: {error}", &filename);
+                    break;
+                }
+                thumbnails.push(thumbnail_filename);
+            }
+            thumbnails
+        })
+        .await
+        .unwrap_or_default();
+
+        for thumbnail_filename in &thumbnails {
+            if let Err(error) =
+                record_thumbnail(&ctx.db, &source_filename, thumbnail_filename).await
+            {
+                error!("Error recording thumbnail {thumbnail_filename}: {error}");
+            }
+        }
+
+        info!(
+            "Pregenerated {} thumbnails for {}",
+            thumbnails.len(),
+            &source_filename
+        );
+    });
+}
+
+/// Thumbnails sized or deleted per query, below SQLite's bound variables limit
+const EVICTION_BATCH_SIZE: u64 = 500;
+
+/// Delete the least recently requested thumbnails until the rest fit in
+/// `max_thumbnail_cache_bytes`, originals are left alone. Returns how many were deleted.
+pub async fn evict_thumbnails(ctx: &AppContext) -> Result<usize, anyhow::Error> {
+    let Some(max_bytes) = ctx.config.max_thumbnail_cache_bytes else {
+        return Ok(0);
+    };
+
+    // Recorded again on each resize request, so `created_at` is the last use. The most
+    // recent ones are kept up to `max_bytes`, the first one past it and every older one
+    // are evicted.
+    let mut pages = entity::thumbnail::Entity::find()
+        .order_by_desc(entity::thumbnail::Column::CreatedAt)
+        .order_by_desc(entity::thumbnail::Column::Id)
+        .paginate(&ctx.db, EVICTION_BATCH_SIZE);
+    let mut total_bytes = 0;
+    let mut cutoff = None;
+    'pages: while let Some(thumbnails) = pages.fetch_and_next().await? {
+        let sizes = join_all(thumbnails.iter().map(|thumbnail| async {
+            tokio::fs::metadata(&thumbnail.filename)
+                .await
+                .map(|metadata| metadata.len())
+                .unwrap_or_default()
+        }))
+        .await;
+
+        for (thumbnail, size) in thumbnails.iter().zip(sizes) {
+            total_bytes += size;
+            if total_bytes > max_bytes {
+                cutoff = Some((thumbnail.created_at, thumbnail.id));
+                break 'pages;
+            }
+        }
+    }
+    let Some((cutoff_at, cutoff_id)) = cutoff else {
+        return Ok(0);
+    };
+
+    let thumbnail_directory = ctx
+        .config
+        .full_thumbnail_directory()
+        .unwrap_or_else(|| ctx.config.full_ipfs_cache_directory());
+    let mut evicted = 0;
+    loop {
+        // Each batch's rows go with its files, an interrupted eviction leaves no row
+        // pointing at a deleted file
+        let thumbnails = entity::thumbnail::Entity::find()
+            .filter(
+                Condition::any()
+                    .add(entity::thumbnail::Column::CreatedAt.lt(cutoff_at))
+                    .add(
+                        Condition::all()
+                            .add(entity::thumbnail::Column::CreatedAt.eq(cutoff_at))
+                            .add(entity::thumbnail::Column::Id.lte(cutoff_id)),
+                    ),
+            )
+            .limit(EVICTION_BATCH_SIZE)
+            .all(&ctx.db)
+            .await?;
+        if thumbnails.is_empty() {
+            break;
+        }
+
+        join_all(thumbnails.iter().map(|thumbnail| {
+            let thumbnail_directory = &thumbnail_directory;
+            async move {
+                tokio::fs::remove_file(&thumbnail.filename).await.ok();
+                remove_empty_parents(&thumbnail.filename, thumbnail_directory).await;
+            }
+        }))
+        .await;
+
+        entity::thumbnail::Entity::delete_many()
+            .filter(
+                entity::thumbnail::Column::Id
+                    .is_in(thumbnails.iter().map(|thumbnail| thumbnail.id)),
+            )
+            .exec(&ctx.db)
+            .await?;
+        evicted += thumbnails.len();
+    }
+
+    Ok(evicted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thumbnail_in_separate_directory() {
+        let mut config = Settings::new().expect("Can't create configuration");
+        let cache_directory = config.full_ipfs_cache_directory();
+        let filename = format!("{cache_directory}/bafy/image.png");
+        let dimension = Dimension {
+            width: 100,
+            height: 100,
+        };
+
+        let (thumbnail, _) = thumbnail_filename(&config, &filename, &dimension, "png", None);
+        assert_eq!(
+            thumbnail,
+            format!("{cache_directory}/bafy/image.png-100x100.png")
+        );
+
+        config.thumbnail_directory = Some("/tmp/thumbnails".to_string());
+        let (thumbnail, content_type) =
+            thumbnail_filename(&config, &filename, &dimension, "jpeg", None);
+        assert_eq!(thumbnail, "/tmp/thumbnails/bafy/image.png-100x100.jpeg");
+        assert_eq!(content_type, "image/jpeg");
+
+        let (thumbnail, _) = thumbnail_filename(&config, &filename, &dimension, "jpeg", Some(80));
+        assert_eq!(thumbnail, "/tmp/thumbnails/bafy/image.png-100x100-q80.jpeg");
+    }
+
+    #[test]
+    fn parse_thumbnail_names() {
+        assert_eq!(thumbnail_source("image.png-100x100.png"), Some("image.png"));
+        assert_eq!(thumbnail_source("image.png-100x50.jpeg"), Some("image.png"));
+        assert_eq!(
+            thumbnail_source("image.png-100x100-q80.jpeg"),
+            Some("image.png")
+        );
+        assert_eq!(thumbnail_source("image-v2.png"), None);
+        assert_eq!(thumbnail_source("image.png-100x100-q80.png"), None);
+        assert_eq!(thumbnail_source("image.png-100x100.gif"), None);
+        assert_eq!(thumbnail_source("image.png"), None);
+    }
+
+    #[test]
+    fn skip_small_sources() {
+        let dimension = Dimension {
+            width: 100,
+            height: 100,
+        };
+
+        assert!(worth_resizing(500, 300, &dimension, 0));
+        assert!(worth_resizing(500, 50, &dimension, 0));
+        assert!(!worth_resizing(64, 64, &dimension, 0));
+        assert!(!worth_resizing(500, 300, &dimension, 400));
+    }
+
+    #[test]
+    fn pick_auto_format() {
+        use ResizeAutoPolicy::*;
+
+        assert_eq!(auto_format(Original, "image/jpeg", None), "jpeg");
+        assert_eq!(
+            auto_format(Original, "IMAGE/JPEG; charset=binary", None),
+            "jpeg"
+        );
+        assert_eq!(auto_format(Original, "image/png", None), "png");
+        assert_eq!(auto_format(Original, "image/gif", None), "png");
+        assert_eq!(auto_format(Lossy, "image/png", None), "jpeg");
+        assert_eq!(auto_format(Lossless, "image/jpeg", None), "png");
+
+        // Ties go to the policy, higher quality values to the client
+        assert_eq!(auto_format(Lossy, "image/png", Some("image/*")), "jpeg");
+        assert_eq!(
+            auto_format(Lossy, "image/png", Some("image/png, image/*;q=0.5")),
+            "png"
+        );
+        assert_eq!(
+            auto_format(Original, "image/png", Some("image/jpeg, */*;q=0.1")),
+            "jpeg"
+        );
+        assert_eq!(
+            auto_format(Lossless, "image/png", Some("image/*, image/png;q=0")),
+            "jpeg"
+        );
+        assert_eq!(
+            auto_format(Lossless, "image/png", Some("image/webp")),
+            "png"
+        );
+    }
+
+    #[tokio::test]
+    async fn evict_least_recent_thumbnails() -> Result<(), anyhow::Error> {
+        let mut ctx = AppContext::build_for_test().await;
+        ctx.config.max_thumbnail_cache_bytes = Some(20);
+        let directory = ctx.config.full_ipfs_cache_directory();
+        let original = format!("{directory}/bafy/image.png");
+
+        std::fs::create_dir_all(format!("{directory}/bafy"))?;
+
+        let now = chrono::Utc::now().naive_utc();
+        let mut filenames = vec![];
+        for (index, age) in [3, 1, 2].into_iter().enumerate() {
+            let filename = format!("{original}-{index}.png");
+            std::fs::write(&filename, [0; 8])?;
+            entity::thumbnail::ActiveModel {
+                source_filename: sea_orm::ActiveValue::set(original.clone()),
+                filename: sea_orm::ActiveValue::set(filename.clone()),
+                created_at: sea_orm::ActiveValue::set(now - chrono::Duration::minutes(age)),
+                ..Default::default()
+            }
+            .insert(&ctx.db)
+            .await?;
+            filenames.push(filename);
+        }
+
+        // Two 8 bytes thumbnails fit, the one used 3 minutes ago goes
+        assert_eq!(evict_thumbnails(&ctx).await?, 1);
+        assert!(!Path::new(&filenames[0]).exists());
+        assert!(Path::new(&filenames[1]).exists());
+        assert!(Path::new(&filenames[2]).exists());
+        assert_eq!(entity::thumbnail::Entity::find().count(&ctx.db).await?, 2);
+
+        assert_eq!(evict_thumbnails(&ctx).await?, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn evict_thumbnails_in_batches() -> Result<(), anyhow::Error> {
+        let mut ctx = AppContext::build_for_test().await;
+        ctx.config.max_thumbnail_cache_bytes = Some(1);
+        let directory = tempfile::tempdir()?;
+
+        let now = chrono::Utc::now().naive_utc();
+        let count = EVICTION_BATCH_SIZE as usize * 2 + 1;
+        let mut thumbnails = vec![];
+        for index in 0..count {
+            let filename = directory
+                .path()
+                .join(format!("{index}.png"))
+                .display()
+                .to_string();
+            std::fs::write(&filename, [0])?;
+            thumbnails.push(entity::thumbnail::ActiveModel {
+                source_filename: sea_orm::ActiveValue::set("image.png".to_string()),
+                filename: sea_orm::ActiveValue::set(filename),
+                created_at: sea_orm::ActiveValue::set(
+                    now - chrono::Duration::seconds(index as i64),
+                ),
+                ..Default::default()
+            });
+        }
+        for batch in thumbnails.chunks(EVICTION_BATCH_SIZE as usize) {
+            entity::thumbnail::Entity::insert_many(batch.to_vec())
+                .exec(&ctx.db)
+                .await?;
+        }
+
+        // Only the most recent one fits
+        assert_eq!(evict_thumbnails(&ctx).await?, count - 1);
+        assert!(directory.path().join("0.png").exists());
+        assert!(!directory.path().join("1.png").exists());
+        assert_eq!(entity::thumbnail::Entity::find().count(&ctx.db).await?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn clamp_dpr() {
+        assert_eq!(parse_dpr(None), 1);
+        assert_eq!(parse_dpr(Some("2")), 2);
+        assert_eq!(parse_dpr(Some("0")), 1);
+        assert_eq!(parse_dpr(Some("-2")), 1);
+        assert_eq!(parse_dpr(Some("10")), MAX_DPR);
+        assert_eq!(parse_dpr(Some("retina")), 1);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::config::Settings;
+use crate::signing;
+
+/// Resize parameters covered by a signature, `None` when absent from the url
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SignedParams<'a> {
+    pub img_width: Option<&'a str>,
+    pub img_height: Option<&'a str>,
+    pub img_format: Option<&'a str>,
+    pub dpr: Option<&'a str>,
+    pub img_auto: Option<&'a str>,
+}
+
+fn message(base_uri: &str, params: SignedParams, expires_at: i64) -> String {
+    let mut message = format!(
+        "{base_uri}\n{}\n{}\n{}\n{}\n{expires_at}",
+        params.img_width.unwrap_or_default(),
+        params.img_height.unwrap_or_default(),
+        params.img_format.unwrap_or_default(),
+        params.dpr.unwrap_or_default()
+    );
+    // Appended only when present so signatures made before `img-auto` stay valid
+    if let Some(img_auto) = params.img_auto {
+        message.push_str(&format!("\n{img_auto}"));
+    }
+    message
+}
+
+/// `signature` query parameter allowing `base_uri` (the CID and percent-encoded subpath
+/// as returned by `check_ipfs_url`) with these resize parameters until `expires_at` (unix
+/// seconds), formatted as `<expires_at>.<hex hmac>`
+pub fn sign_url(secret: &str, base_uri: &str, params: SignedParams, expires_at: i64) -> String {
+    signing::sign(secret, &message(base_uri, params, expires_at), expires_at)
+}
+
+/// Whether the request may be served: always without `require_signed_urls`, otherwise
+/// only with an unexpired signature of this exact url
+pub fn verify_url_signature(
+    config: &Settings,
+    base_uri: &str,
+    params: SignedParams,
+    signature: Option<&str>,
+) -> bool {
+    if !config.require_signed_urls {
+        return true;
+    }
+
+    let (Some(secret), Some(signature)) = (config.url_signing_secret.as_deref(), signature) else {
+        return false;
+    };
+
+    signing::verify(secret, signature, |expires_at| {
+        message(base_uri, params, expires_at)
+    })
+}
+
+/// `signature` verified for a url, signed again for `base_uri` with the same expiry, e.g.
+/// when redirecting to another form of the CID. `None` when urls aren't signed.
+pub fn resign_url(
+    config: &Settings,
+    signature: Option<&str>,
+    base_uri: &str,
+    params: SignedParams,
+) -> Option<String> {
+    if !config.require_signed_urls {
+        return None;
+    }
+    let secret = config.url_signing_secret.as_deref()?;
+    let expires_at = signing::expires_at(signature?)?;
+
+    Some(sign_url(secret, base_uri, params, expires_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    const BASE_URI: &str = "bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/1.png";
+
+    #[test]
+    fn verify_signature() {
+        let mut config = Settings::new().expect("Can't create configuration");
+        config.url_signing_secret = Some("secret".to_string());
+        let params = SignedParams {
+            img_width: Some("100"),
+            img_height: Some("100"),
+            ..Default::default()
+        };
+        let expires_at = Utc::now().timestamp() + 60;
+        let signature = sign_url("secret", BASE_URI, params, expires_at);
+
+        // Open proxy when signatures aren't required
+        assert!(verify_url_signature(&config, BASE_URI, params, None));
+
+        config.require_signed_urls = true;
+        assert!(verify_url_signature(
+            &config,
+            BASE_URI,
+            params,
+            Some(&signature)
+        ));
+        assert!(!verify_url_signature(&config, BASE_URI, params, None));
+        assert!(!verify_url_signature(
+            &config,
+            BASE_URI,
+            params,
+            Some("garbage")
+        ));
+        // Another path, other resize parameters, another secret or expired
+        assert!(!verify_url_signature(
+            &config,
+            "bafybeicugp6ayh2wh3j2dwb2bhesmxmo2husbbs5prla4wj6rf3ivg3344/2.png",
+            params,
+            Some(&signature)
+        ));
+        assert!(!verify_url_signature(
+            &config,
+            BASE_URI,
+            SignedParams::default(),
+            Some(&signature)
+        ));
+        assert!(!verify_url_signature(
+            &config,
+            BASE_URI,
+            SignedParams {
+                img_auto: Some("1"),
+                ..params
+            },
+            Some(&signature)
+        ));
+        assert!(!verify_url_signature(
+            &config,
+            BASE_URI,
+            params,
+            Some(&sign_url("other", BASE_URI, params, expires_at))
+        ));
+        assert!(!verify_url_signature(
+            &config,
+            BASE_URI,
+            params,
+            Some(&sign_url("secret", BASE_URI, params, expires_at - 120))
+        ));
+    }
+}